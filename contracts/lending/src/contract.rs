@@ -1,4 +1,40 @@
-use soroban_sdk::{contract, contractimpl, contracttype, token, Address, Env, Map};
+use soroban_sdk::{
+    contract, contractimpl, contracttype, token, Address, BytesN, Env, IntoVal, Map, Symbol, Val,
+    Vec,
+};
+
+use crate::emissions::{EmissionConfig, RewardIndex};
+use crate::events::{
+    AdminAction, BadDebt, Borrow, FlashLoan, Frozen, Liquidation, OwnerChanged,
+    ParamChangeCancelled, ParamChangeQueued, Paused, PoolDeactivated, PoolInitialized,
+    PriceCircuitBreaker, PricesUpdated, Repay, ReserveConfigUpdated, ReservesWithdrawn, Supply,
+    SupplyCapNear, Unfrozen, Unpaused, UpgradeScheduled, WithdrawClaimed, WithdrawRequested,
+};
+use crate::flash_loan::FlashLoanReceiverClient;
+use crate::oracle::{Asset, ReflectorClient};
+use crate::receipt::SupplyTokenClient;
+use crate::timelock::{ParamKey, PendingChange};
+
+/// Fixed-point scale of `Pool.borrow_index` (1e9 == 1.0).
+const INDEX_SCALE: i128 = 1_000_000_000;
+const SECONDS_PER_YEAR: u64 = 31_536_000;
+/// Supply shares locked forever by a pool's first deposit.
+const MINIMUM_SHARES: i128 = 1000;
+/// Price decimals of deployments made before `DataKey::PriceDecimals`.
+const DEFAULT_PRICE_DECIMALS: u32 = 7;
+/// Decimal places every token amount is scaled to before it is valued.
+///
+/// Health math works in "value units": an amount in these accounting
+/// decimals times a `price_decimals` price. Tokens with other decimals are
+/// scaled first, so a 6-decimal and an 18-decimal asset compare fairly.
+const ACCOUNTING_DECIMALS: u32 = 7;
+/// Storage layout version written by this code. Bump it, and teach
+/// `migrate` the previous layout, whenever stored types change shape.
+const STORAGE_VERSION: u32 = 1;
+/// Seconds between `schedule_upgrade` and the earliest matching `upgrade`.
+const UPGRADE_DELAY: u64 = 172_800;
+/// Share of the supply cap, out of 10,000, past which `SupplyCapNear` is published.
+const SUPPLY_CAP_WARNING: u32 = 9000;
 
 #[derive(Clone)]
 #[contracttype]
@@ -8,6 +44,112 @@ pub enum DataKey {
     UserPos(Address),
     Ltv(Address),
     Price(Address),
+    LiquidationBonus(Address),
+    CloseFactor,
+    InterestRate(Address),
+    Oracle,
+    UseOracle(Address),
+    PriceTimestamp(Address),
+    MaxPriceAge,
+    PendingOwner,
+    Paused,
+    ExitsWhilePaused,
+    ActionPaused(Action),
+    BorrowCap(Address),
+    SupplyCap(Address),
+    ReserveFactor(Address),
+    FlashLoanFee,
+    Decimals(Address),
+    RateModel(Address),
+    /// (user, token): whether `user`'s deposit of `token` backs their debt.
+    CollateralEnabled(Address, Address),
+    PoolActive(Address),
+    PendingUpgrade,
+    Version,
+    PoolList,
+    Isolation(Address),
+    LiquidationThreshold(Address),
+    EmodeCategory(u32),
+    AssetEmode(Address),
+    UserEmode(Address),
+    /// (delegator, delegatee, token): how much more the delegatee may borrow.
+    BorrowAllowance(Address, Address, Address),
+    MinBorrow(Address),
+    /// Set while a state-changing call is running (see `non_reentrant`).
+    Locked,
+    MaxPriceDeviation,
+    /// Whether a large price move froze borrows and liquidations of the asset.
+    PriceFrozen(Address),
+    /// Whether only allowed accounts may supply to or borrow from the pool.
+    Allowlist(Address),
+    /// (token, account): whether `account` is on `token`'s allowlist.
+    Allowed(Address, Address),
+    WithdrawCooldown,
+    /// Ledger timestamp of the user's last supply to any pool.
+    LastSupply(Address),
+    /// Receipt token whose balances are the pool's supply shares.
+    SupplyToken(Address),
+    /// Ledger timestamp of the last `unpause`.
+    UnpauseTimestamp,
+    GracePeriod,
+    EmissionConfig(Address),
+    RewardIndex(Address),
+    /// (user, token)
+    UserRewards(Address, Address),
+    OriginationFee(Address),
+    MaxUtilization(Address),
+    /// Decimal places of every price, set by the constructor.
+    PriceDecimals,
+}
+
+/// Storage keys added once `DataKey` reached the 50 variants a contract
+/// type enum can hold.
+#[derive(Clone)]
+#[contracttype]
+pub enum ExtraKey {
+    /// Liquidation bonus of assets without a `DataKey::LiquidationBonus`.
+    DefaultLiquidationBonus,
+    MinHealthBuffer,
+    /// Number of users with debt; they fill `BorrowerAt(0..BorrowerCount)`.
+    BorrowerCount,
+    BorrowerAt(u32),
+    /// A borrower's slot in `BorrowerAt`.
+    BorrowerIndex(Address),
+    LiquidationProtocolFee,
+    /// (user, token): underlying supplied, net of what has since left the
+    /// deposit; see `supplier_accrued`.
+    SupplyPrincipal(Address, Address),
+    /// Set by `set_frozen`; absent means not frozen.
+    Frozen(Address),
+    MinLiquidationSeize(Address),
+    /// Feeds set by `set_oracles`, used instead of `DataKey::Oracle`.
+    Oracles,
+    /// Fresh prices out of `Oracles` needed to price an asset.
+    MinOracles,
+    Guardian,
+    TimelockDelay,
+    /// Changes queued with `queue_param_change`, by id.
+    PendingChanges,
+    NextChangeId,
+    /// (user, token): supply shares queued with `request_withdraw`.
+    WithdrawRequest(Address, Address),
+    /// A pool's supply shares queued by all its users.
+    QueuedShares(Address),
+    /// Last oracle price of an oracle-priced asset that passed the
+    /// `DataKey::MaxPriceDeviation` check.
+    AcceptedPrice(Address),
+}
+
+/// A user-facing operation that can be paused on its own.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[contracttype]
+pub enum Action {
+    Supply,
+    Withdraw,
+    Borrow,
+    Repay,
+    Liquidate,
+    FlashLoan,
 }
 
 /// Represents a single asset pool.
@@ -18,17 +160,154 @@ pub struct Pool {
     pub total_supply_shares: i128,
     pub total_debt_shares: i128,
     pub total_reserves: i128,
+    /// Underlying tokens held by the pool through its own accounting.
+    /// Tokens sent to the contract outside of `supply`/`repay` are ignored.
+    pub total_cash: i128,
+    /// Cumulative borrow interest, scaled by `INDEX_SCALE`.
+    /// One debt share is owed as `borrow_index / INDEX_SCALE` tokens.
+    pub borrow_index: i128,
+    /// Ledger timestamp of the last interest accrual.
+    pub last_accrual: u64,
+}
+
+/// `Pool` as stored before storage version 1, when shares were minted 1:1
+/// with tokens and there was no interest.
+#[derive(Clone, Debug)]
+#[contracttype]
+pub struct PoolV0 {
+    pub token: Address,
+    pub total_supply_shares: i128,
+    pub total_debt_shares: i128,
+    pub total_reserves: i128,
 }
 
 #[derive(Clone, Debug)]
 #[contracttype]
 pub struct UserPosition {
-    /// Map<Token Address, Amount Deposited>
+    /// Map<Token Address, Supply Shares> (see `LendingPool::total_assets`)
     pub deposit_shares: Map<Address, i128>,
-    /// Map<Token Address, Amount Borrowed>
+    /// Map<Token Address, Debt Shares> (see `Pool.borrow_index`)
     pub debt_shares: Map<Address, i128>,
 }
 
+/// A kinked borrow-rate curve. Every field is out of 10,000.
+///
+/// Below `optimal_utilization` the rate climbs from `base_rate` by up to
+/// `slope1`; past the kink it climbs by up to `slope2` more, reaching
+/// `base_rate + slope1 + slope2` at full utilization.
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[contracttype]
+pub struct RateModel {
+    pub base_rate: u32,
+    pub slope1: u32,
+    pub slope2: u32,
+    pub optimal_utilization: u32,
+}
+
+/// Isolation-mode settings for a risky collateral asset.
+///
+/// An isolated asset can't be combined with other collateral, and a user
+/// whose only collateral it is can owe at most `debt_ceiling` in total,
+/// valued in dollars with `price_decimals` decimals.
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[contracttype]
+pub struct IsolationConfig {
+    pub isolated: bool,
+    pub debt_ceiling: i128,
+}
+
+/// Boosted risk parameters for a group of correlated assets, such as
+/// stablecoins. Both values are out of 10,000.
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[contracttype]
+pub struct EmodeCategory {
+    pub ltv: u32,
+    pub liquidation_threshold: u32,
+}
+
+/// Every risk parameter of one market, as returned by `get_reserve_config`.
+///
+/// Ratios are out of 10,000, caps and amounts are in token units (0 means
+/// no cap), and `price` has `price_decimals` decimals (0 when unset).
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[contracttype]
+pub struct ReserveConfig {
+    pub ltv: u32,
+    pub liquidation_threshold: u32,
+    pub liquidation_bonus: u32,
+    pub reserve_factor: u32,
+    pub price: i128,
+    pub borrow_cap: i128,
+    pub supply_cap: i128,
+    /// Whether the pool accepts new supply and borrows (see `deactivate_pool`).
+    pub active: bool,
+    /// Whether the whole protocol is paused.
+    pub paused: bool,
+}
+
+/// A user's whole position at a glance, as returned by `get_account_data`.
+///
+/// Values are in the protocol's value units: token amounts scaled to
+/// `ACCOUNTING_DECIMALS` times `price_decimals` prices. Ratios are out of
+/// 10,000.
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[contracttype]
+pub struct AccountData {
+    /// Market value of the deposits that count as collateral.
+    pub total_collateral_value: i128,
+    pub total_debt_value: i128,
+    /// Value that can still be borrowed before reaching the LTV limit.
+    pub available_borrows_value: i128,
+    /// Collateral-weighted average LTV.
+    pub current_ltv: u32,
+    /// Collateral-weighted average liquidation threshold.
+    pub liquidation_threshold: u32,
+    /// As returned by `get_health_factor`.
+    pub health_factor: i128,
+}
+
+/// A position valued in one pass by `LendingPool::get_user_health`.
+///
+/// Values are accounting units times `price_decimals` prices. `borrow_limit`
+/// weights collateral by LTV and caps new debt; `liquidation_limit` weights
+/// it by liquidation threshold and decides when the position can be
+/// liquidated.
+struct AccountHealth {
+    collateral_value: i128,
+    borrow_limit: i128,
+    liquidation_limit: i128,
+    debt_value: i128,
+}
+
+/// Why `LendingPool::plan_borrow` refused a borrow.
+enum BorrowError {
+    Refused(&'static str),
+    /// (attempted total debt, cap)
+    BorrowCap(i128, i128),
+}
+
+impl BorrowError {
+    fn raise(self) -> ! {
+        match self {
+            BorrowError::Refused(reason) => panic!("{}", reason),
+            BorrowError::BorrowCap(attempted, cap) => {
+                panic!("borrow cap exceeded: {} > {}", attempted, cap)
+            }
+        }
+    }
+}
+
+/// Where a price is read from.
+enum PriceSource {
+    /// The price last written by `set_price` or `set_prices`.
+    Stored,
+    /// The Reflector oracle, or the median of the `set_oracles` feeds,
+    /// falling back to the stored price for assets none of them quote.
+    Oracle,
+    /// The given prices, and each token's own source for tokens not in the map.
+    Override(Map<Address, i128>),
+}
+
 #[contract]
 pub struct LendingPool;
 
@@ -37,304 +316,4230 @@ impl LendingPool {
     // --- Constructor & Admin ---
 
     /// Initializes the contract and sets the administrator.
-    pub fn __constructor(e: Env, admin: Address) {
+    /// Every price the pool stores, reads from its oracle or reports has
+    /// `price_decimals` decimal places, fixed for the life of the contract.
+    ///
+    /// `close_factor`, `liquidation_bonus` and `max_price_age` optionally
+    /// replace the protocol's defaults, as `set_close_factor` and
+    /// `set_max_price_age` would. `liquidation_bonus` applies to every asset
+    /// without a bonus of its own from `set_liquidation_bonus`.
+    pub fn __constructor(
+        e: Env,
+        admin: Address,
+        price_decimals: u32,
+        close_factor: Option<u32>,
+        liquidation_bonus: Option<u32>,
+        max_price_age: Option<u64>,
+    ) {
+        if price_decimals > 18 {
+            panic!("price decimals cannot be over 18");
+        }
+        let storage = e.storage().persistent();
+        if let Some(close_factor) = close_factor {
+            Self::require_valid_close_factor(close_factor);
+            storage.set(&DataKey::CloseFactor, &close_factor);
+        }
+        if let Some(bonus) = liquidation_bonus {
+            Self::require_valid_liquidation_bonus(bonus);
+            storage.set(&ExtraKey::DefaultLiquidationBonus, &bonus);
+        }
+        if let Some(max_age) = max_price_age {
+            storage.set(&DataKey::MaxPriceAge, &max_age);
+        }
         // Use persistent storage for the owner.
         // `instance` storage is loaded on every call and best for
         // data needed *every time*. Persistent is better for most data.
         e.storage().persistent().set(&DataKey::Owner, &admin);
+        e.storage()
+            .persistent()
+            .set(&DataKey::PriceDecimals, &price_decimals);
+        e.storage()
+            .persistent()
+            .set(&DataKey::Version, &STORAGE_VERSION);
     }
 
-    /// (Admin) Adds a new token to be used as a lending pool.
-    pub fn init_pool(e: Env, token: Address) {
+    /// (Admin) Brings storage written by an older version of this contract
+    /// up to `STORAGE_VERSION`, rewriting the pools for `tokens` (every
+    /// initialized pool) in the current layout. Runs once per version.
+    /// Version 0 kept no pool list, so `tokens` also becomes `list_pools`.
+    ///
+    /// Version 0 pools gain the fields they lacked: their cash is what was
+    /// supplied and not borrowed, the borrow index starts at 1.0,
+    /// interest starts accruing now and the token's decimals are recorded.
+    pub fn migrate(e: Env, tokens: Vec<Address>) {
         Self::get_owner(&e).require_auth();
-
-        let key = DataKey::Pool(token.clone());
-        if e.storage().persistent().has(&key) {
-            panic!("pool already initialized");
+        let version: u32 = e.storage().persistent().get(&DataKey::Version).unwrap_or(0);
+        if version >= STORAGE_VERSION {
+            panic!("storage already migrated");
         }
 
-        // NOTE: Manually construct `Pool` instead of using `..Default`.
-        // We must clone `token` here because it's not `Copy` and is moved.
-        e.storage().persistent().set(
-            &key,
-            &Pool {
-                token: token.clone(), // This clone fixes the move error.
-                total_supply_shares: 0,
-                total_debt_shares: 0,
-                total_reserves: 0,
-            },
-        );
-
-        // Initialize LTV and Price to 0
+        for token in tokens.iter() {
+            let key = DataKey::Pool(token.clone());
+            let old: PoolV0 = e
+                .storage()
+                .persistent()
+                .get(&key)
+                .expect("pool not initialized");
+            let pool = Pool {
+                token,
+                total_supply_shares: old.total_supply_shares,
+                total_debt_shares: old.total_debt_shares,
+                total_reserves: old.total_reserves,
+                total_cash: old
+                    .total_supply_shares
+                    .checked_sub(old.total_debt_shares)
+                    .expect("underflow"),
+                borrow_index: INDEX_SCALE,
+                last_accrual: e.ledger().timestamp(),
+            };
+            Self::record_decimals(&e, &pool.token);
+            e.storage().persistent().set(&key, &pool);
+        }
+        e.storage().persistent().set(&DataKey::PoolList, &tokens);
         e.storage()
             .persistent()
-            .set(&DataKey::Ltv(token.clone()), &0u32);
-        e.storage().persistent().set(&DataKey::Price(token), &0i128); // `token` is moved here.
+            .set(&DataKey::Version, &STORAGE_VERSION);
     }
 
-    /// (Admin) Sets the Loan-To-Value ratio for an asset.
-    /// LTV is a number out of 10,000 (e.g., 7500 = 75%).
-    pub fn set_ltv(e: Env, token: Address, ltv: u32) {
-        Self::get_owner(&e).require_auth();
-        if ltv > 10000 {
-            panic!("LTV cannot be over 10000");
+    /// (Admin) Proposes `new_owner` as the next administrator.
+    /// Nothing changes until they call `accept_ownership`, so the role can't
+    /// be handed to an address nobody controls.
+    pub fn propose_owner(e: Env, new_owner: Address) {
+        let owner = Self::get_owner(&e);
+        owner.require_auth();
+        if new_owner == owner {
+            panic!("new owner is the current owner");
         }
-        e.storage().persistent().set(&DataKey::Ltv(token), &ltv);
+        e.storage()
+            .persistent()
+            .set(&DataKey::PendingOwner, &new_owner);
+        Self::publish_admin_action(&e, "pending_owner", None, Some(new_owner));
     }
 
-    /// (Admin) Sets the mock price for an asset.
-    /// Price should have a consistent number of decimals, e.g., 7.
-    /// (e.g., $1.50 = 15000000)
-    pub fn set_price(e: Env, token: Address, price: i128) {
-        Self::get_owner(&e).require_auth();
-        if price < 0 {
-            panic!("price cannot be negative");
+    /// Completes an ownership transfer. Must be authorized by the proposed owner.
+    pub fn accept_ownership(e: Env) {
+        let new_owner: Address = e
+            .storage()
+            .persistent()
+            .get(&DataKey::PendingOwner)
+            .expect("no pending owner");
+        new_owner.require_auth();
+
+        let previous_owner = Self::get_owner(&e);
+        e.storage().persistent().set(&DataKey::Owner, &new_owner);
+        e.storage().persistent().remove(&DataKey::PendingOwner);
+        OwnerChanged {
+            previous_owner,
+            new_owner,
         }
-        e.storage().persistent().set(&DataKey::Price(token), &price);
+        .publish(&e);
     }
 
-    // --- Core Functions ---
-
-    /// Supplies assets to a pool.
-    /// `user` deposits `amount` of `token` into the contract.
-    pub fn supply(e: Env, user: Address, token: Address, amount: i128) {
-        user.require_auth();
-        if amount <= 0 {
-            panic!("amount must be positive");
+    /// (Admin) Withdraws a pending ownership proposal.
+    pub fn cancel_ownership_transfer(e: Env) {
+        Self::get_owner(&e).require_auth();
+        if !e.storage().persistent().has(&DataKey::PendingOwner) {
+            panic!("no pending owner");
         }
-
-        // Get persistent state
-        let mut pool = Self::get_pool(&e, token.clone());
-        let mut user_pos = Self::get_user_pos(&e, user.clone());
-
-        // 1. Transfer tokens from user to this contract
-        let token_client = token::Client::new(&e, &token);
-        token_client.transfer(&user, &e.current_contract_address(), &amount);
-
-        // 2. Update user's deposit balance
-        let new_deposits = user_pos.deposit_shares.get(token.clone()).unwrap_or(0) + amount;
-        user_pos.deposit_shares.set(token.clone(), new_deposits);
-
-        // 3. Update pool's total supply
-        pool.total_supply_shares += amount;
-
-        // 4. Save the updated state
-        Self::save_pool(&e, token, &pool); // `token` is moved here
-        Self::save_user_pos(e, user, &user_pos); // `user` is moved here
+        e.storage().persistent().remove(&DataKey::PendingOwner);
+        Self::publish_admin_action(&e, "pending_owner", None, None::<Address>);
     }
 
-    /// Withdraws assets from a pool.
-    /// `user` withdraws `amount` of `token` from the contract.
-    pub fn withdraw(e: Env, user: Address, token: Address, amount: i128) {
-        user.require_auth();
-        if amount <= 0 {
-            panic!("amount must be positive");
+    /// (Admin) Announces an upgrade to `new_wasm_hash`. It can be applied
+    /// with `upgrade` once `UPGRADE_DELAY` has passed, giving users time to
+    /// exit if they disagree with it. Replaces any upgrade already scheduled.
+    pub fn schedule_upgrade(e: Env, new_wasm_hash: BytesN<32>) {
+        Self::get_owner(&e).require_auth();
+        let ready_at = e.ledger().timestamp() + UPGRADE_DELAY;
+        e.storage()
+            .persistent()
+            .set(&DataKey::PendingUpgrade, &(new_wasm_hash.clone(), ready_at));
+        UpgradeScheduled {
+            wasm_hash: new_wasm_hash.clone(),
+            ready_at,
         }
+        .publish(&e);
+        Self::publish_admin_action(&e, "pending_upgrade", None, Some(new_wasm_hash));
+    }
 
-        // Get persistent state
-        let mut pool = Self::get_pool(&e, token.clone());
-        let mut user_pos = Self::get_user_pos(&e, user.clone());
-
-        // 1. Check user's current deposit
-        let current_deposit = user_pos.deposit_shares.get(token.clone()).unwrap_or(0);
-        if current_deposit == 0 {
-            panic!("no assets to withdraw");
+    /// (Admin) Withdraws an upgrade scheduled with `schedule_upgrade`.
+    pub fn cancel_upgrade(e: Env) {
+        Self::get_owner(&e).require_auth();
+        if !e.storage().persistent().has(&DataKey::PendingUpgrade) {
+            panic!("no pending upgrade");
         }
+        e.storage().persistent().remove(&DataKey::PendingUpgrade);
+        Self::publish_admin_action(&e, "pending_upgrade", None, None::<BytesN<32>>);
+    }
 
-        // 2. Determine actual amount to withdraw (can't over-withdraw)
-        let amount_to_withdraw = amount.min(current_deposit);
-
-        user_pos
-            .deposit_shares
-            .set(token.clone(), current_deposit - amount_to_withdraw);
-        let (collateral_value, debt_value) = Self::get_user_health(&e, &user_pos);
-
-        if collateral_value < debt_value {
-            // Revert state before panicking
-            user_pos.deposit_shares.set(token.clone(), current_deposit);
-            panic!("insufficient collateral after withdrawal");
+    /// (Admin) Replaces this contract's code with `new_wasm_hash`, which
+    /// must have been scheduled at least `UPGRADE_DELAY` seconds ago.
+    /// Storage is kept as is.
+    pub fn upgrade(e: Env, new_wasm_hash: BytesN<32>) {
+        Self::get_owner(&e).require_auth();
+        let (scheduled_hash, ready_at): (BytesN<32>, u64) = e
+            .storage()
+            .persistent()
+            .get(&DataKey::PendingUpgrade)
+            .expect("no pending upgrade");
+        if scheduled_hash != new_wasm_hash {
+            panic!("upgrade does not match the scheduled hash");
         }
-
-        // 4. Check for available liquidity in the pool
-        let available_liquidity = pool.total_supply_shares - pool.total_debt_shares;
-        if amount_to_withdraw > available_liquidity {
-            panic!("insufficient liquidity in the pool");
+        if e.ledger().timestamp() < ready_at {
+            panic!("upgrade timelock has not passed");
         }
 
-        // 5. Update pool's total supply
-        pool.total_supply_shares -= amount_to_withdraw;
-
-        // 6. Transfer tokens from this contract to the user
-        let token_client = token::Client::new(&e, &token);
-        token_client.transfer(&e.current_contract_address(), &user, &amount_to_withdraw);
-
-        // 7. Save the updated state (user_pos is already updated)
-        Self::save_pool(&e, token, &pool);
-        Self::save_user_pos(e, user, &user_pos);
+        e.storage().persistent().remove(&DataKey::PendingUpgrade);
+        e.deployer().update_current_contract_wasm(new_wasm_hash);
     }
 
-    /// Repays a debt.
-    /// `user` repays `amount` of `token` to the contract.
-    pub fn repay(e: Env, user: Address, token: Address, amount: i128) {
-        user.require_auth();
-        if amount <= 0 {
-            panic!("amount must be positive");
-        }
-
-        // Get persistent state
-        let mut pool = Self::get_pool(&e, token.clone());
-        let mut user_pos = Self::get_user_pos(&e, user.clone());
+    /// (Admin) Adds a new token to be used as a lending pool.
+    /// Returns the new, empty `Pool`.
+    ///
+    /// Any SEP-41 token works, including native XLM through its Stellar
+    /// Asset Contract (7 decimals, like every SAC): list the native SAC's
+    /// address and it behaves like any other asset. A token paid out as
+    /// rewards can't be listed.
+    pub fn init_pool(e: Env, token: Address) -> Pool {
+        Self::get_owner(&e).require_auth();
+        Self::require_not_self(&e, &token);
 
-        // 1. Check user's current debt
-        let current_debt = user_pos.debt_shares.get(token.clone()).unwrap_or(0);
-        if current_debt == 0 {
-            panic!("no debt to repay");
+        let key = DataKey::Pool(token.clone());
+        if e.storage().persistent().has(&key) {
+            panic!("pool already initialized");
+        }
+        // Suppliers' deposits would be paid out as rewards
+        for listed in Self::get_pool_list(&e).iter() {
+            let config: Option<EmissionConfig> = e
+                .storage()
+                .persistent()
+                .get(&DataKey::EmissionConfig(listed));
+            if config.is_some_and(|config| config.reward_token == token) {
+                panic!("reward token cannot be a pool asset");
+            }
         }
 
-        // 2. Determine actual amount to repay (can't overpay)
-        let amount_to_repay = amount.min(current_debt);
+        // Make sure `token` speaks the token interface before listing it,
+        // and record its decimals so its amounts can be normalized
+        Self::record_decimals(&e, &token);
 
-        // 3. Transfer tokens from user to this contract
-        let token_client = token::Client::new(&e, &token);
-        token_client.transfer(&user, &e.current_contract_address(), &amount_to_repay);
+        // NOTE: Manually construct `Pool` instead of using `..Default`.
+        // We must clone `token` here because it's not `Copy` and is moved.
+        let pool = Pool {
+            token: token.clone(), // This clone fixes the move error.
+            total_supply_shares: 0,
+            total_debt_shares: 0,
+            total_reserves: 0,
+            total_cash: 0,
+            borrow_index: INDEX_SCALE,
+            last_accrual: e.ledger().timestamp(),
+        };
+        e.storage().persistent().set(&key, &pool);
 
-        // 4. Update user's debt balance
-        user_pos
-            .debt_shares
-            .set(token.clone(), current_debt - amount_to_repay);
+        // List the pool so integrators can discover it
+        let mut pools = Self::get_pool_list(&e);
+        pools.push_back(token.clone());
+        e.storage().persistent().set(&DataKey::PoolList, &pools);
 
-        // 5. Update pool's total debt
-        pool.total_debt_shares -= amount_to_repay;
+        // Initialize LTV and Price to 0
+        e.storage()
+            .persistent()
+            .set(&DataKey::Ltv(token.clone()), &0u32);
+        e.storage()
+            .persistent()
+            .set(&DataKey::Price(token.clone()), &0i128);
 
-        // 6. Save the updated state
-        Self::save_pool(&e, token, &pool);
-        Self::save_user_pos(e, user, &user_pos);
+        PoolInitialized { token }.publish(&e); // `token` is moved here.
+        pool
     }
 
-    /// Borrows assets from a pool.
-    pub fn borrow(e: Env, user: Address, token: Address, amount: i128) {
-        user.require_auth();
-        if amount <= 0 {
-            panic!("amount must be positive");
+    /// (Admin) Retires a pool: `supply` and `borrow` stop, while `withdraw`,
+    /// `repay` and `liquidate` keep working so existing positions can wind
+    /// down. Outstanding debt keeps accruing interest until repaid.
+    pub fn deactivate_pool(e: Env, token: Address) {
+        Self::get_owner(&e).require_auth();
+        Self::get_pool(&e, token.clone());
+        e.storage()
+            .persistent()
+            .set(&DataKey::PoolActive(token.clone()), &false);
+        PoolDeactivated {
+            token: token.clone(),
         }
+        .publish(&e);
+        Self::publish_admin_action(&e, "pool_active", Some(token), false);
+    }
 
-        let mut user_pos = Self::get_user_pos(&e, user.clone());
-        let (collateral_value, mut debt_value) = Self::get_user_health(&e, &user_pos);
-
-        // 2. Calculate new debt value
-        let price = Self::get_price(&e, token.clone());
-        if price <= 0 {
-            panic!("price for this asset is not set");
+    /// (Admin or guardian) Freezes or unfreezes a pool. A frozen pool takes no new
+    /// `supply` or `borrow`, but `withdraw`, `repay` and `liquidate` work
+    /// as usual, so an asset can be wound down. Unlike `deactivate_pool`,
+    /// a freeze can be lifted, but only by the owner.
+    pub fn set_frozen(e: Env, caller: Address, token: Address, frozen: bool) {
+        if frozen {
+            Self::require_owner_or_guardian(&e, &caller);
+        } else {
+            Self::require_owner_caller(&e, &caller);
         }
-        let new_debt_value = amount.checked_mul(price).expect("overflow");
-        debt_value += new_debt_value;
-
-        // 3. Check if health factor is safe
-        //    (Total Collateral Value must be >= Total Debt Value)
-        if collateral_value < debt_value {
-            panic!("insufficient collateral");
+        Self::require_listed(&e, &token);
+        let key = ExtraKey::Frozen(token.clone());
+        if frozen {
+            e.storage().persistent().set(&key, &true);
+            Frozen {
+                token: token.clone(),
+            }
+            .publish(&e);
+        } else {
+            e.storage().persistent().remove(&key);
+            Unfrozen {
+                token: token.clone(),
+            }
+            .publish(&e);
         }
+        Self::publish_admin_action(&e, "frozen", Some(token), frozen);
+    }
 
-        // 4. Get persistent pool state
-        let mut pool = Self::get_pool(&e, token.clone());
+    /// (Admin) Sets the collateral value share, out of 10,000, below which a
+    /// position holding `token` can be liquidated. Defaults to the asset's LTV.
+    /// Must be at least the LTV, or new borrows would be liquidatable at once.
+    /// Timelocked while `set_timelock_delay` is on.
+    pub fn set_liquidation_threshold(e: Env, token: Address, threshold: u32) {
+        Self::get_owner(&e).require_auth();
+        Self::require_not_timelocked(&e);
+        Self::write_liquidation_threshold(&e, token, threshold);
+    }
 
-        // 5. Check for available liquidity in the pool
-        // (Reserves are not implemented, so just total supply - total debt)
-        let available_liquidity = pool.total_supply_shares - pool.total_debt_shares;
-        if amount > available_liquidity {
-            panic!("insufficient liquidity in the pool");
+    pub(crate) fn write_liquidation_threshold(e: &Env, token: Address, threshold: u32) {
+        Self::require_listed(e, &token);
+        if threshold > 10000 {
+            panic!("liquidation threshold cannot be over 10000");
         }
+        if threshold < Self::get_ltv(e, token.clone()) {
+            panic!("liquidation threshold cannot be below LTV");
+        }
+        e.storage()
+            .persistent()
+            .set(&DataKey::LiquidationThreshold(token.clone()), &threshold);
+        Self::publish_admin_action(e, "liquidation_threshold", Some(token), threshold);
+    }
 
-        // 6. Update user's debt
-        let new_debt = user_pos.debt_shares.get(token.clone()).unwrap_or(0) + amount;
-        user_pos.debt_shares.set(token.clone(), new_debt);
-
-        // 7. Update pool's total debt
-        pool.total_debt_shares += amount;
-
-        // 8. Transfer tokens from this contract to the user
-        let token_client = token::Client::new(&e, &token);
-        token_client.transfer(&e.current_contract_address(), &user, &amount);
-
-        // 9. Save the updated state
-        Self::save_pool(&e, token, &pool);
-        Self::save_user_pos(e, user, &user_pos);
+    /// (Admin) Creates or updates e-mode category `id`. 0 means "no
+    /// category" and can't be configured. While `set_timelock_delay` is
+    /// on, an existing category only changes through the timelock.
+    pub fn set_emode_category(e: Env, id: u32, category: EmodeCategory) {
+        Self::get_owner(&e).require_auth();
+        if Self::get_emode_category(&e, id).is_some() {
+            Self::require_not_timelocked(&e);
+        }
+        Self::write_emode_category(&e, id, category);
     }
 
-    // --- Helper Functions ---
-    fn get_owner(e: &Env) -> Address {
+    pub(crate) fn write_emode_category(e: &Env, id: u32, category: EmodeCategory) {
+        if id == 0 {
+            panic!("e-mode category 0 is reserved");
+        }
+        if category.ltv > category.liquidation_threshold || category.liquidation_threshold > 10000 {
+            panic!("invalid e-mode parameters");
+        }
         e.storage()
             .persistent()
-            .get(&DataKey::Owner)
-            .expect("owner not set")
+            .set(&DataKey::EmodeCategory(id), &category);
+        Self::publish_admin_action(e, "emode_category", None, (id, category));
     }
 
-    /// Gets the `Pool` struct for a given `token`.
-    fn get_pool(e: &Env, token: Address) -> Pool {
+    /// (Admin) Puts `token` in e-mode category `id`, or takes it out with 0.
+    /// Timelocked while `set_timelock_delay` is on.
+    pub fn set_asset_emode(e: Env, token: Address, id: u32) {
+        Self::get_owner(&e).require_auth();
+        Self::require_not_timelocked(&e);
+        Self::write_asset_emode(&e, token, id);
+    }
+
+    pub(crate) fn write_asset_emode(e: &Env, token: Address, id: u32) {
+        if id != 0 && Self::get_emode_category(e, id).is_none() {
+            panic!("e-mode category not found");
+        }
         e.storage()
             .persistent()
-            .get(&DataKey::Pool(token))
-            .expect("pool not initialized")
+            .set(&DataKey::AssetEmode(token.clone()), &id);
+        Self::publish_admin_action(e, "asset_emode", Some(token), id);
     }
 
-    /// Saves the `Pool` struct for a given `token`.
-    fn save_pool(e: &Env, token: Address, pool: &Pool) {
-        e.storage().persistent().set(&DataKey::Pool(token), pool);
+    /// (Admin) Sets the Loan-To-Value ratio for an asset.
+    /// LTV is a number out of 10,000 (e.g., 7500 = 75%).
+    /// Can't exceed a liquidation threshold set with `set_liquidation_threshold`.
+    /// Timelocked while `set_timelock_delay` is on.
+    pub fn set_ltv(e: Env, token: Address, ltv: u32) {
+        Self::get_owner(&e).require_auth();
+        Self::require_not_timelocked(&e);
+        Self::write_ltv(&e, token, ltv);
     }
 
-    /// Gets the `UserPosition` struct for a given `user`.
-    /// Returns a new, empty struct if this is a new user.
-    fn get_user_pos(e: &Env, user: Address) -> UserPosition {
+    pub(crate) fn write_ltv(e: &Env, token: Address, ltv: u32) {
+        Self::require_listed(e, &token);
+        if ltv > 10000 {
+            panic!("LTV cannot be over 10000");
+        }
+        let threshold: Option<u32> = e
+            .storage()
+            .persistent()
+            .get(&DataKey::LiquidationThreshold(token.clone()));
+        if threshold.is_some_and(|threshold| ltv > threshold) {
+            panic!("LTV cannot exceed liquidation threshold");
+        }
         e.storage()
             .persistent()
-            .get(&DataKey::UserPos(user))
-            .unwrap_or_else(|| UserPosition {
-                deposit_shares: Map::new(e),
-                debt_shares: Map::new(e),
-            })
+            .set(&DataKey::Ltv(token.clone()), &ltv);
+        Self::publish_admin_action(e, "ltv", Some(token), ltv);
     }
 
-    fn save_user_pos(e: Env, user: Address, pos: &UserPosition) {
-        e.storage().persistent().set(&DataKey::UserPos(user), pos);
+    /// (Admin) Sets how long, in seconds, `set_ltv`,
+    /// `set_liquidation_threshold` and e-mode changes wait in the queue; 0 turns the
+    /// timelock off. Raising the delay applies at once, while lowering it
+    /// is itself queued.
+    pub fn set_timelock_delay(e: Env, delay: u64) {
+        Self::get_owner(&e).require_auth();
+        if delay < Self::timelock_delay(&e) {
+            panic!("lower the delay with queue_param_change");
+        }
+        Self::write_timelock_delay(&e, delay);
     }
 
-    fn get_ltv(e: &Env, token: Address) -> u32 {
+    pub(crate) fn write_timelock_delay(e: &Env, delay: u64) {
         e.storage()
             .persistent()
-            .get(&DataKey::Ltv(token))
-            .unwrap_or(0)
+            .set(&ExtraKey::TimelockDelay, &delay);
+        Self::publish_admin_action(e, "timelock_delay", None, delay);
     }
 
-    fn get_price(e: &Env, token: Address) -> i128 {
-        e.storage()
-            .persistent()
-            .get(&DataKey::Price(token))
-            .unwrap_or(0)
+    /// (Admin) Queues setting `key` to `value`, to be applied by
+    /// `execute_param_change` once the timelock delay has passed. Returns
+    /// the change's id. The value is checked when it is applied.
+    pub fn queue_param_change(e: Env, key: ParamKey, value: u64) -> u32 {
+        Self::get_owner(&e).require_auth();
+        let storage = e.storage().persistent();
+        let id: u32 = storage.get(&ExtraKey::NextChangeId).unwrap_or(0);
+        storage.set(&ExtraKey::NextChangeId, &(id + 1));
+        let change = PendingChange {
+            key,
+            value,
+            eta: e.ledger().timestamp() + Self::timelock_delay(&e),
+        };
+        let mut changes = Self::pending_changes(&e);
+        changes.set(id, change.clone());
+        Self::save_pending_changes(&e, &changes);
+        ParamChangeQueued { id, change }.publish(&e);
+        id
     }
 
-    fn get_user_health(e: &Env, user_pos: &UserPosition) -> (i128, i128) {
-        let mut total_collateral_value: i128 = 0;
-        let mut total_debt_value: i128 = 0;
+    /// (Admin) Applies the queued change `id` once its `eta` has passed.
+    pub fn execute_param_change(e: Env, id: u32) {
+        Self::get_owner(&e).require_auth();
+        let mut changes = Self::pending_changes(&e);
+        let change = changes.get(id).expect("no pending change");
+        if e.ledger().timestamp() < change.eta {
+            panic!("timelock not expired");
+        }
+        changes.remove(id);
+        Self::save_pending_changes(&e, &changes);
+        Self::apply_param_change(&e, change.key, change.value);
+    }
 
-        for (token, amount) in user_pos.deposit_shares.iter() {
-            let price = Self::get_price(e, token.clone());
-            let ltv = Self::get_ltv(e, token);
-            let value = amount.checked_mul(price).expect("overflow");
-            let collateral_value = value
-                .checked_mul(ltv as i128)
-                .expect("overflow")
-                .checked_div(10000)
-                .expect("div by zero");
-            total_collateral_value += collateral_value;
+    /// (Admin) Drops the queued change `id` without applying it.
+    pub fn cancel_param_change(e: Env, id: u32) {
+        Self::get_owner(&e).require_auth();
+        let mut changes = Self::pending_changes(&e);
+        if changes.remove(id).is_none() {
+            panic!("no pending change");
+        }
+        Self::save_pending_changes(&e, &changes);
+        ParamChangeCancelled { id }.publish(&e);
+    }
+
+    /// (Admin) Updates every risk parameter of `token` in one call, so a
+    /// price and the LTV that goes with it never apply separately. Takes
+    /// the values `get_reserve_config` returns; `active` and `paused` must
+    /// be left as they are (see `deactivate_pool` and `pause`). The price is
+    /// only written when it changes.
+    pub fn set_reserve_config(e: Env, token: Address, config: ReserveConfig) {
+        Self::get_owner(&e).require_auth();
+        let previous = Self::get_reserve_config(e.clone(), token.clone());
+        if config.active != previous.active || config.paused != previous.paused {
+            panic!("pool status cannot be changed with set_reserve_config");
+        }
+        if config.liquidation_threshold > 10000 {
+            panic!("liquidation threshold cannot be over 10000");
+        }
+        if config.ltv > config.liquidation_threshold {
+            panic!("LTV cannot exceed liquidation threshold");
+        }
+        if config.ltv != previous.ltv
+            || config.liquidation_threshold != previous.liquidation_threshold
+        {
+            Self::require_not_timelocked(&e);
+        }
+        Self::require_valid_liquidation_bonus(config.liquidation_bonus);
+        if config.reserve_factor > 10000 {
+            panic!("reserve factor cannot be over 10000");
+        }
+        if config.borrow_cap < 0 || config.supply_cap < 0 {
+            panic!("cap cannot be negative");
         }
 
-        // Calculate total debt value
-        for (token, amount) in user_pos.debt_shares.iter() {
-            let price = Self::get_price(e, token);
-            let debt_value = amount.checked_mul(price).expect("overflow");
-            total_debt_value += debt_value;
+        // Settle interest at the old reserve factor before switching.
+        let mut pool = Self::get_pool(&e, token.clone());
+        Self::accrue_interest(&e, &mut pool);
+        Self::save_pool(&e, token.clone(), &pool);
+
+        if config.price != previous.price {
+            Self::write_price(&e, token.clone(), config.price);
+        }
+        let storage = e.storage().persistent();
+        storage.set(&DataKey::Ltv(token.clone()), &config.ltv);
+        storage.set(
+            &DataKey::LiquidationThreshold(token.clone()),
+            &config.liquidation_threshold,
+        );
+        storage.set(
+            &DataKey::LiquidationBonus(token.clone()),
+            &config.liquidation_bonus,
+        );
+        storage.set(
+            &DataKey::ReserveFactor(token.clone()),
+            &config.reserve_factor,
+        );
+        storage.set(&DataKey::BorrowCap(token.clone()), &config.borrow_cap);
+        storage.set(&DataKey::SupplyCap(token.clone()), &config.supply_cap);
+        ReserveConfigUpdated {
+            token: token.clone(),
+            previous,
+            config: config.clone(),
         }
+        .publish(&e);
+        Self::publish_admin_action(&e, "reserve_config", Some(token), config);
+    }
 
-        (total_collateral_value, total_debt_value)
+    /// (Admin) Sets the mock price for an asset.
+    /// Price has `price_decimals` decimals
+    /// (e.g., $1.50 = 15000000 with 7)
+    pub fn set_price(e: Env, token: Address, price: i128) {
+        Self::get_owner(&e).require_auth();
+        Self::write_price(&e, token.clone(), price);
+        Self::publish_admin_action(&e, "price", Some(token), price);
+    }
+
+    /// (Admin) Sets the mock prices of several assets atomically.
+    /// Panics without writing anything if any price is negative.
+    pub fn set_prices(e: Env, prices: Map<Address, i128>) {
+        Self::get_owner(&e).require_auth();
+        for (token, price) in prices.iter() {
+            Self::write_price(&e, token.clone(), price);
+            Self::publish_admin_action(&e, "price", Some(token), price);
+        }
+        PricesUpdated {
+            count: prices.len(),
+        }
+        .publish(&e);
+    }
+
+    /// (Admin) Sets the maximum age, in seconds, of a price used by
+    /// `borrow`, `withdraw`, and `liquidate`. 0 disables the check.
+    pub fn set_max_price_age(e: Env, max_age: u64) {
+        Self::get_owner(&e).require_auth();
+        e.storage()
+            .persistent()
+            .set(&DataKey::MaxPriceAge, &max_age);
+        Self::publish_admin_action(&e, "max_price_age", None, max_age);
+    }
+
+    /// (Admin) Sets the largest move, out of 10,000, a new stored price may
+    /// make from the previous one. A bigger move still stores the price but
+    /// freezes borrows and liquidations involving the asset until
+    /// `clear_price_freeze`. An oracle price that moves as far from the
+    /// last one accepted freezes every call that prices the asset, until
+    /// the oracle is back in range or `clear_price_freeze`. 0 disables the
+    /// check.
+    pub fn set_max_price_deviation(e: Env, max_deviation: u32) {
+        Self::get_owner(&e).require_auth();
+        e.storage()
+            .persistent()
+            .set(&DataKey::MaxPriceDeviation, &max_deviation);
+        Self::publish_admin_action(&e, "max_price_deviation", None, max_deviation);
+    }
+
+    /// (Admin) Lifts a freeze placed on `token` by a large price move,
+    /// once the new price has been confirmed. The next oracle price read
+    /// for `token` is accepted as is.
+    pub fn clear_price_freeze(e: Env, token: Address) {
+        Self::get_owner(&e).require_auth();
+        e.storage()
+            .persistent()
+            .remove(&DataKey::PriceFrozen(token.clone()));
+        e.storage()
+            .persistent()
+            .remove(&ExtraKey::AcceptedPrice(token.clone()));
+        Self::publish_admin_action(&e, "price_frozen", Some(token), false);
+    }
+
+    /// (Admin) Sets the Reflector price-feed contract used by oracle-priced assets.
+    pub fn set_oracle(e: Env, oracle: Address) {
+        Self::get_owner(&e).require_auth();
+        e.storage().persistent().set(&DataKey::Oracle, &oracle);
+        Self::publish_admin_action(&e, "oracle", None, oracle);
+    }
+
+    /// (Admin) Prices oracle-priced assets at the median of several
+    /// Reflector feeds instead of the one set by `set_oracle`. Feeds
+    /// without a price for the asset, with one older than
+    /// `set_max_price_age` allows, or whose calls fail, are left out, and
+    /// pricing panics unless at least `min_fresh` remain. An empty list
+    /// goes back to `set_oracle`.
+    pub fn set_oracles(e: Env, oracles: Vec<Address>, min_fresh: u32) {
+        Self::get_owner(&e).require_auth();
+        let storage = e.storage().persistent();
+        if oracles.is_empty() {
+            storage.remove(&ExtraKey::Oracles);
+            storage.remove(&ExtraKey::MinOracles);
+        } else {
+            if min_fresh == 0 || min_fresh > oracles.len() {
+                panic!("min fresh oracles must be between 1 and the oracle count");
+            }
+            storage.set(&ExtraKey::Oracles, &oracles);
+            storage.set(&ExtraKey::MinOracles, &min_fresh);
+        }
+        Self::publish_admin_action(&e, "oracles", None, (oracles, min_fresh));
+    }
+
+    /// (Admin) Switches an asset between oracle pricing and the price
+    /// stored by `set_price`.
+    pub fn set_use_oracle(e: Env, token: Address, enabled: bool) {
+        Self::get_owner(&e).require_auth();
+        let storage = e.storage().persistent();
+        if enabled && !storage.has(&DataKey::Oracle) && !storage.has(&ExtraKey::Oracles) {
+            panic!("oracle not set");
+        }
+        e.storage()
+            .persistent()
+            .set(&DataKey::UseOracle(token.clone()), &enabled);
+        Self::publish_admin_action(&e, "use_oracle", Some(token), enabled);
+    }
+
+    /// (Admin) Sets the liquidation bonus for a collateral asset.
+    /// Bonus is a number out of 10,000 (e.g., 500 = 5%) and is paid to
+    /// liquidators as extra collateral on top of the repaid value.
+    pub fn set_liquidation_bonus(e: Env, token: Address, bonus: u32) {
+        Self::get_owner(&e).require_auth();
+        Self::require_valid_liquidation_bonus(bonus);
+        e.storage()
+            .persistent()
+            .set(&DataKey::LiquidationBonus(token.clone()), &bonus);
+        Self::publish_admin_action(&e, "liquidation_bonus", Some(token), bonus);
+    }
+
+    /// (Admin) Sets the least collateral, in `token`'s own units, that a
+    /// liquidation seizing `token` pays out, so small positions stay worth
+    /// liquidating. Only a liquidation repaying all the close factor allows
+    /// gets the floor, so it can't be collected again by repaying in
+    /// pieces; on positions small enough that their whole debt may be
+    /// repaid, that means clearing it. The seizure is still capped at the
+    /// borrower's deposit. Defaults to 0 (no floor).
+    pub fn set_min_liquidation_seize(e: Env, token: Address, amount: i128) {
+        Self::get_owner(&e).require_auth();
+        Self::require_listed(&e, &token);
+        if amount < 0 {
+            panic!("minimum seize cannot be negative");
+        }
+        e.storage()
+            .persistent()
+            .set(&ExtraKey::MinLiquidationSeize(token.clone()), &amount);
+        Self::publish_admin_action(&e, "min_liquidation_seize", Some(token), amount);
+    }
+
+    /// (Admin) Sets the protocol's cut of liquidation bonuses: the share of
+    /// the bonus in seized collateral that goes to the pool's reserves
+    /// instead of the liquidator.
+    /// Fee is a number out of 10,000 of the bonus and defaults to 0.
+    pub fn set_liquidation_protocol_fee(e: Env, fee: u32) {
+        Self::get_owner(&e).require_auth();
+        if fee > 10000 {
+            panic!("liquidation protocol fee cannot be over 10000");
+        }
+        e.storage()
+            .persistent()
+            .set(&ExtraKey::LiquidationProtocolFee, &fee);
+        Self::publish_admin_action(&e, "liquidation_protocol_fee", None, fee);
+    }
+
+    /// (Admin) Sets the close factor: the maximum share of a borrower's debt
+    /// in one token that a single liquidation may repay.
+    /// Close factor is a number out of 10,000 and defaults to 5000 (50%).
+    pub fn set_close_factor(e: Env, close_factor: u32) {
+        Self::get_owner(&e).require_auth();
+        Self::require_valid_close_factor(close_factor);
+        e.storage()
+            .persistent()
+            .set(&DataKey::CloseFactor, &close_factor);
+        Self::publish_admin_action(&e, "close_factor", None, close_factor);
+    }
+
+    /// (Admin) Sets the annual borrow interest rate for a pool.
+    /// Rate is a number out of 10,000 (e.g., 500 = 5% APR).
+    pub fn set_interest_rate(e: Env, token: Address, rate: u32) {
+        Self::get_owner(&e).require_auth();
+        if rate > 10000 {
+            panic!("interest rate cannot be over 10000");
+        }
+
+        // Settle interest at the old rate before switching.
+        let mut pool = Self::get_pool(&e, token.clone());
+        Self::accrue_interest(&e, &mut pool);
+        Self::save_pool(&e, token.clone(), &pool);
+
+        e.storage()
+            .persistent()
+            .set(&DataKey::InterestRate(token.clone()), &rate);
+        Self::publish_admin_action(&e, "interest_rate", Some(token), rate);
+    }
+
+    /// (Admin) Sets a utilization-based rate model for a pool.
+    /// Once set, it replaces the flat rate from `set_interest_rate`.
+    pub fn set_rate_model(e: Env, token: Address, model: RateModel) {
+        Self::get_owner(&e).require_auth();
+        if model.optimal_utilization == 0 || model.optimal_utilization > 10000 {
+            panic!("optimal utilization must be between 1 and 10000");
+        }
+        let max_rate = model.base_rate as u64 + model.slope1 as u64 + model.slope2 as u64;
+        if max_rate > 10000 {
+            panic!("interest rate cannot be over 10000");
+        }
+
+        // Settle interest at the old rate before switching.
+        let mut pool = Self::get_pool(&e, token.clone());
+        Self::accrue_interest(&e, &mut pool);
+        Self::save_pool(&e, token.clone(), &pool);
+
+        e.storage()
+            .persistent()
+            .set(&DataKey::RateModel(token.clone()), &model);
+        Self::publish_admin_action(&e, "rate_model", Some(token), model);
+    }
+
+    /// (Admin) Sets the share of accrued interest kept as protocol reserves.
+    /// Reserve factor is a number out of 10,000 (e.g., 1000 = 10%).
+    pub fn set_reserve_factor(e: Env, token: Address, reserve_factor: u32) {
+        Self::get_owner(&e).require_auth();
+        if reserve_factor > 10000 {
+            panic!("reserve factor cannot be over 10000");
+        }
+
+        // Settle interest at the old factor before switching.
+        let mut pool = Self::get_pool(&e, token.clone());
+        Self::accrue_interest(&e, &mut pool);
+        Self::save_pool(&e, token.clone(), &pool);
+
+        e.storage()
+            .persistent()
+            .set(&DataKey::ReserveFactor(token.clone()), &reserve_factor);
+        Self::publish_admin_action(&e, "reserve_factor", Some(token), reserve_factor);
+    }
+
+    /// (Admin) Sets the guardian, who can `pause`, `set_action_paused` and
+    /// `set_frozen` for fast incident response, but can't unpause the
+    /// protocol, change risk parameters or move funds. `None` removes it.
+    pub fn set_guardian(e: Env, guardian: Option<Address>) {
+        Self::get_owner(&e).require_auth();
+        match &guardian {
+            Some(guardian) => e.storage().persistent().set(&ExtraKey::Guardian, guardian),
+            None => e.storage().persistent().remove(&ExtraKey::Guardian),
+        }
+        Self::publish_admin_action(&e, "guardian", None, guardian);
+    }
+
+    /// (Admin or guardian) Pauses `supply`, `borrow`, `liquidate` and,
+    /// unless exits are allowed with `set_exits_while_paused`, `withdraw`
+    /// and `repay`.
+    pub fn pause(e: Env, caller: Address) {
+        Self::require_owner_or_guardian(&e, &caller);
+        e.storage().persistent().set(&DataKey::Paused, &true);
+        Paused {}.publish(&e);
+        Self::publish_admin_action(&e, "paused", None, true);
+    }
+
+    /// (Admin) Lifts a pause set by `pause`. Liquidations stay blocked
+    /// for the grace period set by `set_grace_period`, so borrowers can
+    /// react to prices that moved during the pause.
+    pub fn unpause(e: Env) {
+        Self::get_owner(&e).require_auth();
+        let storage = e.storage().persistent();
+        let now = e.ledger().timestamp();
+        storage.set(&DataKey::Paused, &false);
+        storage.set(&DataKey::UnpauseTimestamp, &now);
+        let grace_period: u64 = storage.get(&DataKey::GracePeriod).unwrap_or(0);
+        Unpaused {
+            grace_ends_at: now + grace_period,
+        }
+        .publish(&e);
+        Self::publish_admin_action(&e, "paused", None, false);
+    }
+
+    /// (Admin) Sets how far, out of 10,000, a borrow must leave a position
+    /// inside its borrow limit: 10100 means the LTV-weighted collateral
+    /// must stay at least 1.01 times the debt. Defaults to 10000, allowing
+    /// borrows right up to the limit.
+    pub fn set_min_health_buffer(e: Env, buffer: u32) {
+        Self::get_owner(&e).require_auth();
+        if buffer < 10000 {
+            panic!("health buffer cannot be below 10000");
+        }
+        e.storage()
+            .persistent()
+            .set(&ExtraKey::MinHealthBuffer, &buffer);
+        Self::publish_admin_action(&e, "min_health_buffer", None, buffer);
+    }
+
+    /// (Admin) Sets the seconds after `unpause` during which `liquidate`
+    /// is blocked. Other actions resume at once. 0 disables the grace period.
+    pub fn set_grace_period(e: Env, seconds: u64) {
+        Self::get_owner(&e).require_auth();
+        e.storage()
+            .persistent()
+            .set(&DataKey::GracePeriod, &seconds);
+        Self::publish_admin_action(&e, "grace_period", None, seconds);
+    }
+
+    /// (Admin) Lets users keep calling `withdraw` and `repay` while the
+    /// protocol is paused, so they can de-risk during an incident.
+    pub fn set_exits_while_paused(e: Env, allowed: bool) {
+        Self::get_owner(&e).require_auth();
+        e.storage()
+            .persistent()
+            .set(&DataKey::ExitsWhilePaused, &allowed);
+        Self::publish_admin_action(&e, "exits_while_paused", None, allowed);
+    }
+
+    /// (Admin or guardian) Pauses or resumes a single action, independently
+    /// of the global `pause`. Only the owner can resume one.
+    pub fn set_action_paused(e: Env, caller: Address, action: Action, paused: bool) {
+        if paused {
+            Self::require_owner_or_guardian(&e, &caller);
+        } else {
+            Self::require_owner_caller(&e, &caller);
+        }
+        e.storage()
+            .persistent()
+            .set(&DataKey::ActionPaused(action), &paused);
+        Self::publish_admin_action(&e, "action_paused", None, (action, paused));
+    }
+
+    /// (Admin) Puts an asset in or out of isolation mode.
+    /// Positions opened before the change keep their collateral choices.
+    pub fn set_isolation(e: Env, token: Address, config: IsolationConfig) {
+        Self::get_owner(&e).require_auth();
+        if config.debt_ceiling < 0 {
+            panic!("debt ceiling cannot be negative");
+        }
+        e.storage()
+            .persistent()
+            .set(&DataKey::Isolation(token.clone()), &config);
+        Self::publish_admin_action(&e, "isolation", Some(token), config);
+    }
+
+    /// (Admin) Sets the smallest debt, in token units, a position may hold
+    /// in `token`. Borrows can't leave less, and liquidations that would
+    /// must clear the debt instead. 0 disables the minimum.
+    pub fn set_min_borrow(e: Env, token: Address, amount: i128) {
+        Self::get_owner(&e).require_auth();
+        if amount < 0 {
+            panic!("minimum borrow cannot be negative");
+        }
+        e.storage()
+            .persistent()
+            .set(&DataKey::MinBorrow(token.clone()), &amount);
+        Self::publish_admin_action(&e, "min_borrow", Some(token), amount);
+    }
+
+    /// (Admin) Turns `token`'s allowlist on or off. While it is on, only
+    /// accounts added with `set_allowed` can hold new deposits or debt in
+    /// the pool; existing positions can still be withdrawn and repaid.
+    pub fn set_allowlist_enabled(e: Env, token: Address, enabled: bool) {
+        Self::get_owner(&e).require_auth();
+        e.storage()
+            .persistent()
+            .set(&DataKey::Allowlist(token.clone()), &enabled);
+        Self::publish_admin_action(&e, "allowlist_enabled", Some(token), enabled);
+    }
+
+    /// (Admin) Adds `account` to or removes it from `token`'s allowlist.
+    pub fn set_allowed(e: Env, token: Address, account: Address, allowed: bool) {
+        Self::get_owner(&e).require_auth();
+        let key = DataKey::Allowed(token.clone(), account.clone());
+        if allowed {
+            e.storage().persistent().set(&key, &true);
+        } else {
+            e.storage().persistent().remove(&key);
+        }
+        Self::publish_admin_action(&e, "allowed", Some(token), (account, allowed));
+    }
+
+    /// (Admin) Caps the total amount that can be borrowed from a pool,
+    /// in token units. 0 removes the cap.
+    pub fn set_borrow_cap(e: Env, token: Address, cap: i128) {
+        Self::get_owner(&e).require_auth();
+        if cap < 0 {
+            panic!("cap cannot be negative");
+        }
+        e.storage()
+            .persistent()
+            .set(&DataKey::BorrowCap(token.clone()), &cap);
+        Self::publish_admin_action(&e, "borrow_cap", Some(token), cap);
+    }
+
+    /// (Admin) Caps the total amount that can be supplied to a pool,
+    /// in token units. 0 removes the cap.
+    pub fn set_supply_cap(e: Env, token: Address, cap: i128) {
+        Self::get_owner(&e).require_auth();
+        if cap < 0 {
+            panic!("cap cannot be negative");
+        }
+        e.storage()
+            .persistent()
+            .set(&DataKey::SupplyCap(token.clone()), &cap);
+        Self::publish_admin_action(&e, "supply_cap", Some(token), cap);
+    }
+
+    /// (Admin) Registers `supply_token` as `token`'s receipt token. Its
+    /// balances read `deposit_shares_of`, and only it may move deposits
+    /// between accounts with `transfer_deposit`. Every other change to a
+    /// deposit is reported to it with `notify_mint` or `notify_burn`.
+    pub fn set_supply_token(e: Env, token: Address, supply_token: Address) {
+        Self::get_owner(&e).require_auth();
+        Self::get_pool(&e, token.clone());
+        e.storage()
+            .persistent()
+            .set(&DataKey::SupplyToken(token.clone()), &supply_token);
+        Self::publish_admin_action(&e, "supply_token", Some(token), supply_token);
+    }
+
+    /// (Admin) Streams `config.reward_token` to `token`'s suppliers and
+    /// borrowers, pro-rata by shares. Rewards are paid from the contract's
+    /// own balance of the reward token, which the owner must fund. Rewards
+    /// earned under the previous rates are kept. The reward token can't be
+    /// a pool asset, whose balance belongs to suppliers, and can't change
+    /// once set.
+    pub fn set_emission_config(e: Env, token: Address, config: EmissionConfig) {
+        Self::get_owner(&e).require_auth();
+        Self::require_listed(&e, &token);
+        if config.supply_per_second < 0 || config.borrow_per_second < 0 {
+            panic!("emission rate cannot be negative");
+        }
+        let storage = e.storage().persistent();
+        if storage.has(&DataKey::Pool(config.reward_token.clone())) {
+            panic!("reward token cannot be a pool asset");
+        }
+
+        let pool = Self::get_pool(&e, token.clone());
+        let index = match Self::current_reward_index(&e, &token, &pool) {
+            Some((old, _)) if old.reward_token != config.reward_token => {
+                panic!("reward token cannot change")
+            }
+            Some((_, index)) => index,
+            None => RewardIndex {
+                supply_index: 0,
+                borrow_index: 0,
+                last_update: e.ledger().timestamp(),
+            },
+        };
+        storage.set(&DataKey::RewardIndex(token.clone()), &index);
+        storage.set(&DataKey::EmissionConfig(token.clone()), &config);
+        Self::publish_admin_action(&e, "emission_config", Some(token), config);
+    }
+
+    /// (Admin) Sets the seconds a user must wait after supplying before
+    /// they can withdraw, so a deposit can't be supplied and pulled back
+    /// in one ledger. A `supply_for` restarts the beneficiary's wait too.
+    /// 0 disables the cooldown.
+    pub fn set_withdraw_cooldown(e: Env, cooldown: u64) {
+        Self::get_owner(&e).require_auth();
+        e.storage()
+            .persistent()
+            .set(&DataKey::WithdrawCooldown, &cooldown);
+        Self::publish_admin_action(&e, "withdraw_cooldown", None, cooldown);
+    }
+
+    /// (Admin) Sets the fee charged on flash loans.
+    /// Fee is a number out of 10,000 (e.g., 9 = 0.09%) and goes to reserves.
+    pub fn set_flash_loan_fee(e: Env, fee: u32) {
+        Self::get_owner(&e).require_auth();
+        if fee > 10000 {
+            panic!("flash loan fee cannot be over 10000");
+        }
+        e.storage().persistent().set(&DataKey::FlashLoanFee, &fee);
+        Self::publish_admin_action(&e, "flash_loan_fee", None, fee);
+    }
+
+    /// (Admin) Sets the flat fee charged on every borrow of `token`, out of
+    /// 10,000. The fee is added to the borrower's debt and credited to
+    /// reserves, for pools that want fixed-fee loans. 0 disables it.
+    pub fn set_origination_fee(e: Env, token: Address, fee: u32) {
+        Self::get_owner(&e).require_auth();
+        if fee > 10000 {
+            panic!("origination fee cannot be over 10000");
+        }
+        e.storage()
+            .persistent()
+            .set(&DataKey::OriginationFee(token.clone()), &fee);
+        Self::publish_admin_action(&e, "origination_fee", Some(token), fee);
+    }
+
+    /// (Admin) Sets the highest utilization, out of 10,000, a borrow may
+    /// leave `token`'s pool at, keeping a liquidity buffer for withdrawals.
+    /// Defaults to 10000 (no limit).
+    pub fn set_max_utilization(e: Env, token: Address, max_utilization: u32) {
+        Self::get_owner(&e).require_auth();
+        if max_utilization > 10000 {
+            panic!("max utilization cannot be over 10000");
+        }
+        e.storage()
+            .persistent()
+            .set(&DataKey::MaxUtilization(token.clone()), &max_utilization);
+        Self::publish_admin_action(&e, "max_utilization", Some(token), max_utilization);
+    }
+
+    /// (Admin) Sends `amount` of a pool's accumulated reserves to `to`.
+    pub fn withdraw_reserves(e: Env, token: Address, to: Address, amount: i128) {
+        Self::get_owner(&e).require_auth();
+        if amount <= 0 {
+            panic!("amount must be positive");
+        }
+
+        let mut pool = Self::get_pool(&e, token.clone());
+        Self::accrue_interest(&e, &mut pool);
+        if amount > pool.total_reserves {
+            panic!("insufficient reserves");
+        }
+        if amount > pool.total_cash {
+            panic!("insufficient liquidity in the pool");
+        }
+
+        pool.total_reserves = pool.total_reserves.checked_sub(amount).expect("underflow");
+        pool.total_cash = pool.total_cash.checked_sub(amount).expect("underflow");
+
+        let token_client = token::Client::new(&e, &token);
+        token_client.transfer(&e.current_contract_address(), &to, &amount);
+
+        Self::save_pool(&e, token.clone(), &pool);
+        ReservesWithdrawn { token, to, amount }.publish(&e);
+    }
+
+    /// (Admin) Writes off `borrower`'s `token` debt once they have no
+    /// deposits left to liquidate. The loss is shared by `token`'s suppliers
+    /// through a lower supply exchange rate. Returns the amount written off.
+    pub fn clear_bad_debt(e: Env, borrower: Address, token: Address) -> i128 {
+        Self::get_owner(&e).require_auth();
+        let mut user_pos = Self::get_user_pos(&e, borrower.clone());
+        if user_pos
+            .deposit_shares
+            .values()
+            .iter()
+            .any(|shares| shares > 0)
+        {
+            panic!("borrower still has collateral");
+        }
+        let debt_shares = user_pos.debt_shares.get(token.clone()).unwrap_or(0);
+        if debt_shares == 0 {
+            panic!("no debt to repay");
+        }
+
+        let mut pool = Self::get_pool(&e, token.clone());
+        Self::accrue_interest(&e, &mut pool);
+        Self::accrue_rewards(&e, &token, &pool, &borrower, &user_pos);
+        let amount = Self::debt_shares_to_amount(debt_shares, pool.borrow_index);
+        pool.total_debt_shares = pool
+            .total_debt_shares
+            .checked_sub(debt_shares)
+            .expect("underflow");
+        user_pos.debt_shares.remove(token.clone());
+
+        Self::save_pool(&e, token.clone(), &pool);
+        Self::save_user_pos(e.clone(), borrower.clone(), &user_pos);
+        BadDebt {
+            token,
+            borrower,
+            amount,
+        }
+        .publish(&e);
+        amount
+    }
+
+    // --- Core Functions ---
+
+    /// Supplies assets to a pool.
+    /// `user` deposits `amount` of `token` into the contract.
+    /// Returns the supply shares minted to `user`.
+    pub fn supply(e: Env, user: Address, token: Address, amount: i128) -> i128 {
+        user.require_auth();
+        Self::non_reentrant(&e, || {
+            Self::do_supply(&e, user.clone(), user, token, amount)
+        })
+    }
+
+    /// Supplies several assets at once, e.g. to open a position backed by
+    /// more than one collateral. `deposits` maps each token to the amount
+    /// to supply. Fails as a whole if any deposit would fail on its own.
+    /// Returns the number of deposits made.
+    pub fn supply_many(e: Env, user: Address, deposits: Map<Address, i128>) -> u32 {
+        user.require_auth();
+        Self::non_reentrant(&e, || {
+            for (token, amount) in deposits.iter() {
+                Self::do_supply(&e, user.clone(), user.clone(), token, amount);
+            }
+            deposits.len()
+        })
+    }
+
+    /// Supplies assets for another account.
+    /// `from` pays `amount` of `token` and `on_behalf_of` is credited with the deposit.
+    /// Returns the supply shares minted to `on_behalf_of`.
+    pub fn supply_for(
+        e: Env,
+        from: Address,
+        on_behalf_of: Address,
+        token: Address,
+        amount: i128,
+    ) -> i128 {
+        from.require_auth();
+        Self::non_reentrant(&e, || {
+            Self::do_supply(&e, from, on_behalf_of, token, amount)
+        })
+    }
+
+    /// Shared body of `supply` and `supply_for`; the caller checks auth.
+    fn do_supply(
+        e: &Env,
+        from: Address,
+        on_behalf_of: Address,
+        token: Address,
+        amount: i128,
+    ) -> i128 {
+        Self::require_can_supply(e, &on_behalf_of, token.clone());
+        if amount <= 0 {
+            panic!("amount must be positive");
+        }
+
+        // Get persistent state
+        let mut pool = Self::get_pool(e, token.clone());
+        Self::accrue_interest(e, &mut pool);
+        let mut user_pos = Self::get_user_pos(e, on_behalf_of.clone());
+        Self::accrue_rewards(e, &token, &pool, &on_behalf_of, &user_pos);
+        Self::check_supply_cap(e, token.clone(), &pool, amount);
+
+        // 1. Transfer tokens from the payer to this contract. Only what
+        //    actually arrives is credited, so fee-on-transfer tokens can't
+        //    leave the pool short.
+        let amount = Self::pull_tokens(e, &token, &from, amount);
+        let shares = Self::mint_deposit(
+            e,
+            &on_behalf_of,
+            token.clone(),
+            &mut pool,
+            &mut user_pos,
+            amount,
+        );
+
+        // 2. Save the updated state
+        Self::save_pool(e, token.clone(), &pool);
+        Self::save_user_pos(e.clone(), on_behalf_of.clone(), &user_pos);
+        Supply {
+            token,
+            from,
+            user: on_behalf_of,
+            amount,
+            shares,
+        }
+        .publish(e);
+        shares
+    }
+
+    /// Panics unless `user` may add to their `token` deposit.
+    fn require_can_supply(e: &Env, user: &Address, token: Address) {
+        Self::require_not_paused(e, Action::Supply);
+        Self::require_pool_active(e, token.clone());
+        if Self::is_frozen(e, token.clone()) {
+            panic!("pool is frozen");
+        }
+        Self::require_allowed(e, token, user.clone());
+    }
+
+    /// Enforces `token`'s supply cap (0 means unlimited) on `amount` more
+    /// supply, and warns off-chain monitors when the pool will be close
+    /// to it.
+    fn check_supply_cap(e: &Env, token: Address, pool: &Pool, amount: i128) {
+        let cap = Self::get_supply_cap(e, token.clone());
+        let total_supply = Self::total_assets(pool)
+            .checked_add(amount)
+            .expect("overflow");
+        if cap > 0 && total_supply > cap {
+            panic!("supply cap exceeded: {} > {}", total_supply, cap);
+        }
+        let warning_level = Self::apply_bps(cap, SUPPLY_CAP_WARNING);
+        if cap > 0 && total_supply >= warning_level {
+            SupplyCapNear {
+                token,
+                total_supply,
+                cap,
+            }
+            .publish(e);
+        }
+    }
+
+    /// Credits `amount` of `token`, already in the pool, to `user`'s
+    /// deposit and returns the shares minted. The caller saves `pool` and
+    /// `user_pos`.
+    fn mint_deposit(
+        e: &Env,
+        user: &Address,
+        token: Address,
+        pool: &mut Pool,
+        user_pos: &mut UserPosition,
+        amount: i128,
+    ) -> i128 {
+        // Mint shares at the current exchange rate, rounding down in the
+        // pool's favor. The first deposit mints 1:1 but locks
+        // `MINIMUM_SHARES` with no owner, so a near-empty pool's exchange
+        // rate can't be inflated to round later depositors down to nothing.
+        let (shares, locked_shares) = if pool.total_supply_shares == 0 {
+            if amount <= MINIMUM_SHARES {
+                panic!("initial deposit too small");
+            }
+            (amount - MINIMUM_SHARES, MINIMUM_SHARES)
+        } else {
+            let shares = amount
+                .checked_mul(pool.total_supply_shares)
+                .expect("overflow")
+                .checked_div(Self::total_assets(pool))
+                .expect("div by zero");
+            (shares, 0)
+        };
+        if shares == 0 {
+            panic!("amount too small to mint shares");
+        }
+        Self::credit_deposit(e, user, user_pos, token.clone(), shares);
+        Self::notify_supply_token(e, &token, user, shares);
+        Self::add_supply_principal(e, user, token, amount);
+        pool.total_supply_shares = pool
+            .total_supply_shares
+            .checked_add(shares + locked_shares)
+            .expect("overflow");
+        pool.total_cash = pool.total_cash.checked_add(amount).expect("overflow");
+        // Starts the withdraw cooldown
+        e.storage()
+            .persistent()
+            .set(&DataKey::LastSupply(user.clone()), &e.ledger().timestamp());
+        shares
+    }
+
+    /// Withdraws assets from a pool.
+    /// `user` withdraws `amount` of `token` from the contract.
+    /// Passing `i128::MAX` withdraws the largest amount that keeps the
+    /// position healthy and fits in the pool's liquidity.
+    /// Returns the supply shares burned from `user`'s deposit.
+    pub fn withdraw(e: Env, user: Address, token: Address, amount: i128) -> i128 {
+        user.require_auth();
+        Self::non_reentrant(&e, || {
+            Self::do_withdraw(&e, user.clone(), token, amount, user)
+        })
+    }
+
+    /// Withdraws assets from a pool to another address.
+    /// `user`'s deposit is reduced and `to` receives the tokens.
+    /// Accepts the same `i128::MAX` sentinel and return value as `withdraw`.
+    pub fn withdraw_to(e: Env, user: Address, token: Address, amount: i128, to: Address) -> i128 {
+        user.require_auth();
+        Self::non_reentrant(&e, || Self::do_withdraw(&e, user, token, amount, to))
+    }
+
+    /// Shared body of `withdraw` and `withdraw_to`; the caller checks auth.
+    fn do_withdraw(e: &Env, user: Address, token: Address, amount: i128, to: Address) -> i128 {
+        Self::require_not_paused(e, Action::Withdraw);
+        if amount <= 0 {
+            panic!("amount must be positive");
+        }
+        Self::require_cooldown_elapsed(e, &user);
+
+        // Get persistent state
+        let mut pool = Self::get_pool(e, token.clone());
+        Self::accrue_interest(e, &mut pool);
+        let user_pos = Self::get_user_pos(e, user.clone());
+
+        // 1. Check user's current deposit
+        let current_shares = user_pos.deposit_shares.get(token.clone()).unwrap_or(0);
+        if current_shares == 0 {
+            panic!("no assets to withdraw");
+        }
+        let current_deposit = Self::supply_shares_to_amount(&pool, current_shares);
+
+        // 2. Determine actual amount to withdraw (can't over-withdraw)
+        let amount_to_withdraw =
+            if amount == i128::MAX {
+                current_deposit.min(Self::lendable_cash(e, &pool)).min(
+                    Self::max_withdraw_for_health(e, &user, &user_pos, token.clone()),
+                )
+            } else {
+                amount.min(current_deposit)
+            };
+        let shares_to_burn = if amount_to_withdraw == current_deposit {
+            current_shares
+        } else {
+            // Round the burn up so a withdrawal never takes more than its shares are worth.
+            Self::mul_div_ceil(
+                amount_to_withdraw,
+                pool.total_supply_shares,
+                Self::total_assets(&pool),
+            )
+        };
+
+        Self::burn_deposit(
+            e,
+            user,
+            token.clone(),
+            pool,
+            user_pos,
+            shares_to_burn,
+            amount_to_withdraw,
+        );
+
+        // 7. Transfer tokens from this contract to the recipient
+        let token_client = token::Client::new(e, &token);
+        token_client.transfer(&e.current_contract_address(), &to, &amount_to_withdraw);
+        shares_to_burn
+    }
+
+    /// Withdraws exactly `shares` of `user`'s `token` deposit and sends the
+    /// underlying they're worth, rounded down, to `user`. Subject to the
+    /// same health and liquidity checks as `withdraw`.
+    /// Returns the amount of `token` paid out.
+    pub fn withdraw_shares(e: Env, user: Address, token: Address, shares: i128) -> i128 {
+        user.require_auth();
+        Self::non_reentrant(&e, || Self::do_withdraw_shares(&e, user, token, shares))
+    }
+
+    /// Body of `withdraw_shares`; the caller checks auth.
+    fn do_withdraw_shares(e: &Env, user: Address, token: Address, shares: i128) -> i128 {
+        Self::require_not_paused(e, Action::Withdraw);
+        if shares <= 0 {
+            panic!("amount must be positive");
+        }
+        Self::require_cooldown_elapsed(e, &user);
+
+        let mut pool = Self::get_pool(e, token.clone());
+        Self::accrue_interest(e, &mut pool);
+        let user_pos = Self::get_user_pos(e, user.clone());
+        let current_shares = user_pos.deposit_shares.get(token.clone()).unwrap_or(0);
+        if shares > current_shares {
+            panic!("insufficient shares");
+        }
+        let amount = Self::supply_shares_to_amount(&pool, shares);
+
+        Self::burn_deposit(
+            e,
+            user.clone(),
+            token.clone(),
+            pool,
+            user_pos,
+            shares,
+            amount,
+        );
+        let token_client = token::Client::new(e, &token);
+        token_client.transfer(&e.current_contract_address(), &user, &amount);
+        amount
+    }
+
+    /// Queues a withdrawal of `amount` of `token` for when the pool has
+    /// the cash, e.g. while utilization is too high for `withdraw`.
+    /// Passing `i128::MAX` queues the largest amount that keeps the
+    /// position healthy. The queued shares leave `user`'s deposit at once,
+    /// so they back no borrowing and can't be withdrawn or moved again,
+    /// but keep earning interest until `claim_withdraw` pays them out.
+    /// They earn no reward emissions, which go to the pool's other
+    /// suppliers instead.
+    /// Cash returning to the pool goes to queued withdrawals before new
+    /// borrows. Returns the supply shares queued.
+    pub fn request_withdraw(e: Env, user: Address, token: Address, amount: i128) -> i128 {
+        user.require_auth();
+        Self::non_reentrant(&e, || Self::do_request_withdraw(&e, user, token, amount))
+    }
+
+    fn do_request_withdraw(e: &Env, user: Address, token: Address, amount: i128) -> i128 {
+        Self::require_not_paused(e, Action::Withdraw);
+        if amount <= 0 {
+            panic!("amount must be positive");
+        }
+        Self::require_cooldown_elapsed(e, &user);
+
+        let mut pool = Self::get_pool(e, token.clone());
+        Self::accrue_interest(e, &mut pool);
+        let mut user_pos = Self::get_user_pos(e, user.clone());
+        let current_shares = user_pos.deposit_shares.get(token.clone()).unwrap_or(0);
+        if current_shares == 0 {
+            panic!("no assets to withdraw");
+        }
+        let current_deposit = Self::supply_shares_to_amount(&pool, current_shares);
+        let amount = if amount == i128::MAX {
+            current_deposit.min(Self::max_withdraw_for_health(
+                e,
+                &user,
+                &user_pos,
+                token.clone(),
+            ))
+        } else {
+            amount.min(current_deposit)
+        };
+        let shares = if amount == current_deposit {
+            current_shares
+        } else {
+            Self::mul_div_ceil(amount, pool.total_supply_shares, Self::total_assets(&pool))
+        };
+        if shares == 0 {
+            panic!("amount too small to queue");
+        }
+
+        // Take the shares out of the deposit, as a withdrawal would
+        Self::accrue_rewards(e, &token, &pool, &user, &user_pos);
+        Self::set_shares(
+            &mut user_pos.deposit_shares,
+            token.clone(),
+            current_shares - shares,
+        );
+        Self::remove_supply_principal(e, &user, token.clone(), shares, current_shares);
+        Self::notify_supply_token(e, &token, &user, -shares);
+        if Self::is_collateral(e, &user, token.clone()) {
+            let health = Self::get_user_health(e, &user, &user_pos);
+            if health.borrow_limit < health.debt_value {
+                panic!("insufficient collateral after withdrawal");
+            }
+        }
+
+        let storage = e.storage().persistent();
+        let request_key = ExtraKey::WithdrawRequest(user.clone(), token.clone());
+        let queued: i128 = storage.get(&request_key).unwrap_or(0);
+        storage.set(&request_key, &(queued + shares));
+        let total_queued = Self::queued_shares(e, &token);
+        storage.set(
+            &ExtraKey::QueuedShares(token.clone()),
+            &(total_queued + shares),
+        );
+        Self::save_pool(e, token.clone(), &pool);
+        Self::save_user_pos(e.clone(), user.clone(), &user_pos);
+        WithdrawRequested {
+            token,
+            user,
+            shares,
+        }
+        .publish(e);
+        shares
+    }
+
+    /// Pays out as much of `user`'s queued `token` withdrawal as the pool's
+    /// cash allows, at the current exchange rate. What can't be paid stays
+    /// queued. Returns the amount paid, which may be 0.
+    pub fn claim_withdraw(e: Env, user: Address, token: Address) -> i128 {
+        user.require_auth();
+        Self::non_reentrant(&e, || Self::do_claim_withdraw(&e, user, token))
+    }
+
+    fn do_claim_withdraw(e: &Env, user: Address, token: Address) -> i128 {
+        Self::require_not_paused(e, Action::Withdraw);
+        let storage = e.storage().persistent();
+        let request_key = ExtraKey::WithdrawRequest(user.clone(), token.clone());
+        let queued: i128 = storage.get(&request_key).expect("no withdrawal queued");
+
+        let mut pool = Self::get_pool(e, token.clone());
+        Self::accrue_interest(e, &mut pool);
+        let owed = Self::supply_shares_to_amount(&pool, queued);
+        let amount = owed.min(Self::available_cash(&pool));
+        if amount == 0 {
+            return 0;
+        }
+        let shares = if amount == owed {
+            queued
+        } else {
+            Self::mul_div_ceil(amount, pool.total_supply_shares, Self::total_assets(&pool))
+                .min(queued)
+        };
+
+        Self::update_reward_index(e, &token, &pool);
+        pool.total_supply_shares = pool
+            .total_supply_shares
+            .checked_sub(shares)
+            .expect("underflow");
+        pool.total_cash = pool.total_cash.checked_sub(amount).expect("underflow");
+        if shares == queued {
+            storage.remove(&request_key);
+        } else {
+            storage.set(&request_key, &(queued - shares));
+        }
+        let total_queued = Self::queued_shares(e, &token);
+        storage.set(
+            &ExtraKey::QueuedShares(token.clone()),
+            &(total_queued - shares),
+        );
+        Self::save_pool(e, token.clone(), &pool);
+
+        let token_client = token::Client::new(e, &token);
+        token_client.transfer(&e.current_contract_address(), &user, &amount);
+        WithdrawClaimed {
+            token,
+            user,
+            amount,
+            shares,
+        }
+        .publish(e);
+        amount
+    }
+
+    /// Supply shares of `token` waiting in the withdraw queue.
+    pub(crate) fn queued_shares(e: &Env, token: &Address) -> i128 {
+        e.storage()
+            .persistent()
+            .get(&ExtraKey::QueuedShares(token.clone()))
+            .unwrap_or(0)
+    }
+
+    /// Cash in `pool` that can be lent out or withdrawn outside the queue:
+    /// what's available less what queued withdrawals are owed.
+    fn lendable_cash(e: &Env, pool: &Pool) -> i128 {
+        let queued = Self::queued_shares(e, &pool.token);
+        (Self::available_cash(pool) - Self::supply_shares_to_amount(pool, queued)).max(0)
+    }
+
+    /// Steps 3-6 of a withdrawal: burns `shares` of `user`'s `token`
+    /// deposit for `amount` of the pool's cash, checking health and
+    /// liquidity, and saves the result. The caller sends the tokens.
+    fn burn_deposit(
+        e: &Env,
+        user: Address,
+        token: Address,
+        mut pool: Pool,
+        mut user_pos: UserPosition,
+        shares: i128,
+        amount: i128,
+    ) {
+        // 3. Check the position as it will be after the withdrawal. Every
+        //    deposit, the withdrawn one included, is valued at its own
+        //    price and LTV. A panic reverts the whole call, so nothing
+        //    needs restoring on failure. A deposit that isn't collateral
+        //    backs nothing, so it can always leave.
+        Self::accrue_rewards(e, &token, &pool, &user, &user_pos);
+        let current_shares = user_pos.deposit_shares.get(token.clone()).unwrap_or(0);
+        Self::set_shares(
+            &mut user_pos.deposit_shares,
+            token.clone(),
+            current_shares - shares,
+        );
+        Self::remove_supply_principal(e, &user, token.clone(), shares, current_shares);
+        if Self::is_collateral(e, &user, token.clone()) {
+            let health = Self::get_user_health(e, &user, &user_pos);
+            if health.borrow_limit < health.debt_value {
+                panic!("insufficient collateral after withdrawal");
+            }
+        }
+
+        // 4. Check for available liquidity in the pool, leaving what
+        //    queued withdrawals are owed
+        if amount > Self::lendable_cash(e, &pool) {
+            panic!("insufficient liquidity in the pool");
+        }
+
+        // 5. Update pool's total supply
+        pool.total_supply_shares = pool
+            .total_supply_shares
+            .checked_sub(shares)
+            .expect("underflow");
+        pool.total_cash = pool.total_cash.checked_sub(amount).expect("underflow");
+
+        // 6. Save the updated state (user_pos is already updated) before
+        //    any tokens leave the pool
+        Self::save_pool(e, token.clone(), &pool);
+        Self::notify_supply_token(e, &token, &user, -shares);
+        Self::save_user_pos(e.clone(), user, &user_pos);
+    }
+
+    /// Moves `shares` of `from`'s `token` deposit to `to`, as the pool's
+    /// registered supply token does for every receipt transfer. `from`
+    /// faces the same cooldown and health checks as a withdrawal, and `to`
+    /// the same allowlist and collateral rules as a supply.
+    pub fn transfer_deposit(e: Env, token: Address, from: Address, to: Address, shares: i128) {
+        let supply_token: Address = e
+            .storage()
+            .persistent()
+            .get(&DataKey::SupplyToken(token.clone()))
+            .expect("no supply token");
+        supply_token.require_auth();
+        Self::non_reentrant(&e, || {
+            Self::do_transfer_deposit(&e, token, from, to, shares)
+        });
+    }
+
+    /// Body of `transfer_deposit`; the caller checks auth.
+    fn do_transfer_deposit(e: &Env, token: Address, from: Address, to: Address, shares: i128) {
+        Self::require_not_paused(e, Action::Withdraw);
+        Self::require_allowed(e, token.clone(), to.clone());
+        if shares <= 0 {
+            panic!("amount must be positive");
+        }
+        Self::require_cooldown_elapsed(e, &from);
+
+        let mut from_pos = Self::get_user_pos(e, from.clone());
+        let from_shares = from_pos.deposit_shares.get(token.clone()).unwrap_or(0);
+        if shares > from_shares {
+            panic!("insufficient balance");
+        }
+        if from == to {
+            return;
+        }
+        let pool = Self::get_pool(e, token.clone());
+        let mut to_pos = Self::get_user_pos(e, to.clone());
+        Self::accrue_rewards(e, &token, &pool, &from, &from_pos);
+        Self::accrue_rewards(e, &token, &pool, &to, &to_pos);
+        Self::set_shares(
+            &mut from_pos.deposit_shares,
+            token.clone(),
+            from_shares - shares,
+        );
+        if Self::is_collateral(e, &from, token.clone()) {
+            let health = Self::get_user_health(e, &from, &from_pos);
+            if health.borrow_limit < health.debt_value {
+                panic!("insufficient collateral after withdrawal");
+            }
+        }
+
+        Self::credit_deposit(e, &to, &mut to_pos, token.clone(), shares);
+        let principal = Self::remove_supply_principal(e, &from, token.clone(), shares, from_shares);
+        Self::add_supply_principal(e, &to, token, principal);
+        Self::save_user_pos(e.clone(), from, &from_pos);
+        Self::save_user_pos(e.clone(), to, &to_pos);
+    }
+
+    /// Repays a debt.
+    /// `user` repays `amount` of `token` to the contract.
+    /// Passing `i128::MAX` repays the entire current debt, interest included.
+    /// Returns the amount actually repaid.
+    pub fn repay(e: Env, user: Address, token: Address, amount: i128) -> i128 {
+        user.require_auth();
+        Self::non_reentrant(&e, || Self::do_repay(&e, user.clone(), user, token, amount))
+    }
+
+    /// Repays another account's debt.
+    /// `payer` pays up to `amount` of `token` to reduce `borrower`'s debt.
+    /// Accepts the same `i128::MAX` sentinel and return value as `repay`.
+    pub fn repay_for(
+        e: Env,
+        payer: Address,
+        borrower: Address,
+        token: Address,
+        amount: i128,
+    ) -> i128 {
+        payer.require_auth();
+        Self::non_reentrant(&e, || Self::do_repay(&e, payer, borrower, token, amount))
+    }
+
+    /// Repays several debts at once. `repayments` maps each token to the
+    /// amount to repay, clamped to the debt like `repay`, with `i128::MAX`
+    /// repaying it all. Fails as a whole if any repayment would fail on
+    /// its own. Returns the amount actually repaid of each token.
+    pub fn repay_many(e: Env, user: Address, repayments: Map<Address, i128>) -> Map<Address, i128> {
+        user.require_auth();
+        Self::non_reentrant(&e, || {
+            let mut repaid = Map::new(&e);
+            for (token, amount) in repayments.iter() {
+                let paid = Self::do_repay(&e, user.clone(), user.clone(), token.clone(), amount);
+                repaid.set(token, paid);
+            }
+            repaid
+        })
+    }
+
+    /// Shared body of `repay` and `repay_for`; the caller checks auth.
+    fn do_repay(e: &Env, payer: Address, borrower: Address, token: Address, amount: i128) -> i128 {
+        Self::require_not_paused(e, Action::Repay);
+        if amount <= 0 {
+            panic!("amount must be positive");
+        }
+
+        // Get persistent state
+        let mut pool = Self::get_pool(e, token.clone());
+        Self::accrue_interest(e, &mut pool);
+        let mut user_pos = Self::get_user_pos(e, borrower.clone());
+        Self::accrue_rewards(e, &token, &pool, &borrower, &user_pos);
+
+        // 1. Check borrower's current debt (including accrued interest)
+        let debt_shares = user_pos.debt_shares.get(token.clone()).unwrap_or(0);
+        if debt_shares == 0 {
+            panic!("no debt to repay");
+        }
+        let current_debt = Self::debt_shares_to_amount(debt_shares, pool.borrow_index);
+
+        // 2. Determine actual amount to repay (can't overpay, so
+        //    `i128::MAX` settles exactly the current debt)
+        let amount_to_repay = amount.min(current_debt);
+
+        // 3. Transfer tokens from the payer to this contract, crediting only
+        //    what actually arrives
+        let amount_to_repay = Self::pull_tokens(e, &token, &payer, amount_to_repay);
+        Self::burn_debt(&mut pool, &mut user_pos, token.clone(), amount_to_repay);
+
+        // 4. Save the updated state
+        Self::save_pool(e, token.clone(), &pool);
+        Self::save_user_pos(e.clone(), borrower.clone(), &user_pos);
+        Repay {
+            token,
+            payer,
+            borrower,
+            amount: amount_to_repay,
+        }
+        .publish(e);
+        amount_to_repay
+    }
+
+    /// Reduces `user_pos`'s `token` debt by `amount`, already in the pool
+    /// and at most the current debt. The caller saves `pool` and `user_pos`.
+    fn burn_debt(pool: &mut Pool, user_pos: &mut UserPosition, token: Address, amount: i128) {
+        let debt_shares = user_pos.debt_shares.get(token.clone()).unwrap_or(0);
+        let current_debt = Self::debt_shares_to_amount(debt_shares, pool.borrow_index);
+        let shares_to_burn =
+            Self::repaid_debt_shares(amount, current_debt, debt_shares, pool.borrow_index);
+        Self::set_shares(
+            &mut user_pos.debt_shares,
+            token,
+            debt_shares - shares_to_burn,
+        );
+        pool.total_debt_shares = pool
+            .total_debt_shares
+            .checked_sub(shares_to_burn)
+            .expect("underflow");
+        pool.total_cash = pool.total_cash.checked_add(amount).expect("overflow");
+    }
+
+    /// Repays `user`'s `token` debt and supplies whatever `amount` has left
+    /// over, all paid in one transfer. Passing more than the debt, which
+    /// keeps growing until the call lands, settles it in full. Returns the
+    /// amount repaid and the supply shares minted. Without any debt, all of
+    /// `amount` is supplied.
+    pub fn repay_and_supply(e: Env, user: Address, token: Address, amount: i128) -> (i128, i128) {
+        user.require_auth();
+        Self::non_reentrant(&e, || Self::do_repay_and_supply(&e, user, token, amount))
+    }
+
+    fn do_repay_and_supply(e: &Env, user: Address, token: Address, amount: i128) -> (i128, i128) {
+        Self::require_not_paused(e, Action::Repay);
+        if amount <= 0 {
+            panic!("amount must be positive");
+        }
+
+        let mut pool = Self::get_pool(e, token.clone());
+        Self::accrue_interest(e, &mut pool);
+        let mut user_pos = Self::get_user_pos(e, user.clone());
+        Self::accrue_rewards(e, &token, &pool, &user, &user_pos);
+        let debt_shares = user_pos.debt_shares.get(token.clone()).unwrap_or(0);
+        let current_debt = Self::debt_shares_to_amount(debt_shares, pool.borrow_index);
+        if amount > current_debt {
+            Self::require_can_supply(e, &user, token.clone());
+            Self::check_supply_cap(e, token.clone(), &pool, amount - current_debt);
+        }
+
+        // 1. Pull everything at once and repay out of what arrived
+        let received = Self::pull_tokens(e, &token, &user, amount);
+        let repaid = received.min(current_debt);
+        if repaid > 0 {
+            Self::burn_debt(&mut pool, &mut user_pos, token.clone(), repaid);
+        }
+
+        // 2. The rest becomes a deposit. The debt is settled by now, so a
+        //    new deposit counts as collateral as usual.
+        let excess = received - repaid;
+        let shares = if excess > 0 {
+            Self::mint_deposit(e, &user, token.clone(), &mut pool, &mut user_pos, excess)
+        } else {
+            0
+        };
+
+        Self::save_pool(e, token.clone(), &pool);
+        Self::save_user_pos(e.clone(), user.clone(), &user_pos);
+        if repaid > 0 {
+            Repay {
+                token: token.clone(),
+                payer: user.clone(),
+                borrower: user.clone(),
+                amount: repaid,
+            }
+            .publish(e);
+        }
+        if shares > 0 {
+            Supply {
+                token,
+                from: user.clone(),
+                user,
+                amount: excess,
+                shares,
+            }
+            .publish(e);
+        }
+        (repaid, shares)
+    }
+
+    /// Repays up to `amount` of `user`'s `debt_token` debt out of their own
+    /// deposit, with no liquidation bonus and no tokens moving. Returns the
+    /// amount repaid, capped at both the debt and the deposit.
+    ///
+    /// The pool can't swap assets, so `collateral_token` must be
+    /// `debt_token`: the deposit is a plain one made while the debt was
+    /// open, since an asset can't back a loan of itself. Refused unless it
+    /// leaves the position healthier.
+    pub fn repay_with_collateral(
+        e: Env,
+        user: Address,
+        debt_token: Address,
+        collateral_token: Address,
+        amount: i128,
+    ) -> i128 {
+        user.require_auth();
+        Self::non_reentrant(&e, || {
+            Self::do_repay_with_collateral(&e, user, debt_token, collateral_token, amount)
+        })
+    }
+
+    fn do_repay_with_collateral(
+        e: &Env,
+        user: Address,
+        debt_token: Address,
+        collateral_token: Address,
+        amount: i128,
+    ) -> i128 {
+        Self::require_not_paused(e, Action::Repay);
+        Self::require_not_paused(e, Action::Withdraw);
+        if amount <= 0 {
+            panic!("amount must be positive");
+        }
+        if collateral_token != debt_token {
+            panic!("collateral must be the debt token");
+        }
+
+        let mut debt_pool = Self::get_pool(e, debt_token.clone());
+        Self::accrue_interest(e, &mut debt_pool);
+        let mut user_pos = Self::get_user_pos(e, user.clone());
+        let health_before = Self::health_factor(&Self::get_user_health(e, &user, &user_pos));
+        let debt_shares = user_pos.debt_shares.get(debt_token.clone()).unwrap_or(0);
+        if debt_shares == 0 {
+            panic!("no debt to repay");
+        }
+        let current_debt = Self::debt_shares_to_amount(debt_shares, debt_pool.borrow_index);
+        Self::accrue_rewards(e, &debt_token, &debt_pool, &user, &user_pos);
+
+        let repaid = Self::repay_from_own_deposit(
+            e,
+            &user,
+            &mut debt_pool,
+            &mut user_pos,
+            amount.min(current_debt),
+        );
+        Self::save_pool(e, debt_token.clone(), &debt_pool);
+        Self::save_user_pos(e.clone(), user.clone(), &user_pos);
+
+        // The position must come out healthier than it went in
+        let health_after = Self::health_factor(&Self::get_user_health(e, &user, &user_pos));
+        if health_after <= health_before {
+            panic!("position would not be healthier");
+        }
+        Repay {
+            token: debt_token,
+            payer: user.clone(),
+            borrower: user,
+            amount: repaid,
+        }
+        .publish(e);
+        repaid
+    }
+
+    /// Nets up to `amount` of `user`'s debt to `pool` against their deposit
+    /// in the same pool. The repaid tokens never leave the pool, so its
+    /// cash is unchanged. Returns the amount repaid; the caller saves
+    /// `pool` and `user_pos`.
+    fn repay_from_own_deposit(
+        e: &Env,
+        user: &Address,
+        pool: &mut Pool,
+        user_pos: &mut UserPosition,
+        amount: i128,
+    ) -> i128 {
+        let token = pool.token.clone();
+        let deposit_shares = user_pos.deposit_shares.get(token.clone()).unwrap_or(0);
+        if deposit_shares == 0 {
+            panic!("no assets to withdraw");
+        }
+        let current_deposit = Self::supply_shares_to_amount(pool, deposit_shares);
+        let amount_to_repay = amount.min(current_deposit);
+        let deposit_to_burn = if amount_to_repay == current_deposit {
+            deposit_shares
+        } else {
+            Self::mul_div_ceil(
+                amount_to_repay,
+                pool.total_supply_shares,
+                Self::total_assets(pool),
+            )
+        };
+
+        // `burn_debt` counts the repayment as cash arriving, but it never left
+        Self::burn_debt(pool, user_pos, token.clone(), amount_to_repay);
+        pool.total_cash = pool
+            .total_cash
+            .checked_sub(amount_to_repay)
+            .expect("underflow");
+        Self::set_shares(
+            &mut user_pos.deposit_shares,
+            token.clone(),
+            deposit_shares - deposit_to_burn,
+        );
+        Self::remove_supply_principal(e, user, token.clone(), deposit_to_burn, deposit_shares);
+        Self::notify_supply_token(e, &token, user, -deposit_to_burn);
+        pool.total_supply_shares = pool
+            .total_supply_shares
+            .checked_sub(deposit_to_burn)
+            .expect("underflow");
+        amount_to_repay
+    }
+
+    /// Chooses whether `user`'s deposit of `token` backs their debt.
+    /// Deposits count as collateral by default. Disabling one is refused if
+    /// the rest of the user's collateral wouldn't cover their debt.
+    pub fn set_use_as_collateral(e: Env, user: Address, token: Address, enabled: bool) {
+        user.require_auth();
+        Self::get_pool(&e, token.clone());
+        e.storage().persistent().set(
+            &DataKey::CollateralEnabled(user.clone(), token.clone()),
+            &enabled,
+        );
+
+        let user_pos = Self::get_user_pos(&e, user.clone());
+        if enabled {
+            if user_pos.debt_shares.get(token.clone()).unwrap_or(0) > 0 {
+                panic!("cannot use a borrowed asset as collateral");
+            }
+            if Self::mixes_isolated(&e, &user, &user_pos, token) {
+                panic!("isolated collateral cannot be mixed");
+            }
+        } else {
+            let health = Self::get_user_health(&e, &user, &user_pos);
+            if health.borrow_limit < health.debt_value {
+                panic!("insufficient collateral");
+            }
+        }
+    }
+
+    /// Selects e-mode category `id` for `user`, or leaves e-mode with 0.
+    /// The boosted parameters apply while everything the user supplies as
+    /// collateral or borrows belongs to the category. Refused if the change
+    /// would leave the user's collateral short of their debt.
+    pub fn set_user_emode(e: Env, user: Address, id: u32) {
+        user.require_auth();
+        if id != 0 && !e.storage().persistent().has(&DataKey::EmodeCategory(id)) {
+            panic!("e-mode category not found");
+        }
+        e.storage()
+            .persistent()
+            .set(&DataKey::UserEmode(user.clone()), &id);
+
+        let user_pos = Self::get_user_pos(&e, user.clone());
+        let health = Self::get_user_health(&e, &user, &user_pos);
+        if health.borrow_limit < health.debt_value {
+            panic!("insufficient collateral");
+        }
+    }
+
+    /// Borrows assets from a pool.
+    /// An asset the user supplies as collateral can't be borrowed; see
+    /// `set_use_as_collateral`.
+    pub fn borrow(e: Env, user: Address, token: Address, amount: i128) {
+        user.require_auth();
+        Self::non_reentrant(&e, || {
+            Self::do_borrow(&e, user.clone(), token, amount, user)
+        });
+    }
+
+    /// Lets `delegatee` borrow up to `amount` of `token` against
+    /// `delegator`'s collateral with `borrow_on_behalf`. The debt is the
+    /// delegator's. Replaces any previous allowance; 0 revokes it.
+    pub fn approve_delegation(
+        e: Env,
+        delegator: Address,
+        delegatee: Address,
+        token: Address,
+        amount: i128,
+    ) {
+        delegator.require_auth();
+        if amount < 0 {
+            panic!("allowance cannot be negative");
+        }
+        e.storage().persistent().set(
+            &DataKey::BorrowAllowance(delegator, delegatee, token),
+            &amount,
+        );
+    }
+
+    /// Borrows against `delegator`'s collateral within the allowance they
+    /// granted with `approve_delegation`. The debt is added to the
+    /// delegator's position and `delegatee` receives the tokens.
+    pub fn borrow_on_behalf(
+        e: Env,
+        delegatee: Address,
+        delegator: Address,
+        token: Address,
+        amount: i128,
+    ) {
+        delegatee.require_auth();
+        let key = DataKey::BorrowAllowance(delegator.clone(), delegatee.clone(), token.clone());
+        let allowance: i128 = e.storage().persistent().get(&key).unwrap_or(0);
+        if amount > allowance {
+            panic!("borrow allowance exceeded");
+        }
+        e.storage().persistent().set(&key, &(allowance - amount));
+        Self::non_reentrant(&e, || {
+            Self::do_borrow(&e, delegator, token, amount, delegatee)
+        });
+    }
+
+    /// Shared body of `borrow` and `borrow_on_behalf`; the caller checks auth.
+    /// `user` takes on the debt and `to` receives the tokens.
+    fn do_borrow(e: &Env, user: Address, token: Address, amount: i128, to: Address) {
+        let mut pool = Self::get_pool(e, token.clone());
+        Self::accrue_interest(e, &mut pool);
+        let mut user_pos = Self::get_user_pos(e, user.clone());
+        Self::accrue_rewards(e, &token, &pool, &user, &user_pos);
+        let (fee, _) = Self::plan_borrow(e, &user, &token, amount, &mut pool, &mut user_pos)
+            .unwrap_or_else(|err| err.raise());
+
+        // 8. Save the updated state before any tokens leave the pool
+        Self::save_pool(e, token.clone(), &pool);
+        Self::save_user_pos(e.clone(), user.clone(), &user_pos);
+
+        // 9. Transfer tokens from this contract to the recipient
+        let token_client = token::Client::new(e, &token);
+        token_client.transfer(&e.current_contract_address(), &to, &amount);
+        Borrow {
+            token,
+            user,
+            to,
+            amount,
+            fee,
+        }
+        .publish(e);
+    }
+
+    /// Why `user` can't borrow any `token` at all right now, whatever the
+    /// amount, or `None` if they may.
+    fn borrow_refusal(
+        e: &Env,
+        user: &Address,
+        token: &Address,
+        user_pos: &UserPosition,
+    ) -> Option<&'static str> {
+        if let Some(reason) = Self::pause_reason(e, Action::Borrow) {
+            return Some(reason);
+        }
+        if !Self::get_pool_active(e, token.clone()) {
+            return Some("pool is not active");
+        }
+        if Self::is_frozen(e, token.clone()) {
+            return Some("pool is frozen");
+        }
+        if !Self::is_allowed(e, token.clone(), user.clone()) {
+            return Some("account not allowed");
+        }
+        // An asset can't back a loan of itself: looping a deposit into its
+        // own debt only inflates utilization. Users who hold both must
+        // first stop using the deposit as collateral.
+        if Self::backs_own_loan(e, user, user_pos, token.clone()) {
+            return Some("cannot borrow an asset used as collateral");
+        }
+        let frozen = Self::is_price_frozen(e, token.clone())
+            || Self::collateral_tokens(e, user, user_pos)
+                .iter()
+                .any(|collateral| Self::is_price_frozen(e, collateral));
+        if frozen {
+            return Some("price circuit breaker tripped");
+        }
+        None
+    }
+
+    /// Checks a borrow of `amount` of `token` by `user` and applies it to
+    /// `pool` (accrued to now) and `user_pos` in memory, for `borrow` to
+    /// save or `preview_borrow` to discard. Returns the origination fee and
+    /// the health factor the position ends at.
+    fn plan_borrow(
+        e: &Env,
+        user: &Address,
+        token: &Address,
+        amount: i128,
+        pool: &mut Pool,
+        user_pos: &mut UserPosition,
+    ) -> Result<(i128, i128), BorrowError> {
+        if let Some(reason) = Self::borrow_refusal(e, user, token, user_pos) {
+            return Err(BorrowError::Refused(reason));
+        }
+        if amount <= 0 {
+            return Err(BorrowError::Refused("amount must be positive"));
+        }
+        let health = Self::get_user_health(e, user, user_pos);
+
+        // 2. Calculate new debt value, origination fee included
+        let fee = Self::origination_fee(e, token.clone(), amount);
+        let debt_amount = amount.checked_add(fee).expect("overflow");
+        let debt_value = health.debt_value + Self::debt_amount_value(e, token.clone(), debt_amount);
+        let health_factor = Self::health_factor(&AccountHealth {
+            debt_value,
+            ..health
+        });
+
+        // 3. Check if health factor is safe
+        //    (LTV-weighted collateral value must cover the debt, with the
+        //    `ExtraKey::MinHealthBuffer` margin on top)
+        if Self::buffered_borrow_limit(e, &health) < debt_value {
+            return Err(BorrowError::Refused("insufficient collateral"));
+        }
+        let debt_limit = Self::isolation_debt_limit(e, user, user_pos).unwrap_or(i128::MAX);
+        if debt_value > debt_limit {
+            return Err(BorrowError::Refused("isolation debt ceiling exceeded"));
+        }
+
+        // 4. Check for available liquidity in the pool
+        if amount > Self::lendable_cash(e, pool) {
+            return Err(BorrowError::Refused("insufficient liquidity in the pool"));
+        }
+
+        // 5. Enforce the pool's borrow cap (0 means unlimited)
+        let cap = Self::get_borrow_cap(e, token.clone());
+        let attempted = Self::total_debt(pool)
+            .checked_add(debt_amount)
+            .expect("overflow");
+        if cap > 0 && attempted > cap {
+            return Err(BorrowError::BorrowCap(attempted, cap));
+        }
+
+        // 6. Update user's debt.
+        // Shares are rounded up so the borrower never owes less than `debt_amount`.
+        let new_shares = Self::mul_div_ceil(debt_amount, INDEX_SCALE, pool.borrow_index);
+        let new_debt = user_pos.debt_shares.get(token.clone()).unwrap_or(0) + new_shares;
+        user_pos.debt_shares.set(token.clone(), new_debt);
+        let min_borrow = Self::get_min_borrow(e, token.clone());
+        if Self::debt_shares_to_amount(new_debt, pool.borrow_index) < min_borrow {
+            return Err(BorrowError::Refused("debt below minimum borrow"));
+        }
+
+        // 7. Update pool's total debt
+        pool.total_debt_shares = pool
+            .total_debt_shares
+            .checked_add(new_shares)
+            .expect("overflow");
+        pool.total_cash = pool.total_cash.checked_sub(amount).expect("underflow");
+        pool.total_reserves = pool.total_reserves.checked_add(fee).expect("overflow");
+        if Self::total_debt(pool) > Self::max_utilized_debt(e, pool) {
+            return Err(BorrowError::Refused("max utilization exceeded"));
+        }
+        Ok((fee, health_factor))
+    }
+
+    /// Liquidates an unhealthy position.
+    /// `liquidator` repays `repay_amount` of `borrower`'s `debt_token` debt
+    /// and receives the borrower's `collateral_token` deposit worth the repaid
+    /// value plus the collateral's liquidation bonus.
+    pub fn liquidate(
+        e: Env,
+        liquidator: Address,
+        borrower: Address,
+        debt_token: Address,
+        collateral_token: Address,
+        repay_amount: i128,
+    ) {
+        liquidator.require_auth();
+        Self::non_reentrant(&e, || {
+            Self::do_liquidate(
+                &e,
+                liquidator,
+                borrower,
+                debt_token,
+                collateral_token,
+                repay_amount,
+            )
+        });
+    }
+
+    /// Body of `liquidate`; the caller checks auth.
+    fn do_liquidate(
+        e: &Env,
+        liquidator: Address,
+        borrower: Address,
+        debt_token: Address,
+        collateral_token: Address,
+        repay_amount: i128,
+    ) {
+        Self::require_not_paused(e, Action::Liquidate);
+        Self::require_grace_period_over(e);
+        Self::require_price_not_frozen(e, debt_token.clone());
+        Self::require_price_not_frozen(e, collateral_token.clone());
+        if repay_amount <= 0 {
+            panic!("amount must be positive");
+        }
+
+        let mut borrower_pos = Self::get_user_pos(e, borrower.clone());
+
+        // 1. Only unhealthy positions can be liquidated, and only through
+        //    deposits that back their debt
+        let health = Self::get_user_health(e, &borrower, &borrower_pos);
+        if health.liquidation_limit >= health.debt_value {
+            panic!("position is healthy");
+        }
+        if !Self::is_collateral(e, &borrower, collateral_token.clone()) {
+            panic!("asset is not used as collateral");
+        }
+        let collateral_shares = borrower_pos
+            .deposit_shares
+            .get(collateral_token.clone())
+            .unwrap_or(0);
+        if collateral_shares == 0 {
+            panic!("no collateral to seize");
+        }
+
+        // 2. Only a close-factor share of the debt can be repaid at once
+        let mut debt_pool = Self::get_pool(e, debt_token.clone());
+        Self::accrue_interest(e, &mut debt_pool);
+        let debt_shares = borrower_pos
+            .debt_shares
+            .get(debt_token.clone())
+            .unwrap_or(0);
+        if debt_shares == 0 {
+            panic!("no debt to repay");
+        }
+        let current_debt = Self::debt_shares_to_amount(debt_shares, debt_pool.borrow_index);
+        let max_repay = Self::max_liquidation_repay(e, debt_token.clone(), current_debt);
+        if repay_amount > max_repay {
+            panic!("repay amount exceeds close factor");
+        }
+        // Pull the repayment now and work from what actually arrived
+        let amount_to_repay = Self::pull_tokens(e, &debt_token, &liquidator, repay_amount);
+        if Self::leaves_dust(e, debt_token.clone(), current_debt - amount_to_repay) {
+            panic!("liquidation must clear the remaining debt");
+        }
+
+        // 3. Convert the repaid value into an amount of collateral
+        let seize_target = Self::liquidation_seize_target(
+            e,
+            debt_token.clone(),
+            collateral_token.clone(),
+            amount_to_repay,
+            repay_amount == max_repay,
+        );
+
+        // 4. Reduce the borrower's debt by the repayment
+        Self::accrue_rewards(e, &debt_token, &debt_pool, &borrower, &borrower_pos);
+        let shares_to_burn = Self::repaid_debt_shares(
+            amount_to_repay,
+            current_debt,
+            debt_shares,
+            debt_pool.borrow_index,
+        );
+        debt_pool.total_debt_shares = debt_pool
+            .total_debt_shares
+            .checked_sub(shares_to_burn)
+            .expect("underflow");
+        debt_pool.total_cash = debt_pool
+            .total_cash
+            .checked_add(amount_to_repay)
+            .expect("overflow");
+        Self::save_pool(e, debt_token.clone(), &debt_pool);
+        Self::set_shares(
+            &mut borrower_pos.debt_shares,
+            debt_token.clone(),
+            debt_shares - shares_to_burn,
+        );
+
+        // 5. Seize the collateral from the borrower's deposit.
+        // The pool is loaded after the debt pool is saved so that
+        // `debt_token == collateral_token` doesn't clobber the update above.
+        let mut collateral_pool = Self::get_pool(e, collateral_token.clone());
+        Self::accrue_interest(e, &mut collateral_pool);
+        let current_collateral = Self::supply_shares_to_amount(&collateral_pool, collateral_shares);
+        Self::accrue_rewards(
+            e,
+            &collateral_token,
+            &collateral_pool,
+            &borrower,
+            &borrower_pos,
+        );
+
+        // The seized amount can never exceed what the borrower deposited.
+        let (seize_amount, seize_shares) = if seize_target >= current_collateral {
+            (current_collateral, collateral_shares)
+        } else {
+            let shares = Self::mul_div_ceil(
+                seize_target,
+                collateral_pool.total_supply_shares,
+                Self::total_assets(&collateral_pool),
+            );
+            (seize_target, shares)
+        };
+        // The protocol's cut of the bonus stays in the pool as reserves
+        let repaid_collateral = Self::collateral_for_repay(
+            e,
+            debt_token.clone(),
+            collateral_token.clone(),
+            amount_to_repay,
+            0,
+        );
+        let protocol_fee = Self::liquidation_protocol_fee(e, seize_amount, repaid_collateral);
+        let liquidator_amount = seize_amount - protocol_fee;
+        if liquidator_amount > Self::lendable_cash(e, &collateral_pool) {
+            panic!("insufficient liquidity in the pool");
+        }
+        collateral_pool.total_supply_shares = collateral_pool
+            .total_supply_shares
+            .checked_sub(seize_shares)
+            .expect("underflow");
+        collateral_pool.total_cash = collateral_pool
+            .total_cash
+            .checked_sub(liquidator_amount)
+            .expect("underflow");
+        collateral_pool.total_reserves = collateral_pool
+            .total_reserves
+            .checked_add(protocol_fee)
+            .expect("overflow");
+        Self::set_shares(
+            &mut borrower_pos.deposit_shares,
+            collateral_token.clone(),
+            collateral_shares - seize_shares,
+        );
+        Self::remove_supply_principal(
+            e,
+            &borrower,
+            collateral_token.clone(),
+            seize_shares,
+            collateral_shares,
+        );
+
+        // 6. Save the updated state
+        Self::save_pool(e, collateral_token.clone(), &collateral_pool);
+        Self::save_user_pos(e.clone(), borrower.clone(), &borrower_pos);
+        Self::notify_supply_token(e, &collateral_token, &borrower, -seize_shares);
+
+        // 7. Transfer the seized collateral to the liquidator
+        let collateral_client = token::Client::new(e, &collateral_token);
+        collateral_client.transfer(
+            &e.current_contract_address(),
+            &liquidator,
+            &liquidator_amount,
+        );
+        Liquidation {
+            borrower,
+            liquidator,
+            debt_token,
+            collateral_token,
+            repaid: amount_to_repay,
+            seized: seize_amount,
+            protocol_fee,
+        }
+        .publish(e);
+    }
+
+    /// The most of `current_debt` in `debt_token` one liquidation may repay:
+    /// a close-factor share of it, or all of it when that share would
+    /// leave less than the minimum borrow. Dust left behind would never be
+    /// worth liquidating.
+    fn max_liquidation_repay(e: &Env, debt_token: Address, current_debt: i128) -> i128 {
+        let max_repay = Self::apply_bps(current_debt, Self::get_close_factor(e));
+        if Self::leaves_dust(e, debt_token, current_debt - max_repay) {
+            return current_debt;
+        }
+        max_repay
+    }
+
+    /// Whether `remaining_debt` of `debt_token` is owed but under the
+    /// minimum borrow.
+    fn leaves_dust(e: &Env, debt_token: Address, remaining_debt: i128) -> bool {
+        remaining_debt > 0 && remaining_debt < Self::get_min_borrow(e, debt_token)
+    }
+
+    /// The `collateral_token` a liquidator earns for repaying
+    /// `amount_to_repay` of `debt_token`: the repaid value plus the
+    /// collateral's liquidation bonus, or its minimum seize if that is more
+    /// and the liquidation `repays_max`, before capping at the deposit.
+    fn liquidation_seize_target(
+        e: &Env,
+        debt_token: Address,
+        collateral_token: Address,
+        amount_to_repay: i128,
+        repays_max: bool,
+    ) -> i128 {
+        let bonus = Self::get_liquidation_bonus(e, collateral_token.clone());
+        let seize_target = Self::collateral_for_repay(
+            e,
+            debt_token,
+            collateral_token.clone(),
+            amount_to_repay,
+            bonus,
+        );
+        if !repays_max {
+            return seize_target;
+        }
+        let min_seize: i128 = e
+            .storage()
+            .persistent()
+            .get(&ExtraKey::MinLiquidationSeize(collateral_token))
+            .unwrap_or(0);
+        seize_target.max(min_seize)
+    }
+
+    /// The `collateral_token` worth `amount_to_repay` of `debt_token` plus
+    /// `bonus` out of 10,000, at current prices and rounded down.
+    fn collateral_for_repay(
+        e: &Env,
+        debt_token: Address,
+        collateral_token: Address,
+        amount_to_repay: i128,
+        bonus: u32,
+    ) -> i128 {
+        let debt_price = Self::get_checked_price(e, debt_token.clone());
+        let collateral_price = Self::get_checked_price(e, collateral_token.clone());
+        let repay_value = Self::value_of(
+            amount_to_repay,
+            debt_price,
+            Self::get_decimals(e, debt_token),
+        );
+        let seize_value = Self::apply_bps(repay_value, 10000 + bonus);
+        Self::from_accounting_units(e, collateral_token, seize_value / collateral_price)
+    }
+
+    /// Lends `amount` of `token` to `receiver` for the duration of a single
+    /// `exec_op` callback, which must pay back `amount` plus the flash loan
+    /// fee. The fee is added to the pool's reserves.
+    ///
+    /// Soroban does not allow a contract to be re-entered, so the receiver
+    /// can't call back into this pool (e.g. `borrow` or `withdraw`) while
+    /// the loan is outstanding.
+    pub fn flash_loan(e: Env, receiver: Address, token: Address, amount: i128) {
+        receiver.require_auth();
+        Self::non_reentrant(&e, || Self::do_flash_loan(&e, receiver, token, amount));
+    }
+
+    /// Body of `flash_loan`; the caller checks auth.
+    fn do_flash_loan(e: &Env, receiver: Address, token: Address, amount: i128) {
+        Self::require_not_paused(e, Action::FlashLoan);
+        if amount <= 0 {
+            panic!("amount must be positive");
+        }
+
+        let mut pool = Self::get_pool(e, token.clone());
+        Self::accrue_interest(e, &mut pool);
+        if amount > Self::available_cash(&pool) {
+            panic!("insufficient liquidity in the pool");
+        }
+        let fee_rate: u32 = e
+            .storage()
+            .persistent()
+            .get(&DataKey::FlashLoanFee)
+            .unwrap_or(0);
+        let fee = Self::mul_div_ceil(amount, fee_rate as i128, 10000);
+
+        // 1. Send the loan and hand control to the receiver
+        let this = e.current_contract_address();
+        let token_client = token::Client::new(e, &token);
+        let balance_before = token_client.balance(&this);
+        token_client.transfer(&this, &receiver, &amount);
+        FlashLoanReceiverClient::new(e, &receiver).exec_op(&this, &token, &amount, &fee);
+
+        // 2. The loan and fee must be back before the call ends. Checking
+        //    the balance itself also covers fee-on-transfer tokens.
+        if token_client.balance(&this) < balance_before + fee {
+            panic!("flash loan not repaid");
+        }
+
+        // 3. Credit the fee to reserves
+        pool.total_cash = pool.total_cash.checked_add(fee).expect("overflow");
+        pool.total_reserves = pool.total_reserves.checked_add(fee).expect("overflow");
+        Self::save_pool(e, token.clone(), &pool);
+        FlashLoan {
+            token,
+            receiver,
+            amount,
+            fee,
+        }
+        .publish(e);
+    }
+
+    /// Sends `user` the rewards they've earned in `token`'s pool and
+    /// returns the amount sent.
+    pub fn claim_rewards(e: Env, user: Address, token: Address) -> i128 {
+        user.require_auth();
+        Self::non_reentrant(&e, || {
+            let pool = Self::get_pool(&e, token.clone());
+            let user_pos = Self::get_user_pos(&e, user.clone());
+            let Some((config, mut rewards)) =
+                Self::accrue_rewards(&e, &token, &pool, &user, &user_pos)
+            else {
+                return 0;
+            };
+            let amount = rewards.accrued;
+            rewards.accrued = 0;
+            e.storage()
+                .persistent()
+                .set(&DataKey::UserRewards(user.clone(), token), &rewards);
+            if amount > 0 {
+                token::Client::new(&e, &config.reward_token).transfer(
+                    &e.current_contract_address(),
+                    &user,
+                    &amount,
+                );
+            }
+            amount
+        })
+    }
+
+    /// Returns the rewards `user` could claim from `token`'s pool right now.
+    pub fn pending_rewards(e: Env, user: Address, token: Address) -> i128 {
+        let pool = Self::get_pool(&e, token.clone());
+        match Self::current_reward_index(&e, &token, &pool) {
+            Some((_, index)) => {
+                let user_pos = Self::get_user_pos(&e, user.clone());
+                Self::settled_rewards(&e, &token, &index, &user, &user_pos).accrued
+            }
+            None => 0,
+        }
+    }
+
+    /// Accrues interest on `token`'s pool up to the current ledger and saves it.
+    /// Anyone may call this; a second call in the same ledger changes nothing.
+    pub fn accrue(e: Env, token: Address) {
+        let mut pool = Self::get_pool(&e, token.clone());
+        if pool.last_accrual >= e.ledger().timestamp() {
+            return;
+        }
+        Self::accrue_interest(&e, &mut pool);
+        Self::save_pool(&e, token, &pool);
+    }
+
+    // --- View Functions ---
+
+    /// Returns the decimal places of every price the pool works with.
+    pub fn price_decimals(e: Env) -> u32 {
+        Self::get_price_decimals(&e)
+    }
+
+    /// Returns the contract owner, who authorizes every `(Admin)` call.
+    pub fn get_admin(e: Env) -> Address {
+        Self::get_owner(&e)
+    }
+
+    /// Returns every change queued with `queue_param_change` and not yet
+    /// executed or cancelled, by id.
+    pub fn pending_param_changes(e: Env) -> Map<u32, PendingChange> {
+        Self::pending_changes(&e)
+    }
+
+    /// Returns the guardian set by `set_guardian`, if any.
+    pub fn get_guardian(e: Env) -> Option<Address> {
+        e.storage().persistent().get(&ExtraKey::Guardian)
+    }
+
+    /// Returns the stored `Pool` for `token`.
+    pub fn get_pool_info(e: Env, token: Address) -> Pool {
+        Self::get_pool(&e, token)
+    }
+
+    /// Returns every pool's token, in the order the pools were initialized.
+    /// Deactivated pools stay listed so their positions can still be found;
+    /// check `is_pool_active` to tell them apart.
+    pub fn list_pools(e: Env) -> Vec<Address> {
+        Self::get_pool_list(&e)
+    }
+
+    /// Returns whether `token`'s pool accepts new supply and borrows.
+    pub fn is_pool_active(e: Env, token: Address) -> bool {
+        Self::get_pool(&e, token.clone());
+        Self::get_pool_active(&e, token)
+    }
+
+    /// Returns all of `token`'s risk parameters in one call, with the same
+    /// defaults the protocol applies for any that were never set.
+    pub fn get_reserve_config(e: Env, token: Address) -> ReserveConfig {
+        Self::get_pool(&e, token.clone());
+        ReserveConfig {
+            ltv: Self::get_ltv(&e, token.clone()),
+            liquidation_threshold: Self::get_liquidation_threshold(&e, token.clone()),
+            liquidation_bonus: Self::get_liquidation_bonus(&e, token.clone()),
+            reserve_factor: Self::get_reserve_factor(&e, token.clone()),
+            price: Self::get_price(&e, token.clone()),
+            borrow_cap: Self::get_borrow_cap(&e, token.clone()),
+            supply_cap: Self::get_supply_cap(&e, token.clone()),
+            active: Self::get_pool_active(&e, token),
+            paused: e
+                .storage()
+                .persistent()
+                .get(&DataKey::Paused)
+                .unwrap_or(false),
+        }
+    }
+
+    /// Returns the value of everything supplied across all pools, interest
+    /// included, in the same value units as `get_account_data`.
+    pub fn total_value_locked(e: Env) -> i128 {
+        Self::pools_value(&e, Self::total_assets)
+    }
+
+    /// Returns the value of everything borrowed across all pools, interest
+    /// included, in the same value units as `get_account_data`.
+    pub fn total_borrowed_value(e: Env) -> i128 {
+        Self::pools_value(&e, Self::total_debt)
+    }
+
+    /// Returns `user`'s `UserPosition`, or an empty one for a new user.
+    pub fn get_position(e: Env, user: Address) -> UserPosition {
+        Self::get_user_pos(&e, user)
+    }
+
+    /// Returns the tokens `user` has deposited and the tokens they owe,
+    /// so clients can fetch per-token details for just those.
+    pub fn user_tokens(e: Env, user: Address) -> (Vec<Address>, Vec<Address>) {
+        let user_pos = Self::get_user_pos(&e, user);
+        (user_pos.deposit_shares.keys(), user_pos.debt_shares.keys())
+    }
+
+    /// Returns up to `limit` users with outstanding debt, starting from
+    /// the `start`th, for liquidators to page through. The order is not
+    /// stable: a user whose debt is cleared is replaced by the last one.
+    /// Positions last changed before the registry existed appear once
+    /// they are next updated.
+    pub fn borrowers(e: Env, start: u32, limit: u32) -> Vec<Address> {
+        let storage = e.storage().persistent();
+        let count: u32 = storage.get(&ExtraKey::BorrowerCount).unwrap_or(0);
+        let end = start.saturating_add(limit).min(count);
+        let mut borrowers = Vec::new(&e);
+        for index in start..end {
+            borrowers.push_back(storage.get(&ExtraKey::BorrowerAt(index)).unwrap());
+        }
+        borrowers
+    }
+
+    /// Returns `user`'s health factor out of 10,000 (10000 == 1.0), or
+    /// `i128::MAX` when the user has no debt.
+    /// Below 10000 the position can be liquidated.
+    pub fn get_health_factor(e: Env, user: Address) -> i128 {
+        let user_pos = Self::get_user_pos(&e, user.clone());
+        Self::health_factor(&Self::get_user_health(&e, &user, &user_pos))
+    }
+
+    /// Returns whether `user`'s position can be liquidated right now: its
+    /// threshold-weighted collateral is below its debt, the same test
+    /// `liquidate` applies.
+    pub fn is_liquidatable(e: Env, user: Address) -> bool {
+        let user_pos = Self::get_user_pos(&e, user.clone());
+        let health = Self::get_user_health(&e, &user, &user_pos);
+        health.liquidation_limit < health.debt_value
+    }
+
+    /// Returns the health factor `user` would have if every token in
+    /// `price_overrides` were at the given price, with other tokens at their
+    /// current price. Nothing is written, so this is safe for what-if
+    /// scenarios such as a 20% drop in one collateral.
+    pub fn simulate_health(e: Env, user: Address, price_overrides: Map<Address, i128>) -> i128 {
+        for (_, price) in price_overrides.iter() {
+            if price <= 0 {
+                panic!("price must be positive");
+            }
+        }
+        let user_pos = Self::get_user_pos(&e, user.clone());
+        let source = PriceSource::Override(price_overrides);
+        let health = Self::user_health_at(&e, &user, &user_pos, |token| {
+            Self::price_for(&e, token, &source)
+        });
+        Self::health_factor(&health)
+    }
+
+    /// Returns `user`'s collateral, debt, borrowing headroom, weighted risk
+    /// parameters and health factor, all from a single valuation.
+    pub fn get_account_data(e: Env, user: Address) -> AccountData {
+        let user_pos = Self::get_user_pos(&e, user.clone());
+        let health = Self::get_user_health(&e, &user, &user_pos);
+        let weighted = |limit: i128| -> u32 {
+            if health.collateral_value == 0 {
+                return 0;
+            }
+            (limit.checked_mul(10000).expect("overflow") / health.collateral_value) as u32
+        };
+        AccountData {
+            total_collateral_value: health.collateral_value,
+            total_debt_value: health.debt_value,
+            available_borrows_value: (health.borrow_limit - health.debt_value).max(0),
+            current_ltv: weighted(health.borrow_limit),
+            liquidation_threshold: weighted(health.liquidation_limit),
+            health_factor: Self::health_factor(&health),
+        }
+    }
+
+    /// Returns how much of `token` can currently be borrowed or withdrawn:
+    /// the pool's cash less its reserves and what queued withdrawals are
+    /// owed, never below 0.
+    /// Reserves include interest accrued up to the current ledger.
+    pub fn available_liquidity(e: Env, token: Address) -> i128 {
+        let mut pool = Self::get_pool(&e, token);
+        Self::accrue_interest(&e, &mut pool);
+        Self::lendable_cash(&e, &pool)
+    }
+
+    /// Panics if `token`'s pool accounting is inconsistent; returns
+    /// otherwise. Meant for tests and fuzzing. With interest accrued to now:
+    /// - the contract holds at least the pool's cash, so the token balance
+    ///   plus outstanding debt covers everything owed to suppliers and the
+    ///   reserves (tokens sent to the pool directly may add a surplus);
+    /// - cash plus debt covers the reserves, so supply shares aren't
+    ///   backed by a negative amount;
+    /// - the token balance plus the debt, `total_debt_shares` at the borrow
+    ///   index, equals what `total_supply_shares` are worth plus the
+    ///   reserves, to within a unit of share rounding and besides any
+    ///   surplus. Queued withdrawals keep their shares until claimed, so
+    ///   they count as supply;
+    /// - no total is negative.
+    pub fn check_invariants(e: Env, token: Address) {
+        let mut pool = Self::get_pool(&e, token.clone());
+        Self::accrue_interest(&e, &mut pool);
+        if pool.total_cash < 0
+            || pool.total_reserves < 0
+            || pool.total_supply_shares < 0
+            || pool.total_debt_shares < 0
+        {
+            panic!("invariant violated: negative total");
+        }
+        let balance = token::Client::new(&e, &token).balance(&e.current_contract_address());
+        if balance < pool.total_cash {
+            panic!("invariant violated: balance below cash");
+        }
+        if Self::total_assets(&pool) < 0 {
+            panic!("invariant violated: reserves exceed assets");
+        }
+
+        let total_debt = Self::total_debt(&pool);
+        let total_supply = Self::supply_shares_to_amount(&pool, pool.total_supply_shares);
+        let held = balance.checked_add(total_debt).expect("overflow");
+        let owed = total_supply
+            .checked_add(pool.total_reserves)
+            .expect("overflow");
+        let surplus = balance - pool.total_cash;
+        if held < owed - 1 {
+            panic!("invariant violated: assets short of supply and reserves");
+        }
+        if held - owed > surplus + 1 {
+            panic!("invariant violated: assets owed to no one");
+        }
+    }
+
+    /// Returns the share of `token`'s supplied assets that is borrowed,
+    /// out of 10,000.
+    pub fn utilization(e: Env, token: Address) -> u32 {
+        let mut pool = Self::get_pool(&e, token);
+        Self::accrue_interest(&e, &mut pool);
+        Self::utilization_of(&pool)
+    }
+
+    /// Returns the underlying one supply share of `token` is worth, scaled
+    /// by 1e9 and accrued to the current ledger. An empty pool mints at 1:1.
+    pub fn supply_exchange_rate(e: Env, token: Address) -> i128 {
+        let mut pool = Self::get_pool(&e, token);
+        Self::accrue_interest(&e, &mut pool);
+        if pool.total_supply_shares == 0 {
+            return INDEX_SCALE;
+        }
+        Self::total_assets(&pool)
+            .checked_mul(INDEX_SCALE)
+            .expect("overflow")
+            / pool.total_supply_shares
+    }
+
+    /// Returns the amount one debt share of `token` owes, scaled by 1e9 and
+    /// accrued to the current ledger: the pool's borrow index.
+    pub fn borrow_exchange_rate(e: Env, token: Address) -> i128 {
+        let mut pool = Self::get_pool(&e, token);
+        Self::accrue_interest(&e, &mut pool);
+        pool.borrow_index
+    }
+
+    /// Returns the annual rate borrowers of `token` currently pay, out of
+    /// 10,000. This is the rate interest accrues at.
+    pub fn borrow_rate(e: Env, token: Address) -> u32 {
+        let mut pool = Self::get_pool(&e, token);
+        Self::accrue_interest(&e, &mut pool);
+        Self::borrow_rate_of(&e, &pool)
+    }
+
+    /// Returns the annual rate suppliers of `token` currently earn, out of
+    /// 10,000: the borrow rate scaled by utilization, less the reserve factor.
+    pub fn supply_rate(e: Env, token: Address) -> u32 {
+        let mut pool = Self::get_pool(&e, token);
+        Self::accrue_interest(&e, &mut pool);
+        Self::supply_rate_of(&e, &pool)
+    }
+
+    /// Returns what borrowing `token` costs over a year at the current
+    /// `borrow_rate`, out of 10,000, with interest compounding.
+    pub fn borrow_apy(e: Env, token: Address) -> u32 {
+        Self::compounded_rate(Self::borrow_rate(e, token))
+    }
+
+    /// Returns what supplying `token` earns over a year at the current
+    /// `supply_rate`, out of 10,000, with interest compounding. Like the
+    /// rate, it accounts for utilization and the reserve factor.
+    pub fn supply_apy(e: Env, token: Address) -> u32 {
+        Self::compounded_rate(Self::supply_rate(e, token))
+    }
+
+    /// Returns the most `user` can borrow of `token` right now: what their
+    /// collateral still covers, limited by the pool's liquidity, borrow cap
+    /// and any isolation debt ceiling. Returns 0 when they are at or past
+    /// their limit, when `borrow` would refuse them outright (paused,
+    /// frozen, not allowlisted, a tripped price) or when the most they can
+    /// borrow falls short of the minimum borrow.
+    pub fn max_borrow(e: Env, user: Address, token: Address) -> i128 {
+        let mut pool = Self::get_pool(&e, token.clone());
+        Self::accrue_interest(&e, &mut pool);
+        if Self::get_price(&e, token.clone()) <= 0 {
+            return 0;
+        }
+        let price = Self::get_checked_price(&e, token.clone());
+
+        // The same values `borrow` checks, so borrowing this much passes.
+        let user_pos = Self::get_user_pos(&e, user.clone());
+        if Self::borrow_refusal(&e, &user, &token, &user_pos).is_some() {
+            return 0;
+        }
+        let health = Self::get_user_health(&e, &user, &user_pos);
+        let borrow_limit = Self::buffered_borrow_limit(&e, &health);
+        if borrow_limit <= health.debt_value {
+            return 0;
+        }
+        let mut headroom = borrow_limit - health.debt_value;
+        if let Some(limit) = Self::isolation_debt_limit(&e, &user, &user_pos) {
+            headroom = headroom.min(limit - health.debt_value);
+        }
+        let units = headroom.max(0) / price;
+        let mut max_debt = Self::from_accounting_units(&e, token.clone(), units);
+
+        let cap = Self::get_borrow_cap(&e, token.clone());
+        if cap > 0 {
+            max_debt = max_debt.min(cap - Self::total_debt(&pool));
+        }
+        max_debt = max_debt.min(Self::max_utilized_debt(&e, &pool) - Self::total_debt(&pool));
+        // Collateral and the caps limit the debt, which includes the fee
+        let fee = Self::get_origination_fee(&e, token.clone()) as i128;
+        let max = max_debt.max(0).checked_mul(10000).expect("overflow") / (10000 + fee);
+        let max = max.min(Self::lendable_cash(&e, &pool));
+
+        // A borrow must leave the debt at or above the minimum
+        let debt_shares = user_pos.debt_shares.get(token.clone()).unwrap_or(0);
+        let new_debt = Self::debt_shares_to_amount(debt_shares, pool.borrow_index)
+            + max
+            + Self::origination_fee(&e, token.clone(), max);
+        if max > 0 && new_debt < Self::get_min_borrow(&e, token) {
+            return 0;
+        }
+        max
+    }
+
+    /// Dry-runs `borrow`: returns whether `user` could borrow `amount` of
+    /// `token` right now and the health factor their position would end at,
+    /// as `get_health_factor` reports it. Nothing is saved. Like `borrow`,
+    /// panics if a price the position needs is unset or stale.
+    pub fn preview_borrow(e: Env, user: Address, token: Address, amount: i128) -> (bool, i128) {
+        let mut pool = Self::get_pool(&e, token.clone());
+        Self::accrue_interest(&e, &mut pool);
+        let user_pos = Self::get_user_pos(&e, user.clone());
+        match Self::plan_borrow(&e, &user, &token, amount, &mut pool, &mut user_pos.clone()) {
+            Ok((_, health_factor)) => (true, health_factor),
+            Err(_) => {
+                let mut health = Self::get_user_health(&e, &user, &user_pos);
+                let debt_amount =
+                    amount.max(0) + Self::origination_fee(&e, token.clone(), amount.max(0));
+                health.debt_value += Self::debt_amount_value(&e, token, debt_amount);
+                (false, Self::health_factor(&health))
+            }
+        }
+    }
+
+    /// Dry-runs `liquidate`: returns the `collateral_token` the liquidator
+    /// would receive, bonus included and protocol fee taken out, and the debt they would repay once
+    /// `repay_amount` is clamped to the close factor. Returns zeros when the
+    /// borrower is healthy or `liquidate` would refuse for another reason.
+    /// Nothing is saved.
+    pub fn preview_liquidation(
+        e: Env,
+        borrower: Address,
+        debt_token: Address,
+        collateral_token: Address,
+        repay_amount: i128,
+    ) -> (i128, i128) {
+        let blocked = Self::pause_reason(&e, Action::Liquidate).is_some()
+            || Self::in_grace_period(&e)
+            || Self::is_price_frozen(&e, debt_token.clone())
+            || Self::is_price_frozen(&e, collateral_token.clone())
+            || !Self::is_collateral(&e, &borrower, collateral_token.clone());
+        if blocked || repay_amount <= 0 {
+            return (0, 0);
+        }
+        let borrower_pos = Self::get_user_pos(&e, borrower.clone());
+        let health = Self::get_user_health(&e, &borrower, &borrower_pos);
+        if health.liquidation_limit >= health.debt_value {
+            return (0, 0);
+        }
+
+        let mut debt_pool = Self::get_pool(&e, debt_token.clone());
+        Self::accrue_interest(&e, &mut debt_pool);
+        let debt_shares = borrower_pos
+            .debt_shares
+            .get(debt_token.clone())
+            .unwrap_or(0);
+        let current_debt = Self::debt_shares_to_amount(debt_shares, debt_pool.borrow_index);
+        let max_repay = Self::max_liquidation_repay(&e, debt_token.clone(), current_debt);
+        let repay = repay_amount.min(max_repay);
+        if repay == 0 || Self::leaves_dust(&e, debt_token.clone(), current_debt - repay) {
+            return (0, 0);
+        }
+
+        let mut collateral_pool = Self::get_pool(&e, collateral_token.clone());
+        Self::accrue_interest(&e, &mut collateral_pool);
+        let collateral_shares = borrower_pos
+            .deposit_shares
+            .get(collateral_token.clone())
+            .unwrap_or(0);
+        let current_collateral = Self::supply_shares_to_amount(&collateral_pool, collateral_shares);
+        if debt_token == collateral_token {
+            // `liquidate` adds the repayment to the cash it pays out of
+            collateral_pool.total_cash += repay;
+        }
+        let seized = Self::liquidation_seize_target(
+            &e,
+            debt_token.clone(),
+            collateral_token.clone(),
+            repay,
+            repay == max_repay,
+        )
+        .min(current_collateral);
+        let repaid_collateral =
+            Self::collateral_for_repay(&e, debt_token, collateral_token, repay, 0);
+        let protocol_fee = Self::liquidation_protocol_fee(&e, seized, repaid_collateral);
+        if seized - protocol_fee > Self::lendable_cash(&e, &collateral_pool) {
+            return (0, 0);
+        }
+        (seized - protocol_fee, repay)
+    }
+
+    /// Returns the most `user` can withdraw of `token` right now: their
+    /// deposit, limited by the pool's liquidity and by what keeps their
+    /// position within its borrow limit. This is the amount a `withdraw`
+    /// of `i128::MAX` takes.
+    pub fn max_withdraw(e: Env, user: Address, token: Address) -> i128 {
+        let user_pos = Self::get_user_pos(&e, user.clone());
+        let shares = user_pos.deposit_shares.get(token.clone()).unwrap_or(0);
+        if shares == 0 {
+            return 0;
+        }
+        let mut pool = Self::get_pool(&e, token.clone());
+        Self::accrue_interest(&e, &mut pool);
+        Self::supply_shares_to_amount(&pool, shares)
+            .min(Self::lendable_cash(&e, &pool))
+            .min(Self::max_withdraw_for_health(&e, &user, &user_pos, token))
+    }
+
+    /// Returns how much more of `token` `delegatee` may borrow against
+    /// `delegator`'s collateral.
+    pub fn borrow_allowance(
+        e: Env,
+        delegator: Address,
+        delegatee: Address,
+        token: Address,
+    ) -> i128 {
+        e.storage()
+            .persistent()
+            .get(&DataKey::BorrowAllowance(delegator, delegatee, token))
+            .unwrap_or(0)
+    }
+
+    /// Returns `user`'s supply shares in `token`'s pool, or 0 if they have
+    /// no deposit. These are the balances of the pool's supply token.
+    pub fn deposit_shares_of(e: Env, user: Address, token: Address) -> i128 {
+        Self::get_user_pos(&e, user)
+            .deposit_shares
+            .get(token)
+            .unwrap_or(0)
+    }
+
+    /// Returns the interest `user` has earned on their `token` deposit so
+    /// far: its current value less the principal they put in. Principal
+    /// follows the deposit pro rata as it is withdrawn, transferred or
+    /// seized. Deposits made before principal was tracked count in full.
+    /// Negative if the pool has written off bad debt.
+    pub fn supplier_accrued(e: Env, user: Address, token: Address) -> i128 {
+        let principal = Self::get_supply_principal(&e, &user, token.clone());
+        Self::deposit_of(e, user, token) - principal
+    }
+
+    /// Returns the supply shares `user` has queued with `request_withdraw`
+    /// and not yet claimed.
+    pub fn queued_withdraw(e: Env, user: Address, token: Address) -> i128 {
+        e.storage()
+            .persistent()
+            .get(&ExtraKey::WithdrawRequest(user, token))
+            .unwrap_or(0)
+    }
+
+    /// Returns the receipt token registered with `set_supply_token`, if any.
+    pub fn supply_token_of(e: Env, token: Address) -> Option<Address> {
+        e.storage().persistent().get(&DataKey::SupplyToken(token))
+    }
+
+    /// Returns how much of `token` `user` has deposited, interest
+    /// included, or 0 if they have no deposit.
+    pub fn deposit_of(e: Env, user: Address, token: Address) -> i128 {
+        let shares = Self::get_user_pos(&e, user)
+            .deposit_shares
+            .get(token.clone())
+            .unwrap_or(0);
+        if shares == 0 {
+            return 0;
+        }
+        let mut pool = Self::get_pool(&e, token);
+        Self::accrue_interest(&e, &mut pool);
+        Self::supply_shares_to_amount(&pool, shares)
+    }
+
+    /// Returns how much of `token` `user` owes, interest included, or 0
+    /// if they have no debt.
+    pub fn debt_of(e: Env, user: Address, token: Address) -> i128 {
+        let shares = Self::get_user_pos(&e, user)
+            .debt_shares
+            .get(token.clone())
+            .unwrap_or(0);
+        if shares == 0 {
+            return 0;
+        }
+        let mut pool = Self::get_pool(&e, token);
+        Self::accrue_interest(&e, &mut pool);
+        Self::debt_shares_to_amount(shares, pool.borrow_index)
+    }
+
+    /// Returns the LTV configured for `token`, or 0 if unset.
+    pub fn ltv_of(e: Env, token: Address) -> u32 {
+        Self::get_ltv(&e, token)
+    }
+
+    /// Returns the price configured for `token`, or 0 if unset.
+    pub fn price_of(e: Env, token: Address) -> i128 {
+        Self::get_price(&e, token)
+    }
+
+    // --- Helper Functions ---
+    /// Stores `token`'s decimals for `get_decimals`, panicking if it
+    /// doesn't answer like a token contract.
+    fn record_decimals(e: &Env, token: &Address) {
+        let decimals = match token::Client::new(e, token).try_decimals() {
+            Ok(Ok(decimals)) => decimals,
+            _ => panic!("not a token contract"),
+        };
+        e.storage()
+            .persistent()
+            .set(&DataKey::Decimals(token.clone()), &decimals);
+    }
+
+    fn get_owner(e: &Env) -> Address {
+        e.storage()
+            .persistent()
+            .get(&DataKey::Owner)
+            .expect("owner not set")
+    }
+
+    /// Requires `caller`'s auth, and that they are the owner or guardian.
+    fn require_owner_or_guardian(e: &Env, caller: &Address) {
+        let is_guardian = Self::get_guardian(e.clone()).as_ref() == Some(caller);
+        if *caller != Self::get_owner(e) && !is_guardian {
+            panic!("caller is not the owner or guardian");
+        }
+        caller.require_auth();
+    }
+
+    /// Requires `caller`'s auth, and that they are the owner.
+    fn require_owner_caller(e: &Env, caller: &Address) {
+        if *caller != Self::get_owner(e) {
+            panic!("caller is not the owner");
+        }
+        caller.require_auth();
+    }
+
+    /// Panics if `token` is this contract's own address.
+    fn require_not_self(e: &Env, token: &Address) {
+        if *token == e.current_contract_address() {
+            panic!("token cannot be the pool itself");
+        }
+    }
+
+    /// Panics unless `token` is a listed market, so risk parameters can't
+    /// be written for a mistyped address.
+    fn require_listed(e: &Env, token: &Address) {
+        Self::require_not_self(e, token);
+        if !e.storage().persistent().has(&DataKey::Pool(token.clone())) {
+            panic!("pool not initialized");
+        }
+    }
+
+    /// Adds `shares` of `token` to `user`'s deposit. A new deposit of a
+    /// borrowed asset, or one that would mix with isolated collateral, is
+    /// held as a plain deposit instead of collateral.
+    fn credit_deposit(
+        e: &Env,
+        user: &Address,
+        user_pos: &mut UserPosition,
+        token: Address,
+        shares: i128,
+    ) {
+        let old_deposits = user_pos.deposit_shares.get(token.clone()).unwrap_or(0);
+        let is_borrowed = user_pos.debt_shares.get(token.clone()).unwrap_or(0) > 0;
+        if old_deposits == 0
+            && (is_borrowed || Self::mixes_isolated(e, user, user_pos, token.clone()))
+        {
+            e.storage().persistent().set(
+                &DataKey::CollateralEnabled(user.clone(), token.clone()),
+                &false,
+            );
+        }
+        let new_deposits = old_deposits.checked_add(shares).expect("overflow");
+        user_pos.deposit_shares.set(token, new_deposits);
+    }
+
+    /// Reports that `user`'s `token` deposit changed by `shares` other than
+    /// by `transfer_deposit` to the pool's supply token, if it has one, so
+    /// it can publish the matching SEP-41 `mint` or `burn`.
+    fn notify_supply_token(e: &Env, token: &Address, user: &Address, shares: i128) {
+        let supply_token: Option<Address> = e
+            .storage()
+            .persistent()
+            .get(&DataKey::SupplyToken(token.clone()));
+        let Some(supply_token) = supply_token else {
+            return;
+        };
+        let client = SupplyTokenClient::new(e, &supply_token);
+        if shares > 0 {
+            client.notify_mint(user, &shares);
+        } else if shares < 0 {
+            client.notify_burn(user, &-shares);
+        }
+    }
+
+    fn get_supply_principal(e: &Env, user: &Address, token: Address) -> i128 {
+        e.storage()
+            .persistent()
+            .get(&ExtraKey::SupplyPrincipal(user.clone(), token))
+            .unwrap_or(0)
+    }
+
+    fn add_supply_principal(e: &Env, user: &Address, token: Address, amount: i128) {
+        let principal = Self::get_supply_principal(e, user, token.clone())
+            .checked_add(amount)
+            .expect("overflow");
+        e.storage()
+            .persistent()
+            .set(&ExtraKey::SupplyPrincipal(user.clone(), token), &principal);
+    }
+
+    /// Takes the principal behind `shares` of the `shares_before` that
+    /// `user` held in `token` off their deposit, and returns it.
+    fn remove_supply_principal(
+        e: &Env,
+        user: &Address,
+        token: Address,
+        shares: i128,
+        shares_before: i128,
+    ) -> i128 {
+        let principal = Self::get_supply_principal(e, user, token.clone());
+        if principal == 0 {
+            return 0;
+        }
+        let key = ExtraKey::SupplyPrincipal(user.clone(), token);
+        if shares >= shares_before {
+            e.storage().persistent().remove(&key);
+            return principal;
+        }
+        let removed = principal.checked_mul(shares).expect("overflow") / shares_before;
+        e.storage().persistent().set(&key, &(principal - removed));
+        removed
+    }
+
+    /// Panics if the protocol was unpaused less than `DataKey::GracePeriod` ago.
+    fn require_grace_period_over(e: &Env) {
+        if Self::in_grace_period(e) {
+            panic!("liquidation grace period active");
+        }
+    }
+
+    /// Whether liquidations are still held back after the last unpause.
+    fn in_grace_period(e: &Env) -> bool {
+        let storage = e.storage().persistent();
+        let grace_period: u64 = storage.get(&DataKey::GracePeriod).unwrap_or(0);
+        let unpaused_at: Option<u64> = storage.get(&DataKey::UnpauseTimestamp);
+        let in_grace = unpaused_at.is_some_and(|unpaused_at| {
+            e.ledger().timestamp().saturating_sub(unpaused_at) <= grace_period
+        });
+        grace_period > 0 && in_grace
+    }
+
+    /// Panics if `user` supplied more recently than `DataKey::WithdrawCooldown`.
+    fn require_cooldown_elapsed(e: &Env, user: &Address) {
+        let storage = e.storage().persistent();
+        let cooldown: u64 = storage.get(&DataKey::WithdrawCooldown).unwrap_or(0);
+        if cooldown > 0 {
+            let last_supply: u64 = storage.get(&DataKey::LastSupply(user.clone())).unwrap_or(0);
+            if e.ledger().timestamp().saturating_sub(last_supply) < cooldown {
+                panic!("withdraw cooldown active");
+            }
+        }
+    }
+
+    /// Panics if `token`'s allowlist is on and `account` isn't on it.
+    fn require_allowed(e: &Env, token: Address, account: Address) {
+        if !Self::is_allowed(e, token, account) {
+            panic!("account not allowed");
+        }
+    }
+
+    fn is_allowed(e: &Env, token: Address, account: Address) -> bool {
+        let storage = e.storage().persistent();
+        let enabled = storage
+            .get(&DataKey::Allowlist(token.clone()))
+            .unwrap_or(false);
+        !enabled || storage.has(&DataKey::Allowed(token, account))
+    }
+
+    /// Panics if a large price move froze `token` (see `set_max_price_deviation`).
+    fn require_price_not_frozen(e: &Env, token: Address) {
+        if Self::is_price_frozen(e, token) {
+            panic!("price circuit breaker tripped");
+        }
+    }
+
+    fn is_price_frozen(e: &Env, token: Address) -> bool {
+        e.storage()
+            .persistent()
+            .has(&DataKey::PriceFrozen(token.clone()))
+            || Self::oracle_price_jumped(e, token)
+    }
+
+    /// Runs `f` with the pool locked, panicking if it already is. Soroban
+    /// refuses contract re-entry on its own; the lock keeps that guarantee
+    /// explicit for every call that moves tokens. The flag only lives for
+    /// one invocation, so it is kept in temporary storage.
+    fn non_reentrant<T>(e: &Env, f: impl FnOnce() -> T) -> T {
+        let storage = e.storage().temporary();
+        if storage.has(&DataKey::Locked) {
+            panic!("reentrancy");
+        }
+        storage.set(&DataKey::Locked, &true);
+        let result = f();
+        e.storage().temporary().remove(&DataKey::Locked);
+        result
+    }
+
+    /// Panics if `action` is paused, either on its own or by the global
+    /// pause. `Withdraw` and `Repay` stay open under the global pause when
+    /// exits are allowed while paused.
+    fn require_not_paused(e: &Env, action: Action) {
+        if let Some(reason) = Self::pause_reason(e, action) {
+            panic!("{}", reason);
+        }
+    }
+
+    /// Why `action` is paused, or `None` if it may run.
+    fn pause_reason(e: &Env, action: Action) -> Option<&'static str> {
+        let storage = e.storage().persistent();
+        if storage.get(&DataKey::ActionPaused(action)).unwrap_or(false) {
+            return Some("action paused");
+        }
+        if !storage.get(&DataKey::Paused).unwrap_or(false) {
+            return None;
+        }
+        let is_exit = matches!(action, Action::Withdraw | Action::Repay);
+        if is_exit && storage.get(&DataKey::ExitsWhilePaused).unwrap_or(false) {
+            return None;
+        }
+        Some("protocol paused")
+    }
+
+    /// Panics if `token`'s pool was retired with `deactivate_pool`.
+    fn require_pool_active(e: &Env, token: Address) {
+        if !Self::get_pool_active(e, token) {
+            panic!("pool is not active");
+        }
+    }
+
+    /// Whether `token`'s pool was frozen with `set_frozen`.
+    fn is_frozen(e: &Env, token: Address) -> bool {
+        e.storage().persistent().has(&ExtraKey::Frozen(token))
+    }
+
+    fn get_pool_active(e: &Env, token: Address) -> bool {
+        e.storage()
+            .persistent()
+            .get(&DataKey::PoolActive(token))
+            .unwrap_or(true)
+    }
+
+    /// Whether `user` counts their `token` deposit as collateral (the default).
+    fn is_collateral(e: &Env, user: &Address, token: Address) -> bool {
+        e.storage()
+            .persistent()
+            .get(&DataKey::CollateralEnabled(user.clone(), token))
+            .unwrap_or(true)
+    }
+
+    fn get_isolation(e: &Env, token: Address) -> IsolationConfig {
+        e.storage()
+            .persistent()
+            .get(&DataKey::Isolation(token))
+            .unwrap_or(IsolationConfig {
+                isolated: false,
+                debt_ceiling: 0,
+            })
+    }
+
+    /// Tokens whose deposits currently back `user`'s debt.
+    fn collateral_tokens(e: &Env, user: &Address, user_pos: &UserPosition) -> Vec<Address> {
+        let mut tokens = Vec::new(e);
+        for (token, shares) in user_pos.deposit_shares.iter() {
+            if shares > 0 && Self::is_collateral(e, user, token.clone()) {
+                tokens.push_back(token);
+            }
+        }
+        tokens
+    }
+
+    /// The isolated asset that is `user`'s only collateral, if there is one.
+    fn isolated_collateral(e: &Env, user: &Address, user_pos: &UserPosition) -> Option<Address> {
+        let tokens = Self::collateral_tokens(e, user, user_pos);
+        if tokens.len() != 1 {
+            return None;
+        }
+        let token = tokens.get_unchecked(0);
+        Self::get_isolation(e, token.clone())
+            .isolated
+            .then_some(token)
+    }
+
+    /// The most debt value `user` may carry under isolation mode, in the
+    /// units `get_user_health` returns, or `None` if they aren't isolated.
+    fn isolation_debt_limit(e: &Env, user: &Address, user_pos: &UserPosition) -> Option<i128> {
+        let isolated = Self::isolated_collateral(e, user, user_pos)?;
+        let ceiling = Self::get_isolation(e, isolated).debt_ceiling;
+        Some(
+            ceiling
+                .checked_mul(10i128.pow(ACCOUNTING_DECIMALS))
+                .expect("overflow"),
+        )
+    }
+
+    /// Whether adding `token` to `user`'s collateral would combine an
+    /// isolated asset with any other.
+    fn mixes_isolated(e: &Env, user: &Address, user_pos: &UserPosition, token: Address) -> bool {
+        let mut others = Self::collateral_tokens(e, user, user_pos);
+        if let Some(index) = others.first_index_of(&token) {
+            others.remove(index);
+        }
+        if others.is_empty() {
+            return false;
+        }
+        Self::get_isolation(e, token).isolated
+            || others
+                .iter()
+                .any(|other| Self::get_isolation(e, other).isolated)
+    }
+
+    /// Whether borrowing `token` would be backed by `user`'s own collateral
+    /// deposit of `token`.
+    fn backs_own_loan(e: &Env, user: &Address, user_pos: &UserPosition, token: Address) -> bool {
+        let supplied = user_pos.deposit_shares.get(token.clone()).unwrap_or(0);
+        supplied > 0 && Self::is_collateral(e, user, token)
+    }
+
+    fn get_pool_list(e: &Env) -> Vec<Address> {
+        e.storage()
+            .persistent()
+            .get(&DataKey::PoolList)
+            .unwrap_or(Vec::new(e))
+    }
+
+    /// Sums `amount_of` every listed pool, accrued to now, valued at the
+    /// pool token's price. Unpriced pools count as 0.
+    fn pools_value(e: &Env, amount_of: fn(&Pool) -> i128) -> i128 {
+        let mut total: i128 = 0;
+        for token in Self::get_pool_list(e).iter() {
+            let mut pool = Self::get_pool(e, token.clone());
+            Self::accrue_interest(e, &mut pool);
+            let value = Self::value_of(
+                amount_of(&pool),
+                Self::get_price(e, token.clone()),
+                Self::get_decimals(e, token),
+            );
+            total = total.checked_add(value).expect("overflow");
+        }
+        total
+    }
+
+    /// Gets the `Pool` struct for a given `token`.
+    fn get_pool(e: &Env, token: Address) -> Pool {
+        e.storage()
+            .persistent()
+            .get(&DataKey::Pool(token))
+            .expect("pool not initialized")
+    }
+
+    /// Saves the `Pool` struct for a given `token`.
+    fn save_pool(e: &Env, token: Address, pool: &Pool) {
+        e.storage().persistent().set(&DataKey::Pool(token), pool);
+    }
+
+    /// Gets the `UserPosition` struct for a given `user`.
+    /// Returns a new, empty struct if this is a new user.
+    fn get_user_pos(e: &Env, user: Address) -> UserPosition {
+        e.storage()
+            .persistent()
+            .get(&DataKey::UserPos(user))
+            .unwrap_or_else(|| UserPosition {
+                deposit_shares: Map::new(e),
+                debt_shares: Map::new(e),
+            })
+    }
+
+    fn save_user_pos(e: Env, user: Address, pos: &UserPosition) {
+        e.storage()
+            .persistent()
+            .set(&DataKey::UserPos(user.clone()), pos);
+        Self::update_borrowers(&e, user, !pos.debt_shares.is_empty());
+    }
+
+    /// Adds `user` to or removes them from the `borrowers` registry. A
+    /// removal moves the last borrower into the freed slot, so both are O(1).
+    fn update_borrowers(e: &Env, user: Address, has_debt: bool) {
+        let storage = e.storage().persistent();
+        let index_key = ExtraKey::BorrowerIndex(user.clone());
+        let index: Option<u32> = storage.get(&index_key);
+        let count: u32 = storage.get(&ExtraKey::BorrowerCount).unwrap_or(0);
+        match (index, has_debt) {
+            (None, true) => {
+                storage.set(&ExtraKey::BorrowerAt(count), &user);
+                storage.set(&index_key, &count);
+                storage.set(&ExtraKey::BorrowerCount, &(count + 1));
+            }
+            (Some(index), false) => {
+                let last = count - 1;
+                if index != last {
+                    let moved: Address = storage.get(&ExtraKey::BorrowerAt(last)).unwrap();
+                    storage.set(&ExtraKey::BorrowerAt(index), &moved);
+                    storage.set(&ExtraKey::BorrowerIndex(moved), &index);
+                }
+                storage.remove(&ExtraKey::BorrowerAt(last));
+                storage.remove(&index_key);
+                storage.set(&ExtraKey::BorrowerCount, &last);
+            }
+            _ => {}
+        }
+    }
+
+    /// Sets `token`'s entry in one of a position's share maps, dropping it
+    /// once it reaches zero so emptied positions don't keep stale keys.
+    fn set_shares(shares: &mut Map<Address, i128>, token: Address, amount: i128) {
+        if amount == 0 {
+            shares.remove(token);
+        } else {
+            shares.set(token, amount);
+        }
+    }
+
+    fn get_ltv(e: &Env, token: Address) -> u32 {
+        e.storage()
+            .persistent()
+            .get(&DataKey::Ltv(token))
+            .unwrap_or(0)
+    }
+
+    /// Transfers `amount` of `token` from `from` into the pool and returns
+    /// how much arrived, which is less than `amount` for fee-on-transfer tokens.
+    fn pull_tokens(e: &Env, token: &Address, from: &Address, amount: i128) -> i128 {
+        let this = e.current_contract_address();
+        let token_client = token::Client::new(e, token);
+        let balance_before = token_client.balance(&this);
+        token_client.transfer(from, &this, &amount);
+        token_client.balance(&this) - balance_before
+    }
+
+    fn get_price_decimals(e: &Env) -> u32 {
+        e.storage()
+            .persistent()
+            .get(&DataKey::PriceDecimals)
+            .unwrap_or(DEFAULT_PRICE_DECIMALS)
+    }
+
+    fn get_decimals(e: &Env, token: Address) -> u32 {
+        e.storage()
+            .persistent()
+            .get(&DataKey::Decimals(token))
+            .unwrap_or(ACCOUNTING_DECIMALS)
+    }
+
+    /// Defaults to the asset's LTV when no threshold was set.
+    fn get_liquidation_threshold(e: &Env, token: Address) -> u32 {
+        e.storage()
+            .persistent()
+            .get(&DataKey::LiquidationThreshold(token.clone()))
+            .unwrap_or_else(|| Self::get_ltv(e, token))
+    }
+
+    pub(crate) fn get_emode_category(e: &Env, id: u32) -> Option<EmodeCategory> {
+        e.storage().persistent().get(&DataKey::EmodeCategory(id))
+    }
+
+    fn get_asset_emode(e: &Env, token: Address) -> u32 {
+        e.storage()
+            .persistent()
+            .get(&DataKey::AssetEmode(token))
+            .unwrap_or(0)
+    }
+
+    fn get_user_emode(e: &Env, user: &Address) -> u32 {
+        e.storage()
+            .persistent()
+            .get(&DataKey::UserEmode(user.clone()))
+            .unwrap_or(0)
+    }
+
+    fn get_interest_rate(e: &Env, token: Address) -> u32 {
+        e.storage()
+            .persistent()
+            .get(&DataKey::InterestRate(token))
+            .unwrap_or(0)
+    }
+
+    fn get_borrow_cap(e: &Env, token: Address) -> i128 {
+        e.storage()
+            .persistent()
+            .get(&DataKey::BorrowCap(token))
+            .unwrap_or(0)
+    }
+
+    fn get_origination_fee(e: &Env, token: Address) -> u32 {
+        e.storage()
+            .persistent()
+            .get(&DataKey::OriginationFee(token))
+            .unwrap_or(0)
+    }
+
+    fn get_min_borrow(e: &Env, token: Address) -> i128 {
+        e.storage()
+            .persistent()
+            .get(&DataKey::MinBorrow(token))
+            .unwrap_or(0)
+    }
+
+    fn get_supply_cap(e: &Env, token: Address) -> i128 {
+        e.storage()
+            .persistent()
+            .get(&DataKey::SupplyCap(token))
+            .unwrap_or(0)
+    }
+
+    fn get_reserve_factor(e: &Env, token: Address) -> u32 {
+        e.storage()
+            .persistent()
+            .get(&DataKey::ReserveFactor(token))
+            .unwrap_or(0)
+    }
+
+    fn get_close_factor(e: &Env) -> u32 {
+        e.storage()
+            .persistent()
+            .get(&DataKey::CloseFactor)
+            .unwrap_or(5000)
+    }
+
+    /// Falls back to the constructor's default bonus, then to 0.
+    fn get_liquidation_bonus(e: &Env, token: Address) -> u32 {
+        let storage = e.storage().persistent();
+        storage
+            .get(&DataKey::LiquidationBonus(token))
+            .or_else(|| storage.get(&ExtraKey::DefaultLiquidationBonus))
+            .unwrap_or(0)
+    }
+
+    /// The protocol's cut of a liquidation seizing `seize_amount` of
+    /// collateral for debt worth `repaid_collateral` of it: its
+    /// `LiquidationProtocolFee` share of the bonus, whatever the seize
+    /// was floored or capped to. A seize worth no more than the debt pays
+    /// no fee.
+    fn liquidation_protocol_fee(e: &Env, seize_amount: i128, repaid_collateral: i128) -> i128 {
+        let fee: u32 = e
+            .storage()
+            .persistent()
+            .get(&ExtraKey::LiquidationProtocolFee)
+            .unwrap_or(0);
+        let bonus_amount = seize_amount - repaid_collateral;
+        if fee == 0 || bonus_amount <= 0 {
+            return 0;
+        }
+        Self::apply_bps(bonus_amount, fee)
+    }
+
+    /// Publishes the `AdminAction` for a change of `param` to `value`.
+    fn publish_admin_action(
+        e: &Env,
+        param: &str,
+        token: Option<Address>,
+        value: impl IntoVal<Env, Val>,
+    ) {
+        AdminAction {
+            param: Symbol::new(e, param),
+            token,
+            value: value.into_val(e),
+        }
+        .publish(e);
+    }
+
+    /// The most debt value `health` may carry after a borrow: its borrow
+    /// limit shrunk by the `ExtraKey::MinHealthBuffer` margin.
+    fn buffered_borrow_limit(e: &Env, health: &AccountHealth) -> i128 {
+        let buffer: u32 = e
+            .storage()
+            .persistent()
+            .get(&ExtraKey::MinHealthBuffer)
+            .unwrap_or(10000);
+        health.borrow_limit.checked_mul(10000).expect("overflow") / buffer as i128
+    }
+
+    /// The origination fee on a borrow of `amount` of `token`, rounded up.
+    fn origination_fee(e: &Env, token: Address, amount: i128) -> i128 {
+        Self::mul_div_ceil(
+            amount,
+            Self::get_origination_fee(e, token.clone()) as i128,
+            10000,
+        )
+    }
+
+    /// Values `amount` of new `token` debt, rounding up against the borrower.
+    fn debt_amount_value(e: &Env, token: Address, amount: i128) -> i128 {
+        let price = Self::get_checked_price(e, token.clone());
+        Self::debt_value_of(amount, price, Self::get_decimals(e, token))
+    }
+
+    fn require_valid_close_factor(close_factor: u32) {
+        if close_factor == 0 || close_factor > 10000 {
+            panic!("close factor must be between 1 and 10000");
+        }
+    }
+
+    fn require_valid_liquidation_bonus(bonus: u32) {
+        if bonus > 2000 {
+            panic!("liquidation bonus cannot be over 2000");
+        }
+    }
+
+    fn write_price(e: &Env, token: Address, price: i128) {
+        Self::require_listed(e, &token);
+        if price < 0 {
+            panic!("price cannot be negative");
+        }
+        let (previous, _) = Self::get_stored_price(e, token.clone());
+        if Self::exceeds_price_deviation(e, previous, price) {
+            e.storage()
+                .persistent()
+                .set(&DataKey::PriceFrozen(token.clone()), &true);
+            PriceCircuitBreaker {
+                token: token.clone(),
+                previous_price: previous,
+                price,
+            }
+            .publish(e);
+        }
+        e.storage()
+            .persistent()
+            .set(&DataKey::Price(token.clone()), &price);
+        e.storage()
+            .persistent()
+            .set(&DataKey::PriceTimestamp(token), &e.ledger().timestamp());
+    }
+
+    /// Returns the price of `token` with `price_decimals` decimals.
+    fn get_price(e: &Env, token: Address) -> i128 {
+        Self::get_price_data(e, token.clone(), &Self::price_source(e, token)).0
+    }
+
+    /// Like `get_price`, but panics if a set price is older than
+    /// `DataKey::MaxPriceAge`, or if an oracle price tripped the circuit
+    /// breaker (see `oracle_price_jumped`). Used on every risk-sensitive path.
+    fn get_checked_price(e: &Env, token: Address) -> i128 {
+        let source = Self::price_source(e, token.clone());
+        let price = Self::price_for(e, token.clone(), &source);
+        if matches!(source, PriceSource::Oracle) {
+            let key = ExtraKey::AcceptedPrice(token);
+            let accepted: i128 = e.storage().persistent().get(&key).unwrap_or(0);
+            if Self::exceeds_price_deviation(e, accepted, price) {
+                panic!("price circuit breaker tripped");
+            }
+            if accepted != price {
+                e.storage().persistent().set(&key, &price);
+            }
+        }
+        price
+    }
+
+    /// Whether oracle-priced `token`'s price moved further from its last
+    /// accepted price than `DataKey::MaxPriceDeviation` allows. Unlike a
+    /// stored price, the move can't leave a `DataKey::PriceFrozen` behind,
+    /// since the call that reads it fails: the asset stays frozen until
+    /// the oracle is back in range or `clear_price_freeze` accepts the
+    /// new price.
+    fn oracle_price_jumped(e: &Env, token: Address) -> bool {
+        if !matches!(Self::price_source(e, token.clone()), PriceSource::Oracle) {
+            return false;
+        }
+        let (price, _) = Self::get_price_data(e, token.clone(), &PriceSource::Oracle);
+        let accepted: i128 = e
+            .storage()
+            .persistent()
+            .get(&ExtraKey::AcceptedPrice(token))
+            .unwrap_or(0);
+        Self::exceeds_price_deviation(e, accepted, price)
+    }
+
+    /// Whether moving from `previous` to `price` is a bigger move than
+    /// `DataKey::MaxPriceDeviation`. Never true without a previous price.
+    fn exceeds_price_deviation(e: &Env, previous: i128, price: i128) -> bool {
+        let max_deviation: u32 = e
+            .storage()
+            .persistent()
+            .get(&DataKey::MaxPriceDeviation)
+            .unwrap_or(0);
+        if max_deviation == 0 || previous <= 0 {
+            return false;
+        }
+        let moved = (price - previous)
+            .abs()
+            .checked_mul(10000)
+            .expect("overflow");
+        moved
+            > previous
+                .checked_mul(max_deviation as i128)
+                .expect("overflow")
+    }
+
+    /// Returns the price of `token` read from `source`, panicking if it is
+    /// unset or older than `DataKey::MaxPriceAge`. Overridden prices are
+    /// never stale. An unpriced asset is an error rather than worth 0, so
+    /// collateral is never silently left out of health math.
+    fn price_for(e: &Env, token: Address, source: &PriceSource) -> i128 {
+        let (price, timestamp) = Self::get_price_data(e, token.clone(), source);
+        if price <= 0 {
+            panic!("price not set for {:?}", token);
+        }
+        let max_age: u64 = e
+            .storage()
+            .persistent()
+            .get(&DataKey::MaxPriceAge)
+            .unwrap_or(0);
+        if max_age > 0 && e.ledger().timestamp().saturating_sub(timestamp) > max_age {
+            panic!("price is stale");
+        }
+        price
+    }
+
+    /// The source `token` is normally priced from.
+    fn price_source(e: &Env, token: Address) -> PriceSource {
+        let use_oracle = e
+            .storage()
+            .persistent()
+            .get(&DataKey::UseOracle(token))
+            .unwrap_or(false);
+        if use_oracle {
+            PriceSource::Oracle
+        } else {
+            PriceSource::Stored
+        }
+    }
+
+    /// Returns `(price, timestamp)` for `token` as read from `source`.
+    fn get_price_data(e: &Env, token: Address, source: &PriceSource) -> (i128, u64) {
+        match source {
+            PriceSource::Stored => Self::get_stored_price(e, token),
+            PriceSource::Oracle => Self::get_oracle_price(e, token.clone())
+                .unwrap_or_else(|| Self::get_stored_price(e, token)),
+            PriceSource::Override(prices) => match prices.get(token.clone()) {
+                Some(price) => (price, e.ledger().timestamp()),
+                None => Self::get_price_data(e, token.clone(), &Self::price_source(e, token)),
+            },
+        }
+    }
+
+    fn get_stored_price(e: &Env, token: Address) -> (i128, u64) {
+        let price = e
+            .storage()
+            .persistent()
+            .get(&DataKey::Price(token.clone()))
+            .unwrap_or(0);
+        let timestamp = e
+            .storage()
+            .persistent()
+            .get(&DataKey::PriceTimestamp(token))
+            .unwrap_or(0);
+        (price, timestamp)
+    }
+
+    fn get_oracle_price(e: &Env, token: Address) -> Option<(i128, u64)> {
+        let oracles: Option<Vec<Address>> = e.storage().persistent().get(&ExtraKey::Oracles);
+        if let Some(oracles) = oracles {
+            return Self::get_median_price(e, token, oracles);
+        }
+        let oracle: Address = e
+            .storage()
+            .persistent()
+            .get(&DataKey::Oracle)
+            .expect("oracle not set");
+        Self::read_oracle(e, &oracle, token).expect("oracle call failed")
+    }
+
+    /// The median of the fresh prices `oracles` quote for `token`, dated
+    /// to the oldest of them, or `None` if none of them quote it. A feed
+    /// whose call fails counts as stale, so one broken feed can't block
+    /// pricing while enough others are fresh.
+    fn get_median_price(e: &Env, token: Address, oracles: Vec<Address>) -> Option<(i128, u64)> {
+        let max_age: u64 = e
+            .storage()
+            .persistent()
+            .get(&DataKey::MaxPriceAge)
+            .unwrap_or(0);
+        let now = e.ledger().timestamp();
+        let mut quoted = false;
+        // Fresh prices, kept sorted
+        let mut prices: Vec<i128> = Vec::new(e);
+        let mut oldest = now;
+        for oracle in oracles.iter() {
+            let (price, timestamp) = match Self::read_oracle(e, &oracle, token.clone()) {
+                Ok(Some(data)) => data,
+                Ok(None) => continue,
+                Err(()) => {
+                    quoted = true;
+                    continue;
+                }
+            };
+            quoted = true;
+            if price <= 0 || (max_age > 0 && now.saturating_sub(timestamp) > max_age) {
+                continue;
+            }
+            let at = prices
+                .iter()
+                .position(|p| p > price)
+                .unwrap_or(prices.len() as usize);
+            prices.insert(at as u32, price);
+            oldest = oldest.min(timestamp);
+        }
+        if !quoted {
+            return None;
+        }
+        let min_fresh: u32 = e
+            .storage()
+            .persistent()
+            .get(&ExtraKey::MinOracles)
+            .unwrap_or(1);
+        if prices.len() < min_fresh {
+            panic!("not enough fresh oracle prices");
+        }
+        let mid = prices.len() / 2;
+        let median = if prices.len() % 2 == 1 {
+            prices.get_unchecked(mid)
+        } else {
+            (prices.get_unchecked(mid - 1) + prices.get_unchecked(mid)) / 2
+        };
+        Some((median, oldest))
+    }
+
+    /// `oracle`'s latest price for `token`, converted to the protocol's
+    /// price decimals, or `None` if it doesn't quote `token`. `Err` if a
+    /// call to the oracle fails or its price can't be converted.
+    fn read_oracle(e: &Env, oracle: &Address, token: Address) -> Result<Option<(i128, u64)>, ()> {
+        let client = ReflectorClient::new(e, oracle);
+        let Ok(Ok(data)) = client.try_lastprice(&Asset::Stellar(token)) else {
+            return Err(());
+        };
+        let Some(data) = data else {
+            return Ok(None);
+        };
+
+        // Convert from the oracle's decimals to the protocol's.
+        let Ok(Ok(decimals)) = client.try_decimals() else {
+            return Err(());
+        };
+        let price_decimals = Self::get_price_decimals(e);
+        let price = if decimals >= price_decimals {
+            data.price / 10i128.checked_pow(decimals - price_decimals).ok_or(())?
+        } else {
+            10i128
+                .checked_pow(price_decimals - decimals)
+                .and_then(|scale| data.price.checked_mul(scale))
+                .ok_or(())?
+        };
+        Ok(Some((price, data.timestamp)))
+    }
+
+    /// Values `user`'s position as (borrowing power, debt).
+    /// Deposits `user` has disabled as collateral add no borrowing power.
+    fn get_user_health(e: &Env, user: &Address, user_pos: &UserPosition) -> AccountHealth {
+        Self::user_health_at(e, user, user_pos, |token| Self::get_checked_price(e, token))
+    }
+
+    /// `get_user_health` with every token valued at `price_of(token)`.
+    fn user_health_at(
+        e: &Env,
+        user: &Address,
+        user_pos: &UserPosition,
+        price_of: impl Fn(Address) -> i128,
+    ) -> AccountHealth {
+        let mut health = AccountHealth {
+            collateral_value: 0,
+            borrow_limit: 0,
+            liquidation_limit: 0,
+            debt_value: 0,
+        };
+        let emode = Self::active_emode(e, user, user_pos);
+
+        for (token, shares) in user_pos.deposit_shares.iter() {
+            if !Self::is_collateral(e, user, token.clone()) {
+                continue;
+            }
+            let mut pool = Self::get_pool(e, token.clone());
+            Self::accrue_interest(e, &mut pool);
+            let amount = Self::supply_shares_to_amount(&pool, shares);
+            let price = price_of(token.clone());
+            let value = Self::value_of(amount, price, Self::get_decimals(e, token.clone()));
+            let (ltv, threshold) = Self::collateral_params(e, token, &emode);
+            health.collateral_value += value;
+            health.borrow_limit += Self::apply_bps(value, ltv);
+            health.liquidation_limit += Self::apply_bps(value, threshold);
+        }
+
+        // Calculate total debt value, including interest not yet accrued
+        // to storage (the pool copy is never saved here).
+        for (token, shares) in user_pos.debt_shares.iter() {
+            let mut pool = Self::get_pool(e, token.clone());
+            Self::accrue_interest(e, &mut pool);
+            let amount = Self::debt_shares_to_amount(shares, pool.borrow_index);
+            let price = price_of(token.clone());
+            health.debt_value += Self::debt_value_of(amount, price, Self::get_decimals(e, token));
+        }
+
+        health
+    }
+
+    /// Liquidation limit over debt, out of 10,000, or `i128::MAX` without debt.
+    fn health_factor(health: &AccountHealth) -> i128 {
+        if health.debt_value == 0 {
+            return i128::MAX;
+        }
+        health
+            .liquidation_limit
+            .checked_mul(10000)
+            .expect("overflow")
+            .checked_div(health.debt_value)
+            .expect("div by zero")
+    }
+
+    /// LTV and liquidation threshold `token` is valued at as collateral:
+    /// the e-mode category's when one applies, otherwise the asset's own.
+    fn collateral_params(e: &Env, token: Address, emode: &Option<EmodeCategory>) -> (u32, u32) {
+        match emode {
+            Some(category) => (category.ltv, category.liquidation_threshold),
+            None => (
+                Self::get_ltv(e, token.clone()),
+                Self::get_liquidation_threshold(e, token),
+            ),
+        }
+    }
+
+    /// The e-mode category boosting `user`'s position, if they selected one
+    /// and every asset they supply as collateral or borrow belongs to it.
+    fn active_emode(e: &Env, user: &Address, user_pos: &UserPosition) -> Option<EmodeCategory> {
+        let id = Self::get_user_emode(e, user);
+        if id == 0 {
+            return None;
+        }
+        let in_category = |token: Address| Self::get_asset_emode(e, token) == id;
+        for (token, shares) in user_pos.deposit_shares.iter() {
+            if shares > 0 && Self::is_collateral(e, user, token.clone()) && !in_category(token) {
+                return None;
+            }
+        }
+        for (token, shares) in user_pos.debt_shares.iter() {
+            if shares > 0 && !in_category(token) {
+                return None;
+            }
+        }
+        Self::get_emode_category(e, id)
+    }
+
+    /// Value of `amount` of a token with `decimals` at `price`, rounding
+    /// down. Collateral and repayments are valued with this.
+    pub(crate) fn value_of(amount: i128, price: i128, decimals: u32) -> i128 {
+        Self::scale_to_accounting(amount, decimals, false)
+            .checked_mul(price)
+            .expect("overflow")
+    }
+
+    /// Value of `amount` of debt in a token with `decimals` at `price`,
+    /// rounding up against the borrower.
+    pub(crate) fn debt_value_of(amount: i128, price: i128, decimals: u32) -> i128 {
+        Self::scale_to_accounting(amount, decimals, true)
+            .checked_mul(price)
+            .expect("overflow")
+    }
+
+    /// `bps` out of 10,000 of `value`, rounded toward zero. Only panics
+    /// if the result itself overflows.
+    pub(crate) fn apply_bps(value: i128, bps: u32) -> i128 {
+        let bps = bps as i128;
+        (value / 10000)
+            .checked_mul(bps)
+            .and_then(|whole| whole.checked_add(value % 10000 * bps / 10000))
+            .expect("overflow")
+    }
+
+    /// Scales `amount` of a token with `decimals` to `ACCOUNTING_DECIMALS`.
+    /// Precision lost when scaling down is rounded in the direction given.
+    fn scale_to_accounting(amount: i128, decimals: u32, round_up: bool) -> i128 {
+        if decimals <= ACCOUNTING_DECIMALS {
+            amount
+                .checked_mul(10i128.pow(ACCOUNTING_DECIMALS - decimals))
+                .expect("overflow")
+        } else if round_up {
+            Self::mul_div_ceil(amount, 1, 10i128.pow(decimals - ACCOUNTING_DECIMALS))
+        } else {
+            amount / 10i128.pow(decimals - ACCOUNTING_DECIMALS)
+        }
+    }
+
+    /// Converts accounting units back into an amount of `token`, rounding down.
+    fn from_accounting_units(e: &Env, token: Address, units: i128) -> i128 {
+        let decimals = Self::get_decimals(e, token);
+        if decimals <= ACCOUNTING_DECIMALS {
+            units / 10i128.pow(ACCOUNTING_DECIMALS - decimals)
+        } else {
+            units
+                .checked_mul(10i128.pow(decimals - ACCOUNTING_DECIMALS))
+                .expect("overflow")
+        }
+    }
+
+    /// Largest amount of `token` that `user_pos` can withdraw and stay healthy.
+    ///
+    /// One unit each of value, accounting units and `token` is held back so
+    /// the rounding in share conversions, decimal scaling and
+    /// `get_user_health` can never tip the result under water.
+    fn max_withdraw_for_health(
+        e: &Env,
+        user: &Address,
+        user_pos: &UserPosition,
+        token: Address,
+    ) -> i128 {
+        let health = Self::get_user_health(e, user, user_pos);
+        if health.debt_value == 0 || !Self::is_collateral(e, user, token.clone()) {
+            return i128::MAX;
+        }
+        let emode = Self::active_emode(e, user, user_pos);
+        let (ltv, _) = Self::collateral_params(e, token.clone(), &emode);
+        let unit_value = Self::get_checked_price(e, token.clone())
+            .checked_mul(ltv as i128)
+            .expect("overflow");
+        if unit_value == 0 {
+            // The token doesn't count as collateral, so it can all go.
+            return i128::MAX;
+        }
+        let excess = health.borrow_limit - health.debt_value - 1;
+        if excess <= 0 {
+            return 0;
+        }
+        let units = excess.checked_mul(10000).expect("overflow") / unit_value;
+        (Self::from_accounting_units(e, token, units - 1) - 1).max(0)
+    }
+
+    // --- Interest ---
+
+    /// Accrues borrow interest on `pool` up to the current ledger timestamp.
+    ///
+    /// Interest compounds on every accrual: the index grows by
+    /// `rate * elapsed / SECONDS_PER_YEAR` of itself, where `rate` is the
+    /// pool's annual rate out of 10,000. The increase is rounded up, so
+    /// rounding always favors the pool over borrowers.
+    ///
+    /// The pool's reserve factor share of the new interest, rounded down,
+    /// is set aside in `total_reserves`; the rest accrues to suppliers.
+    fn accrue_interest(e: &Env, pool: &mut Pool) {
+        let now = e.ledger().timestamp();
+        if now <= pool.last_accrual {
+            return;
+        }
+
+        let elapsed = (now - pool.last_accrual) as i128;
+        let rate = Self::borrow_rate_of(e, pool) as i128;
+        let growth = rate.checked_mul(elapsed).expect("overflow");
+        let increase =
+            Self::mul_div_ceil(pool.borrow_index, growth, 10000 * SECONDS_PER_YEAR as i128);
+
+        let debt_before = Self::total_debt(pool);
+        pool.borrow_index = pool.borrow_index.checked_add(increase).expect("overflow");
+        pool.last_accrual = now;
+
+        let interest = Self::total_debt(pool) - debt_before;
+        let reserve_factor = Self::get_reserve_factor(e, pool.token.clone());
+        let to_reserves = Self::apply_bps(interest, reserve_factor);
+        pool.total_reserves = pool
+            .total_reserves
+            .checked_add(to_reserves)
+            .expect("overflow");
+    }
+
+    /// The most `pool` may have lent out under its `DataKey::MaxUtilization`.
+    /// Borrowing leaves `total_assets` unchanged, so this is also the limit
+    /// on the pool's total debt after a borrow.
+    fn max_utilized_debt(e: &Env, pool: &Pool) -> i128 {
+        let max_utilization: u32 = e
+            .storage()
+            .persistent()
+            .get(&DataKey::MaxUtilization(pool.token.clone()))
+            .unwrap_or(10000);
+        Self::apply_bps(Self::total_assets(pool), max_utilization)
+    }
+
+    /// Share of `pool`'s assets that is borrowed, out of 10,000.
+    fn utilization_of(pool: &Pool) -> u32 {
+        let assets = Self::total_assets(pool);
+        if assets <= 0 {
+            return 0;
+        }
+        let utilization = Self::total_debt(pool).checked_mul(10000).expect("overflow") / assets;
+        utilization.min(10000) as u32
+    }
+
+    /// Annual borrow rate of `pool`, out of 10,000: from its rate model at
+    /// the current utilization, or its flat rate if it has no model.
+    fn borrow_rate_of(e: &Env, pool: &Pool) -> u32 {
+        let model: Option<RateModel> = e
+            .storage()
+            .persistent()
+            .get(&DataKey::RateModel(pool.token.clone()));
+        let Some(model) = model else {
+            return Self::get_interest_rate(e, pool.token.clone());
+        };
+
+        let utilization = Self::utilization_of(pool) as u64;
+        let optimal = model.optimal_utilization as u64;
+        let rate = if utilization <= optimal {
+            model.base_rate as u64 + model.slope1 as u64 * utilization / optimal
+        } else {
+            let excess = utilization - optimal;
+            model.base_rate as u64
+                + model.slope1 as u64
+                + model.slope2 as u64 * excess / (10000 - optimal)
+        };
+        rate as u32
+    }
+
+    /// Yearly yield, out of 10,000, of an annual `rate` compounded with
+    /// every accrual. Interest accrues often enough to treat this as
+    /// continuous: `e^r - 1`, summed in 18-decimal fixed point until the
+    /// terms of its series round to 0.
+    fn compounded_rate(rate: u32) -> u32 {
+        const SCALE: u128 = 1_000_000_000_000_000_000;
+        let r = rate as u128 * SCALE / 10000;
+        let mut sum = 0;
+        let mut term = r;
+        let mut n = 1;
+        while term > 0 {
+            sum += term;
+            n += 1;
+            term = term.checked_mul(r).expect("overflow") / SCALE / n;
+        }
+        (sum * 10000 / SCALE) as u32
+    }
+
+    /// Annual supply rate of `pool`, out of 10,000, rounded down.
+    fn supply_rate_of(e: &Env, pool: &Pool) -> u32 {
+        let borrow_rate = Self::borrow_rate_of(e, pool) as u64;
+        let utilization = Self::utilization_of(pool) as u64;
+        let kept = 10000 - Self::get_reserve_factor(e, pool.token.clone()) as u64;
+        (borrow_rate * utilization * kept / 100_000_000) as u32
+    }
+
+    /// Underlying tokens owed to suppliers: cash on hand plus outstanding
+    /// debt, less the protocol's reserves.
+    fn total_assets(pool: &Pool) -> i128 {
+        pool.total_cash + Self::total_debt(pool) - pool.total_reserves
+    }
+
+    /// Converts supply shares into underlying tokens, rounding down.
+    fn supply_shares_to_amount(pool: &Pool, shares: i128) -> i128 {
+        if pool.total_supply_shares == 0 {
+            return 0;
+        }
+        shares
+            .checked_mul(Self::total_assets(pool))
+            .expect("overflow")
+            .checked_div(pool.total_supply_shares)
+            .expect("div by zero")
+    }
+
+    /// Cash that can leave the pool without touching its reserves.
+    fn available_cash(pool: &Pool) -> i128 {
+        (pool.total_cash - pool.total_reserves).max(0)
+    }
+
+    /// Total debt owed to `pool`, in tokens.
+    fn total_debt(pool: &Pool) -> i128 {
+        Self::debt_shares_to_amount(pool.total_debt_shares, pool.borrow_index)
+    }
+
+    /// Converts debt shares into the amount owed, rounding up.
+    fn debt_shares_to_amount(shares: i128, borrow_index: i128) -> i128 {
+        Self::mul_div_ceil(shares, borrow_index, INDEX_SCALE)
+    }
+
+    /// Returns the debt shares cleared by repaying `amount` of `current_debt`.
+    /// Partial repayments round down so they never clear more than they pay
+    /// for; repaying the full debt always clears every share.
+    fn repaid_debt_shares(
+        amount: i128,
+        current_debt: i128,
+        shares: i128,
+        borrow_index: i128,
+    ) -> i128 {
+        if amount == current_debt {
+            return shares;
+        }
+        amount
+            .checked_mul(INDEX_SCALE)
+            .expect("overflow")
+            .checked_div(borrow_index)
+            .expect("div by zero")
+    }
+
+    /// Computes `a * b / c` for non-negative values, rounding up.
+    fn mul_div_ceil(a: i128, b: i128, c: i128) -> i128 {
+        let product = a.checked_mul(b).expect("overflow");
+        let quotient = product.checked_div(c).expect("div by zero");
+        if product % c == 0 {
+            quotient
+        } else {
+            quotient + 1
+        }
     }
 }
@@ -0,0 +1,135 @@
+//! Reward emissions for a pool's suppliers and borrowers.
+//!
+//! Each pool can stream a reward token at a fixed rate per second to its
+//! suppliers and, separately, to its borrowers, shared pro-rata by supply
+//! and debt shares. Global indices track the rewards earned per share so
+//! far; a user's rewards are settled against them every time their shares
+//! in the pool change.
+
+use soroban_sdk::{contracttype, Address, Env};
+
+use crate::contract::{DataKey, LendingPool, Pool, UserPosition};
+
+/// Fixed-point scale of reward indices (1e18 == one reward unit per share).
+const REWARD_INDEX_SCALE: i128 = 1_000_000_000_000_000_000;
+
+/// How a pool streams rewards. Rates are reward token units per second.
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[contracttype]
+pub struct EmissionConfig {
+    pub reward_token: Address,
+    pub supply_per_second: i128,
+    pub borrow_per_second: i128,
+}
+
+/// Rewards earned per share since emissions began, scaled by `REWARD_INDEX_SCALE`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[contracttype]
+pub struct RewardIndex {
+    pub supply_index: i128,
+    pub borrow_index: i128,
+    /// Ledger timestamp the indices were last brought up to.
+    pub last_update: u64,
+}
+
+/// A user's rewards in one pool: the indices they were last settled at
+/// and what they've earned but not claimed.
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[contracttype]
+pub struct UserRewards {
+    pub supply_index: i128,
+    pub borrow_index: i128,
+    pub accrued: i128,
+}
+
+impl LendingPool {
+    /// Returns `token`'s emission config and its indices brought up to now,
+    /// without saving them, or `None` if the pool has no emissions.
+    /// `pool` must hold the share totals the rewards were earned on.
+    pub(crate) fn current_reward_index(
+        e: &Env,
+        token: &Address,
+        pool: &Pool,
+    ) -> Option<(EmissionConfig, RewardIndex)> {
+        let storage = e.storage().persistent();
+        let config: EmissionConfig = storage.get(&DataKey::EmissionConfig(token.clone()))?;
+        let mut index: RewardIndex = storage
+            .get(&DataKey::RewardIndex(token.clone()))
+            .expect("reward index not set");
+        let now = e.ledger().timestamp();
+        let elapsed = now.saturating_sub(index.last_update) as i128;
+        let grow = |rate: i128, total_shares: i128| -> i128 {
+            if total_shares == 0 {
+                return 0;
+            }
+            rate.checked_mul(elapsed)
+                .and_then(|r| r.checked_mul(REWARD_INDEX_SCALE))
+                .expect("overflow")
+                / total_shares
+        };
+        // Queued withdrawals belong to no position, so they earn no rewards
+        let reward_shares = pool.total_supply_shares - Self::queued_shares(e, token);
+        index.supply_index += grow(config.supply_per_second, reward_shares);
+        index.borrow_index += grow(config.borrow_per_second, pool.total_debt_shares);
+        index.last_update = now;
+        Some((config, index))
+    }
+
+    /// Brings `token`'s reward indices up to now and saves them. Call
+    /// before the pool's share totals change when no user is settled.
+    pub(crate) fn update_reward_index(e: &Env, token: &Address, pool: &Pool) {
+        if let Some((_, index)) = Self::current_reward_index(e, token, pool) {
+            e.storage()
+                .persistent()
+                .set(&DataKey::RewardIndex(token.clone()), &index);
+        }
+    }
+
+    /// `user`'s rewards in `token`'s pool settled against `index`, given
+    /// the shares in `user_pos` they held since their last settlement.
+    pub(crate) fn settled_rewards(
+        e: &Env,
+        token: &Address,
+        index: &RewardIndex,
+        user: &Address,
+        user_pos: &UserPosition,
+    ) -> UserRewards {
+        let mut rewards: UserRewards = e
+            .storage()
+            .persistent()
+            .get(&DataKey::UserRewards(user.clone(), token.clone()))
+            .unwrap_or(UserRewards {
+                supply_index: 0,
+                borrow_index: 0,
+                accrued: 0,
+            });
+        let earned = |shares: i128, delta: i128| -> i128 {
+            shares.checked_mul(delta).expect("overflow") / REWARD_INDEX_SCALE
+        };
+        let deposit = user_pos.deposit_shares.get(token.clone()).unwrap_or(0);
+        let debt = user_pos.debt_shares.get(token.clone()).unwrap_or(0);
+        rewards.accrued += earned(deposit, index.supply_index - rewards.supply_index);
+        rewards.accrued += earned(debt, index.borrow_index - rewards.borrow_index);
+        rewards.supply_index = index.supply_index;
+        rewards.borrow_index = index.borrow_index;
+        rewards
+    }
+
+    /// Brings `token`'s reward indices up to now and settles `user`'s
+    /// rewards. Call before `user`'s shares or the pool's totals change,
+    /// with `pool` and `user_pos` as they were before the change.
+    pub(crate) fn accrue_rewards(
+        e: &Env,
+        token: &Address,
+        pool: &Pool,
+        user: &Address,
+        user_pos: &UserPosition,
+    ) -> Option<(EmissionConfig, UserRewards)> {
+        let (config, index) = Self::current_reward_index(e, token, pool)?;
+        let rewards = Self::settled_rewards(e, token, &index, user, user_pos);
+        let storage = e.storage().persistent();
+        storage.set(&DataKey::RewardIndex(token.clone()), &index);
+        storage.set(&DataKey::UserRewards(user.clone(), token.clone()), &rewards);
+        Some((config, rewards))
+    }
+}
@@ -0,0 +1,245 @@
+//! Events published by the lending pool.
+
+use soroban_sdk::{contractevent, Address, BytesN, Symbol, Val};
+
+use crate::contract::ReserveConfig;
+use crate::timelock::PendingChange;
+
+/// Published by `set_prices` once the whole batch is written.
+#[contractevent]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct PricesUpdated {
+    pub count: u32,
+}
+
+/// Published when the contract owner changes.
+#[contractevent]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct OwnerChanged {
+    pub previous_owner: Address,
+    pub new_owner: Address,
+}
+
+/// Published when the owner pauses the protocol.
+#[contractevent]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Paused {}
+
+/// Published when the owner unpauses the protocol.
+#[contractevent]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Unpaused {
+    /// When liquidations resume; see `set_grace_period`.
+    pub grace_ends_at: u64,
+}
+
+/// Published by `supply` when a pool reaches 90% of its supply cap.
+#[contractevent]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct SupplyCapNear {
+    #[topic]
+    pub token: Address,
+    pub total_supply: i128,
+    pub cap: i128,
+}
+
+/// Published when the owner takes reserves out of a pool.
+#[contractevent]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ReservesWithdrawn {
+    #[topic]
+    pub token: Address,
+    pub to: Address,
+    pub amount: i128,
+}
+
+/// Published when a flash loan has been repaid.
+#[contractevent]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct FlashLoan {
+    #[topic]
+    pub token: Address,
+    pub receiver: Address,
+    pub amount: i128,
+    pub fee: i128,
+}
+
+/// Published by `init_pool` when a new market is listed.
+#[contractevent]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct PoolInitialized {
+    #[topic]
+    pub token: Address,
+}
+
+/// Published when the owner freezes a pool with `set_frozen`.
+#[contractevent]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Frozen {
+    #[topic]
+    pub token: Address,
+}
+
+/// Published when the owner lifts a pool's freeze.
+#[contractevent]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Unfrozen {
+    #[topic]
+    pub token: Address,
+}
+
+/// Published when the owner retires a pool with `deactivate_pool`.
+#[contractevent]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct PoolDeactivated {
+    #[topic]
+    pub token: Address,
+}
+
+/// Published when the owner schedules a code upgrade.
+#[contractevent]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct UpgradeScheduled {
+    pub wasm_hash: BytesN<32>,
+    pub ready_at: u64,
+}
+
+/// Published by `request_withdraw` when `shares` of `user`'s deposit join
+/// the withdraw queue.
+#[contractevent]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct WithdrawRequested {
+    #[topic]
+    pub token: Address,
+    pub user: Address,
+    pub shares: i128,
+}
+
+/// Published by `claim_withdraw` when `shares` of a queued withdrawal are
+/// paid out as `amount`.
+#[contractevent]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct WithdrawClaimed {
+    #[topic]
+    pub token: Address,
+    pub user: Address,
+    pub amount: i128,
+    pub shares: i128,
+}
+
+/// Published when `clear_bad_debt` writes off debt left without collateral.
+#[contractevent]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct BadDebt {
+    #[topic]
+    pub token: Address,
+    pub borrower: Address,
+    pub amount: i128,
+}
+
+/// Published when a new price moves further from the previous one than
+/// `set_max_price_deviation` allows, freezing the asset.
+#[contractevent]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct PriceCircuitBreaker {
+    #[topic]
+    pub token: Address,
+    pub previous_price: i128,
+    pub price: i128,
+}
+
+/// Published by `supply` and the other ways into a deposit. `from` paid
+/// `amount` and `user` was credited `shares`.
+#[contractevent]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Supply {
+    #[topic]
+    pub token: Address,
+    pub from: Address,
+    pub user: Address,
+    pub amount: i128,
+    pub shares: i128,
+}
+
+/// Published by `repay` and the other ways of paying down debt. `payer`
+/// paid `amount` off `borrower`'s debt.
+#[contractevent]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Repay {
+    #[topic]
+    pub token: Address,
+    pub payer: Address,
+    pub borrower: Address,
+    pub amount: i128,
+}
+
+/// Published by `borrow` and `borrow_on_behalf`. `user` owes `amount`
+/// plus the origination `fee`, and `to` received `amount`.
+#[contractevent]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Borrow {
+    #[topic]
+    pub token: Address,
+    pub user: Address,
+    pub to: Address,
+    pub amount: i128,
+    pub fee: i128,
+}
+
+/// Published by `liquidate`. `seized` is all the collateral taken from
+/// `borrower`; `protocol_fee` of it went to the pool's reserves and the
+/// rest to `liquidator`.
+#[contractevent]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Liquidation {
+    #[topic]
+    pub borrower: Address,
+    pub liquidator: Address,
+    pub debt_token: Address,
+    pub collateral_token: Address,
+    pub repaid: i128,
+    pub seized: i128,
+    pub protocol_fee: i128,
+}
+
+/// Published by `set_reserve_config` with the market's parameters before
+/// and after the update.
+#[contractevent]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ReserveConfigUpdated {
+    #[topic]
+    pub token: Address,
+    pub previous: ReserveConfig,
+    pub config: ReserveConfig,
+}
+
+/// Published by every admin setter with the parameter it changed, the
+/// market it applies to (`None` for protocol-wide settings) and the new
+/// value. Setters taking a key, such as `set_action_paused`, publish a
+/// `(key, value)` pair.
+#[contractevent]
+#[derive(Clone, Debug)]
+pub struct AdminAction {
+    #[topic]
+    pub param: Symbol,
+    #[topic]
+    pub token: Option<Address>,
+    pub value: Val,
+}
+
+/// Published by `queue_param_change`.
+#[contractevent]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ParamChangeQueued {
+    #[topic]
+    pub id: u32,
+    pub change: PendingChange,
+}
+
+/// Published by `cancel_param_change`.
+#[contractevent]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ParamChangeCancelled {
+    #[topic]
+    pub id: u32,
+}
@@ -0,0 +1,11 @@
+//! Interface a contract implements to receive flash loans from the pool.
+
+use soroban_sdk::{contractclient, Address, Env};
+
+#[contractclient(name = "FlashLoanReceiverClient")]
+pub trait FlashLoanReceiver {
+    /// Called by the pool after `amount` of `token` has been sent to the
+    /// receiver. Before returning, the receiver must transfer
+    /// `amount + fee` of `token` back to `pool`.
+    fn exec_op(e: Env, pool: Address, token: Address, amount: i128, fee: i128);
+}
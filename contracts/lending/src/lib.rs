@@ -1,5 +1,11 @@
 #![no_std]
-mod contract;
+pub mod contract;
+pub mod emissions;
+pub mod events;
+pub mod flash_loan;
+pub mod oracle;
+pub mod receipt;
+pub mod timelock;
 
 #[cfg(test)]
 mod test;
@@ -0,0 +1,30 @@
+//! Client interface for a Reflector (SEP-40) price-feed contract.
+//! Only the calls this contract makes are declared.
+
+use soroban_sdk::{contractclient, contracttype, Address, Env, Symbol};
+
+/// An asset quoted by the oracle.
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[contracttype]
+pub enum Asset {
+    Stellar(Address),
+    Other(Symbol),
+}
+
+/// A single price observation.
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[contracttype]
+pub struct PriceData {
+    /// Price with `decimals()` decimal places.
+    pub price: i128,
+    /// Ledger timestamp of the observation, in seconds.
+    pub timestamp: u64,
+}
+
+#[contractclient(name = "ReflectorClient")]
+pub trait Reflector {
+    /// Number of decimal places in every returned price.
+    fn decimals(e: Env) -> u32;
+    /// Most recent price for `asset`, if the oracle quotes it.
+    fn lastprice(e: Env, asset: Asset) -> Option<PriceData>;
+}
@@ -0,0 +1,14 @@
+//! Interface of the supply token registered with `set_supply_token`.
+//! Only the calls this contract makes are declared.
+
+use soroban_sdk::{contractclient, Address, Env};
+
+#[contractclient(name = "SupplyTokenClient")]
+pub trait SupplyToken {
+    /// Called by the pool after `to`'s deposit grew by `amount` shares
+    /// other than by `transfer_deposit`.
+    fn notify_mint(e: Env, to: Address, amount: i128);
+    /// Called by the pool after `from`'s deposit shrank by `amount` shares
+    /// other than by `transfer_deposit`.
+    fn notify_burn(e: Env, from: Address, amount: i128);
+}
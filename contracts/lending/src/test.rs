@@ -1,2 +1,3757 @@
+extern crate std;
+
+use std::rc::Rc;
+
+use crate::contract::{
+    AccountData, Action, DataKey, EmodeCategory, IsolationConfig, LendingPool, LendingPoolClient,
+    Pool, PoolV0, RateModel, ReserveConfig,
+};
+use crate::emissions::EmissionConfig;
+use crate::events::{
+    AdminAction, BadDebt, Borrow, FlashLoan, Frozen, Liquidation, OwnerChanged, ParamChangeQueued,
+    Paused, PoolDeactivated, PoolInitialized, PriceCircuitBreaker, PricesUpdated, Repay,
+    ReserveConfigUpdated, ReservesWithdrawn, Supply, SupplyCapNear, Unfrozen, Unpaused,
+    UpgradeScheduled,
+};
+use crate::oracle::{Asset, PriceData};
+use crate::timelock::{ParamKey, PendingChange};
+use soroban_sdk::{
+    contract, contractimpl, symbol_short,
+    testutils::{Address as _, BytesN as _, Events as _, Ledger},
+    token, vec,
+    xdr::{self, WriteXdr},
+    Address, Bytes, BytesN, Env, Event, IntoVal, Map, MuxedAddress, Symbol, TryFromVal, Vec,
+};
+
+/// $1.00 with the protocol's 7 price decimals.
+const ONE_DOLLAR: i128 = 10_000_000;
+const ONE_YEAR: u64 = 31_536_000;
+const SEED: i128 = 10_000;
+
+/// The smallest Wasm module Soroban accepts: no code, only the
+/// `contractenvmetav0` section declaring protocol 23.
+const EMPTY_WASM: &[u8] = &[
+    0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, // magic, version
+    0x00, 0x1e, 0x11, b'c', b'o', b'n', b't', b'r', b'a', b'c', b't', b'e', b'n', b'v', b'm', b'e',
+    b't', b'a', b'v', b'0', // custom section name
+    0x00, 0x00, 0x00, 0x00, // interface version entry
+    0x00, 0x00, 0x00, 0x17, 0x00, 0x00, 0x00, 0x00, // protocol 23, no pre-release
+];
+
+/// A Reflector-style oracle quoting prices with 14 decimals.
+#[contract]
+struct MockOracle;
+
+#[contractimpl]
+impl MockOracle {
+    pub fn set(e: Env, token: Address, price: i128) {
+        let data = PriceData {
+            price,
+            timestamp: e.ledger().timestamp(),
+        };
+        e.storage().persistent().set(&token, &data);
+    }
+
+    pub fn decimals(_e: Env) -> u32 {
+        14
+    }
+
+    pub fn lastprice(e: Env, asset: Asset) -> Option<PriceData> {
+        match asset {
+            Asset::Stellar(token) => e.storage().persistent().get(&token),
+            Asset::Other(_) => None,
+        }
+    }
+}
+
+/// An oracle whose every call traps, as a broken or upgraded-away feed would.
+#[contract]
+struct BrokenOracle;
+
+#[contractimpl]
+impl BrokenOracle {
+    pub fn decimals(_e: Env) -> u32 {
+        panic!("oracle is broken")
+    }
+
+    pub fn lastprice(_e: Env, _asset: Asset) -> Option<PriceData> {
+        panic!("oracle is broken")
+    }
+}
+
+/// A bare-bones token with configurable decimals and a transfer fee (out of
+/// 10,000, burned from the amount received), for assets that aren't Stellar
+/// Asset Contracts.
+#[contract]
+struct MockToken;
+
+#[contractimpl]
+impl MockToken {
+    pub fn __constructor(e: Env, decimals: u32, fee: i128) {
+        e.storage()
+            .instance()
+            .set(&symbol_short!("decimals"), &decimals);
+        e.storage().instance().set(&symbol_short!("fee"), &fee);
+    }
+
+    /// Makes every later `transfer` panic.
+    pub fn freeze(e: Env) {
+        e.storage().instance().set(&symbol_short!("frozen"), &true);
+    }
+
+    /// Makes every later `transfer` call back into `pool` with a `withdraw`.
+    pub fn set_reenter(e: Env, pool: Address) {
+        e.storage().instance().set(&symbol_short!("reenter"), &pool);
+    }
+
+    pub fn decimals(e: Env) -> u32 {
+        e.storage()
+            .instance()
+            .get(&symbol_short!("decimals"))
+            .unwrap()
+    }
+
+    pub fn mint(e: Env, to: Address, amount: i128) {
+        let balance = Self::balance(e.clone(), to.clone());
+        e.storage().persistent().set(&to, &(balance + amount));
+    }
+
+    pub fn balance(e: Env, id: Address) -> i128 {
+        e.storage().persistent().get(&id).unwrap_or(0)
+    }
+
+    pub fn transfer(e: Env, from: Address, to: MuxedAddress, amount: i128) {
+        from.require_auth();
+        if e.storage().instance().has(&symbol_short!("frozen")) {
+            panic!("token frozen");
+        }
+        let to = to.address();
+        let from_balance = Self::balance(e.clone(), from.clone());
+        if from_balance < amount {
+            panic!("insufficient balance");
+        }
+        e.storage()
+            .persistent()
+            .set(&from, &(from_balance - amount));
+        let fee: i128 = e.storage().instance().get(&symbol_short!("fee")).unwrap();
+        let to_balance = Self::balance(e.clone(), to.clone());
+        let received = amount - amount * fee / 10000;
+        e.storage().persistent().set(&to, &(to_balance + received));
+        let reenter: Option<Address> = e.storage().instance().get(&symbol_short!("reenter"));
+        if let Some(pool) = reenter {
+            let this = e.current_contract_address();
+            LendingPoolClient::new(&e, &pool).withdraw(&to, &this, &1);
+        }
+    }
+}
+
+/// A flash loan receiver that repays `amount + fee` unless told to
+/// `keep` the funds or to `reenter` the pool with a `supply`.
+#[contract]
+struct MockReceiver;
+
+#[contractimpl]
+impl MockReceiver {
+    pub fn set_mode(e: Env, mode: Symbol) {
+        e.storage().instance().set(&symbol_short!("mode"), &mode);
+    }
+
+    pub fn exec_op(e: Env, pool: Address, token: Address, amount: i128, fee: i128) {
+        let mode: Symbol = e
+            .storage()
+            .instance()
+            .get(&symbol_short!("mode"))
+            .unwrap_or(symbol_short!("repay"));
+        let this = e.current_contract_address();
+        if mode == symbol_short!("keep") {
+            return;
+        }
+        if mode == symbol_short!("reenter") {
+            // Would repay the loan by depositing it, if re-entry were allowed.
+            LendingPoolClient::new(&e, &pool).supply(&this, &token, &amount);
+            return;
+        }
+        token::Client::new(&e, &token).transfer(&this, &pool, &(amount + fee));
+    }
+}
+
+/// A supply token that keeps the balances the pool reports to it.
+#[contract]
+struct MockSupplyToken;
+
+#[contractimpl]
+impl MockSupplyToken {
+    pub fn notify_mint(e: Env, to: Address, amount: i128) {
+        let balance = Self::balance(e.clone(), to.clone());
+        e.storage().persistent().set(&to, &(balance + amount));
+    }
+
+    pub fn notify_burn(e: Env, from: Address, amount: i128) {
+        let balance = Self::balance(e.clone(), from.clone());
+        e.storage().persistent().set(&from, &(balance - amount));
+    }
+
+    pub fn balance(e: Env, id: Address) -> i128 {
+        e.storage().persistent().get(&id).unwrap_or(0)
+    }
+}
+
+struct Setup<'a> {
+    e: Env,
+    admin: Address,
+    pool: LendingPoolClient<'a>,
+}
+
+fn setup<'a>() -> Setup<'a> {
+    setup_with_price_decimals(7)
+}
+
+fn setup_with_price_decimals<'a>(price_decimals: u32) -> Setup<'a> {
+    let e = Env::default();
+    e.mock_all_auths();
+
+    let admin = Address::generate(&e);
+    let contract_id = e.register(
+        LendingPool,
+        (
+            &admin,
+            price_decimals,
+            None::<u32>,
+            None::<u32>,
+            None::<u64>,
+        ),
+    );
+    let pool = LendingPoolClient::new(&e, &contract_id);
+
+    Setup { e, admin, pool }
+}
+
+impl Setup<'_> {
+    /// Deploys a Stellar Asset Contract and lists it with the given LTV and price.
+    ///
+    /// Each market is seeded with `SEED` the way a deployment would, so the
+    /// shares locked by the first deposit don't come out of the users under test.
+    fn create_market(&self, ltv: u32, price: i128) -> Address {
+        let token = self.create_empty_market(ltv, price);
+        self.fund_and_supply(&Address::generate(&self.e), &token, SEED);
+        token
+    }
+
+    fn create_empty_market(&self, ltv: u32, price: i128) -> Address {
+        let token = self
+            .e
+            .register_stellar_asset_contract_v2(self.admin.clone())
+            .address();
+        self.pool.init_pool(&token);
+        self.pool.set_ltv(&token, &ltv);
+        self.pool.set_price(&token, &price);
+        token
+    }
+
+    /// Lists and seeds a `MockToken` with `decimals` decimal places and a
+    /// transfer fee of `fee` out of 10,000.
+    fn create_mock_market(&self, decimals: u32, fee: i128, ltv: u32, price: i128) -> Address {
+        let token = self.e.register(MockToken, (decimals, fee));
+        self.pool.init_pool(&token);
+        self.pool.set_ltv(&token, &ltv);
+        self.pool.set_price(&token, &price);
+        self.fund_and_supply(&Address::generate(&self.e), &token, SEED);
+        token
+    }
+
+    /// Asserts that the last invocation published `event` from the pool.
+    fn assert_published(&self, event: &impl Event) {
+        let expected = (
+            self.pool.address.clone(),
+            event.topics(&self.e),
+            event.data(&self.e),
+        );
+        assert!(self.e.events().all().contains(expected));
+    }
+
+    fn mint(&self, token: &Address, to: &Address, amount: i128) {
+        token::StellarAssetClient::new(&self.e, token).mint(to, &amount);
+    }
+
+    fn advance_time(&self, seconds: u64) {
+        self.e.ledger().with_mut(|l| l.timestamp += seconds);
+    }
+
+    fn balance(&self, token: &Address, of: &Address) -> i128 {
+        token::Client::new(&self.e, token).balance(of)
+    }
+
+    /// Funds `user` with `amount` of `token` and supplies it all.
+    fn fund_and_supply(&self, user: &Address, token: &Address, amount: i128) {
+        self.mint(token, user, amount);
+        self.pool.supply(user, token, &amount);
+    }
+
+    /// Deploys the native XLM Stellar Asset Contract.
+    fn native_token(&self) -> Address {
+        let asset = xdr::Asset::Native.to_xdr(xdr::Limits::none()).unwrap();
+        self.e
+            .deployer()
+            .with_stellar_asset(Bytes::from_slice(&self.e, &asset))
+            .deploy()
+    }
+
+    /// Creates a classic account holding `stroops` of XLM. Native XLM
+    /// can't be minted, so this is where test balances come from.
+    fn xlm_account(&self, stroops: i64) -> Address {
+        let id = xdr::AccountId(xdr::PublicKey::PublicKeyTypeEd25519(xdr::Uint256(
+            BytesN::<32>::random(&self.e).to_array(),
+        )));
+        let key = xdr::LedgerKey::Account(xdr::LedgerKeyAccount {
+            account_id: id.clone(),
+        });
+        let entry = xdr::LedgerEntry {
+            data: xdr::LedgerEntryData::Account(xdr::AccountEntry {
+                account_id: id.clone(),
+                balance: stroops,
+                flags: 0,
+                home_domain: Default::default(),
+                inflation_dest: None,
+                num_sub_entries: 0,
+                seq_num: xdr::SequenceNumber(0),
+                thresholds: xdr::Thresholds([1; 4]),
+                signers: Default::default(),
+                ext: xdr::AccountEntryExt::V0,
+            }),
+            last_modified_ledger_seq: 0,
+            ext: xdr::LedgerEntryExt::V0,
+        };
+        self.e
+            .host()
+            .add_ledger_entry(&Rc::new(key), &Rc::new(entry), None)
+            .unwrap();
+        Address::try_from_val(&self.e, &xdr::ScAddress::Account(id)).unwrap()
+    }
+}
+
 #[test]
 fn test() {}
+
+#[test]
+fn test_liquidate_unhealthy_position() {
+    let s = setup();
+    let collateral = s.create_market(8000, ONE_DOLLAR);
+    let debt = s.create_market(8000, ONE_DOLLAR);
+
+    let lender = Address::generate(&s.e);
+    let borrower = Address::generate(&s.e);
+    let liquidator = Address::generate(&s.e);
+    s.fund_and_supply(&lender, &debt, 10_000);
+    s.fund_and_supply(&borrower, &collateral, 1_000);
+    s.pool.borrow(&borrower, &debt, &800);
+
+    // Collateral halves in value: 1000 * 0.5 * 80% = 400 < 800 debt.
+    s.pool.set_price(&collateral, &(ONE_DOLLAR / 2));
+
+    s.mint(&debt, &liquidator, 200);
+    s.pool
+        .liquidate(&liquidator, &borrower, &debt, &collateral, &200);
+
+    // 200 debt at $1 buys 400 collateral at $0.50.
+    assert_eq!(s.balance(&debt, &liquidator), 0);
+    assert_eq!(s.balance(&collateral, &liquidator), 400);
+}
+
+#[test]
+#[should_panic(expected = "position is healthy")]
+fn test_liquidate_healthy_position_panics() {
+    let s = setup();
+    let collateral = s.create_market(8000, ONE_DOLLAR);
+    let debt = s.create_market(8000, ONE_DOLLAR);
+
+    let lender = Address::generate(&s.e);
+    let borrower = Address::generate(&s.e);
+    let liquidator = Address::generate(&s.e);
+    s.fund_and_supply(&lender, &debt, 10_000);
+    s.fund_and_supply(&borrower, &collateral, 1_000);
+    s.pool.borrow(&borrower, &debt, &800);
+
+    s.mint(&debt, &liquidator, 200);
+    s.pool
+        .liquidate(&liquidator, &borrower, &debt, &collateral, &200);
+}
+
+#[test]
+#[should_panic(expected = "no collateral to seize")]
+fn test_liquidate_needs_a_deposit_to_seize() {
+    let s = setup();
+    let collateral = s.create_market(8000, ONE_DOLLAR);
+    let other = s.create_market(8000, ONE_DOLLAR);
+    let debt = s.create_market(8000, ONE_DOLLAR);
+
+    let lender = Address::generate(&s.e);
+    let borrower = Address::generate(&s.e);
+    let liquidator = Address::generate(&s.e);
+    s.fund_and_supply(&lender, &debt, 10_000);
+    s.fund_and_supply(&borrower, &collateral, 1_000);
+    s.pool.borrow(&borrower, &debt, &800);
+    s.pool.set_price(&collateral, &(ONE_DOLLAR / 2));
+
+    // The borrower has nothing deposited in `other` to pay the liquidator with
+    s.mint(&debt, &liquidator, 200);
+    s.pool
+        .liquidate(&liquidator, &borrower, &debt, &other, &200);
+}
+
+#[test]
+fn test_liquidate_pays_liquidation_bonus() {
+    let s = setup();
+    let collateral = s.create_market(8000, ONE_DOLLAR);
+    let debt = s.create_market(8000, ONE_DOLLAR);
+    s.pool.set_liquidation_bonus(&collateral, &1000);
+
+    let lender = Address::generate(&s.e);
+    let borrower = Address::generate(&s.e);
+    let liquidator = Address::generate(&s.e);
+    s.fund_and_supply(&lender, &debt, 10_000);
+    s.fund_and_supply(&borrower, &collateral, 1_000);
+    s.pool.borrow(&borrower, &debt, &800);
+    s.pool.set_price(&collateral, &(ONE_DOLLAR / 2));
+
+    s.mint(&debt, &liquidator, 200);
+    s.pool
+        .liquidate(&liquidator, &borrower, &debt, &collateral, &200);
+
+    // 400 collateral at par plus a 10% bonus.
+    assert_eq!(s.balance(&collateral, &liquidator), 440);
+}
+
+#[test]
+#[should_panic(expected = "liquidation bonus cannot be over 2000")]
+fn test_liquidation_bonus_is_capped() {
+    let s = setup();
+    let token = s.create_market(8000, ONE_DOLLAR);
+    s.pool.set_liquidation_bonus(&token, &2001);
+}
+
+#[test]
+#[should_panic(expected = "repay amount exceeds close factor")]
+fn test_liquidate_rejects_repay_over_close_factor() {
+    let s = setup();
+    let collateral = s.create_market(8000, ONE_DOLLAR);
+    let debt = s.create_market(8000, ONE_DOLLAR);
+
+    let lender = Address::generate(&s.e);
+    let borrower = Address::generate(&s.e);
+    let liquidator = Address::generate(&s.e);
+    s.fund_and_supply(&lender, &debt, 10_000);
+    s.fund_and_supply(&borrower, &collateral, 1_000);
+    s.pool.borrow(&borrower, &debt, &800);
+    s.pool.set_price(&collateral, &(ONE_DOLLAR / 2));
+
+    // Default close factor is 50% of the 800 debt.
+    s.mint(&debt, &liquidator, 401);
+    s.pool
+        .liquidate(&liquidator, &borrower, &debt, &collateral, &401);
+}
+
+#[test]
+fn test_set_close_factor_allows_full_liquidation() {
+    let s = setup();
+    let collateral = s.create_market(8000, ONE_DOLLAR);
+    let debt = s.create_market(8000, ONE_DOLLAR);
+    s.pool.set_close_factor(&10000);
+
+    let lender = Address::generate(&s.e);
+    let borrower = Address::generate(&s.e);
+    let liquidator = Address::generate(&s.e);
+    s.fund_and_supply(&lender, &debt, 10_000);
+    s.fund_and_supply(&borrower, &collateral, 2_000);
+    s.pool.borrow(&borrower, &debt, &800);
+    s.pool.set_price(&collateral, &(ONE_DOLLAR * 2 / 5));
+
+    s.mint(&debt, &liquidator, 800);
+    s.pool
+        .liquidate(&liquidator, &borrower, &debt, &collateral, &800);
+    assert_eq!(s.balance(&collateral, &liquidator), 2_000);
+}
+
+#[test]
+fn test_debt_accrues_interest_over_time() {
+    let s = setup();
+    let collateral = s.create_market(8000, ONE_DOLLAR);
+    let debt = s.create_market(8000, ONE_DOLLAR);
+    s.pool.set_interest_rate(&debt, &1000);
+
+    let lender = Address::generate(&s.e);
+    let borrower = Address::generate(&s.e);
+    s.fund_and_supply(&lender, &debt, 10_000_000);
+    s.fund_and_supply(&borrower, &collateral, 2_000_000);
+    s.pool.borrow(&borrower, &debt, &1_000_000);
+
+    s.advance_time(ONE_YEAR);
+
+    // 10% APR for a year; repaying more than owed only pulls the debt.
+    s.mint(&debt, &borrower, 500_000);
+    s.pool.repay(&borrower, &debt, &1_500_000);
+    assert_eq!(s.balance(&debt, &borrower), 400_000);
+}
+
+#[test]
+fn test_interest_makes_position_liquidatable() {
+    let s = setup();
+    let collateral = s.create_market(8000, ONE_DOLLAR);
+    let debt = s.create_market(8000, ONE_DOLLAR);
+    s.pool.set_interest_rate(&debt, &5000);
+
+    let lender = Address::generate(&s.e);
+    let borrower = Address::generate(&s.e);
+    let liquidator = Address::generate(&s.e);
+    s.fund_and_supply(&lender, &debt, 10_000);
+    s.fund_and_supply(&borrower, &collateral, 1_000);
+    s.pool.borrow(&borrower, &debt, &800);
+
+    // 800 grows to 1200 against 800 of borrowing power.
+    s.advance_time(ONE_YEAR);
+
+    s.mint(&debt, &liquidator, 100);
+    s.pool
+        .liquidate(&liquidator, &borrower, &debt, &collateral, &100);
+    assert_eq!(s.balance(&collateral, &liquidator), 100);
+}
+
+#[test]
+fn test_suppliers_earn_accrued_interest() {
+    let s = setup();
+    let collateral = s.create_market(8000, ONE_DOLLAR);
+    let debt = s.create_empty_market(8000, ONE_DOLLAR);
+    s.pool.set_interest_rate(&debt, &1000);
+
+    let lender = Address::generate(&s.e);
+    let borrower = Address::generate(&s.e);
+    // The first deposit locks 1000 shares, leaving the lender 1_000_000.
+    s.fund_and_supply(&lender, &debt, 1_001_000);
+    s.fund_and_supply(&borrower, &collateral, 2_000_000);
+    s.pool.borrow(&borrower, &debt, &1_000_000);
+
+    s.advance_time(ONE_YEAR);
+    s.mint(&debt, &borrower, 100_000);
+    s.pool.repay(&borrower, &debt, &1_100_000);
+
+    // A later depositor mints at the higher exchange rate and earns nothing extra.
+    let late = Address::generate(&s.e);
+    s.fund_and_supply(&late, &debt, 1_100_000);
+
+    s.pool.withdraw(&lender, &debt, &i128::MAX);
+    s.pool.withdraw(&late, &debt, &i128::MAX);
+    // The locked shares earn their cut of the 100_000 interest too.
+    assert_eq!(s.balance(&debt, &lender), 1_099_900);
+    assert_eq!(s.balance(&debt, &late), 1_100_000);
+}
+
+#[test]
+#[should_panic(expected = "initial deposit too small")]
+fn test_first_deposit_must_exceed_locked_shares() {
+    let s = setup();
+    let token = s.create_empty_market(8000, ONE_DOLLAR);
+    s.fund_and_supply(&Address::generate(&s.e), &token, 1_000);
+}
+
+#[test]
+fn test_donation_cannot_dilute_later_depositor() {
+    let s = setup();
+    let token = s.create_empty_market(8000, ONE_DOLLAR);
+    let attacker = Address::generate(&s.e);
+    let victim = Address::generate(&s.e);
+
+    // The attacker takes the smallest possible position: a single share...
+    s.fund_and_supply(&attacker, &token, 1_001);
+    // ...and donates straight to the contract to inflate the exchange rate.
+    s.mint(&token, &attacker, 1_000_000);
+    token::Client::new(&s.e, &token).transfer(&attacker, &s.pool.address, &1_000_000);
+
+    s.fund_and_supply(&victim, &token, 1_000_000);
+    s.pool.withdraw(&victim, &token, &i128::MAX);
+    s.pool.withdraw(&attacker, &token, &i128::MAX);
+
+    assert_eq!(s.balance(&token, &victim), 1_000_000);
+    assert_eq!(s.balance(&token, &attacker), 1);
+}
+
+#[test]
+fn test_get_pool_info() {
+    let s = setup();
+    let collateral = s.create_market(8000, ONE_DOLLAR);
+    let debt = s.create_market(8000, ONE_DOLLAR);
+
+    let borrower = Address::generate(&s.e);
+    s.fund_and_supply(&borrower, &collateral, 1_000);
+    s.pool.borrow(&borrower, &debt, &800);
+
+    let pool = s.pool.get_pool_info(&debt);
+    assert_eq!(pool.token, debt);
+    assert_eq!(pool.total_supply_shares, SEED);
+    assert_eq!(pool.total_debt_shares, 800);
+    assert_eq!(pool.total_cash, SEED - 800);
+    assert_eq!(pool.total_reserves, 0);
+}
+
+#[test]
+#[should_panic(expected = "pool not initialized")]
+fn test_get_pool_info_uninitialized() {
+    let s = setup();
+    s.pool.get_pool_info(&Address::generate(&s.e));
+}
+
+#[test]
+fn test_get_position() {
+    let s = setup();
+    let collateral = s.create_market(8000, ONE_DOLLAR);
+    let debt = s.create_market(8000, ONE_DOLLAR);
+
+    let user = Address::generate(&s.e);
+    let empty = s.pool.get_position(&user);
+    assert!(empty.deposit_shares.is_empty());
+    assert!(empty.debt_shares.is_empty());
+
+    s.fund_and_supply(&user, &collateral, 1_000);
+    s.pool.borrow(&user, &debt, &500);
+
+    let pos = s.pool.get_position(&user);
+    assert_eq!(pos.deposit_shares.get(collateral), Some(1_000));
+    assert_eq!(pos.debt_shares.get(debt), Some(500));
+}
+
+#[test]
+fn test_user_tokens() {
+    let s = setup();
+    let collateral = s.create_market(8000, ONE_DOLLAR);
+    let other = s.create_market(8000, ONE_DOLLAR);
+    let debt = s.create_market(8000, ONE_DOLLAR);
+
+    let user = Address::generate(&s.e);
+    assert_eq!(s.pool.user_tokens(&user), (vec![&s.e], vec![&s.e]));
+
+    s.fund_and_supply(&user, &collateral, 1_000);
+    s.fund_and_supply(&user, &other, 1_000);
+    s.pool.borrow(&user, &debt, &500);
+
+    let (deposits, debts) = s.pool.user_tokens(&user);
+    assert_eq!(deposits.len(), 2);
+    assert!(deposits.contains(&collateral) && deposits.contains(&other));
+    assert_eq!(debts, vec![&s.e, debt]);
+}
+
+#[test]
+fn test_get_health_factor() {
+    let s = setup();
+    let collateral = s.create_market(8000, ONE_DOLLAR);
+    let debt = s.create_market(8000, ONE_DOLLAR);
+
+    let user = Address::generate(&s.e);
+    assert_eq!(s.pool.get_health_factor(&user), i128::MAX);
+
+    s.fund_and_supply(&user, &collateral, 1_000);
+    s.pool.borrow(&user, &debt, &400);
+    // 800 of borrowing power against 400 of debt.
+    assert_eq!(s.pool.get_health_factor(&user), 20000);
+
+    s.pool.set_price(&collateral, &(ONE_DOLLAR / 4));
+    assert_eq!(s.pool.get_health_factor(&user), 5000);
+}
+
+#[test]
+fn test_ltv_of_and_price_of() {
+    let s = setup();
+    let token = s.create_market(7500, 15_000_000);
+    assert_eq!(s.pool.ltv_of(&token), 7500);
+    assert_eq!(s.pool.price_of(&token), 15_000_000);
+
+    let unknown = Address::generate(&s.e);
+    assert_eq!(s.pool.ltv_of(&unknown), 0);
+    assert_eq!(s.pool.price_of(&unknown), 0);
+}
+
+#[test]
+fn test_oracle_price_is_scaled_to_protocol_decimals() {
+    let s = setup();
+    let quoted = s.create_market(8000, ONE_DOLLAR);
+    let unquoted = s.create_market(8000, ONE_DOLLAR);
+
+    let oracle_id = s.e.register(MockOracle, ());
+    let oracle = MockOracleClient::new(&s.e, &oracle_id);
+    s.pool.set_oracle(&oracle_id);
+    s.pool.set_use_oracle(&quoted, &true);
+    s.pool.set_use_oracle(&unquoted, &true);
+
+    // $2.50 with 14 decimals.
+    oracle.set(&quoted, &250_000_000_000_000);
+    assert_eq!(s.pool.price_of(&quoted), 25_000_000);
+
+    // Assets the oracle doesn't quote fall back to the stored price.
+    assert_eq!(s.pool.price_of(&unquoted), ONE_DOLLAR);
+
+    s.pool.set_use_oracle(&quoted, &false);
+    assert_eq!(s.pool.price_of(&quoted), ONE_DOLLAR);
+}
+
+#[test]
+fn test_median_of_oracles_skips_stale_prices() {
+    let s = setup();
+    let token = s.create_market(8000, ONE_DOLLAR);
+    s.pool.set_max_price_age(&100);
+    let oracles = [
+        s.e.register(MockOracle, ()),
+        s.e.register(MockOracle, ()),
+        s.e.register(MockOracle, ()),
+    ];
+    s.pool.set_oracles(
+        &vec![
+            &s.e,
+            oracles[0].clone(),
+            oracles[1].clone(),
+            oracles[2].clone(),
+        ],
+        &2,
+    );
+    s.pool.set_use_oracle(&token, &true);
+
+    // $10.00 from a feed that then goes quiet, then $2.00 and $3.00
+    MockOracleClient::new(&s.e, &oracles[0]).set(&token, &1_000_000_000_000_000);
+    s.e.ledger().with_mut(|l| l.timestamp += 1_000);
+    MockOracleClient::new(&s.e, &oracles[1]).set(&token, &200_000_000_000_000);
+    MockOracleClient::new(&s.e, &oracles[2]).set(&token, &300_000_000_000_000);
+    assert_eq!(s.pool.price_of(&token), 25_000_000);
+
+    // All three fresh: the median ignores the outlier
+    MockOracleClient::new(&s.e, &oracles[0]).set(&token, &1_000_000_000_000_000);
+    assert_eq!(s.pool.price_of(&token), 30_000_000);
+
+    // Too few fresh feeds to price the asset
+    s.e.ledger().with_mut(|l| l.timestamp += 50);
+    MockOracleClient::new(&s.e, &oracles[1]).set(&token, &200_000_000_000_000);
+    s.e.ledger().with_mut(|l| l.timestamp += 60);
+    assert!(s.pool.try_price_of(&token).is_err());
+    assert!(s
+        .pool
+        .try_set_oracles(&vec![&s.e, oracles[0].clone()], &2)
+        .is_err());
+}
+
+#[test]
+fn test_median_of_oracles_skips_failing_feeds() {
+    let s = setup();
+    let token = s.create_market(8000, ONE_DOLLAR);
+    let feed = s.e.register(MockOracle, ());
+    let other_feed = s.e.register(MockOracle, ());
+    let broken = s.e.register(BrokenOracle, ());
+    let missing = Address::generate(&s.e);
+    s.pool.set_oracles(
+        &vec![
+            &s.e,
+            feed.clone(),
+            broken.clone(),
+            missing.clone(),
+            other_feed.clone(),
+        ],
+        &2,
+    );
+    s.pool.set_use_oracle(&token, &true);
+    MockOracleClient::new(&s.e, &feed).set(&token, &200_000_000_000_000);
+    MockOracleClient::new(&s.e, &other_feed).set(&token, &300_000_000_000_000);
+    assert_eq!(s.pool.price_of(&token), 25_000_000);
+
+    // Failing feeds count against the minimum like stale ones
+    s.pool.set_oracles(&vec![&s.e, feed, broken, missing], &2);
+    assert!(s.pool.try_price_of(&token).is_err());
+}
+
+#[test]
+#[should_panic(expected = "oracle not set")]
+fn test_use_oracle_requires_oracle() {
+    let s = setup();
+    let token = s.create_market(8000, ONE_DOLLAR);
+    s.pool.set_use_oracle(&token, &true);
+}
+
+#[test]
+#[should_panic(expected = "price is stale")]
+fn test_borrow_rejects_stale_price() {
+    let s = setup();
+    let collateral = s.create_market(8000, ONE_DOLLAR);
+    let debt = s.create_market(8000, ONE_DOLLAR);
+    s.pool.set_max_price_age(&3600);
+
+    let borrower = Address::generate(&s.e);
+    s.fund_and_supply(&borrower, &collateral, 1_000);
+
+    s.advance_time(3601);
+    s.pool.borrow(&borrower, &debt, &100);
+}
+
+#[test]
+fn test_refreshed_price_is_not_stale() {
+    let s = setup();
+    let collateral = s.create_market(8000, ONE_DOLLAR);
+    let debt = s.create_market(8000, ONE_DOLLAR);
+    s.pool.set_max_price_age(&3600);
+
+    let borrower = Address::generate(&s.e);
+    s.fund_and_supply(&borrower, &collateral, 1_000);
+
+    s.advance_time(3601);
+    s.pool.set_price(&collateral, &ONE_DOLLAR);
+    s.pool.set_price(&debt, &ONE_DOLLAR);
+    s.pool.borrow(&borrower, &debt, &100);
+    assert_eq!(s.balance(&debt, &borrower), 100);
+}
+
+#[test]
+fn test_set_prices_updates_all_atomically() {
+    let s = setup();
+    let a = s.create_market(8000, ONE_DOLLAR);
+    let b = s.create_market(8000, ONE_DOLLAR);
+
+    let mut prices = Map::new(&s.e);
+    prices.set(a.clone(), 2 * ONE_DOLLAR);
+    prices.set(b.clone(), 3 * ONE_DOLLAR);
+    s.pool.set_prices(&prices);
+
+    s.assert_published(&PricesUpdated { count: 2 });
+    assert_eq!(s.pool.price_of(&a), 2 * ONE_DOLLAR);
+    assert_eq!(s.pool.price_of(&b), 3 * ONE_DOLLAR);
+}
+
+#[test]
+fn test_set_prices_rejects_negative_price() {
+    let s = setup();
+    let a = s.create_market(8000, ONE_DOLLAR);
+    let b = s.create_market(8000, ONE_DOLLAR);
+
+    let mut prices = Map::new(&s.e);
+    prices.set(a.clone(), 2 * ONE_DOLLAR);
+    prices.set(b.clone(), -1);
+    assert!(s.pool.try_set_prices(&prices).is_err());
+    assert_eq!(s.pool.price_of(&a), ONE_DOLLAR);
+}
+
+#[test]
+fn test_two_step_ownership_transfer() {
+    let s = setup();
+    assert_eq!(s.pool.get_admin(), s.admin);
+    let new_owner = Address::generate(&s.e);
+    s.pool.propose_owner(&new_owner);
+    // The proposal alone changes nothing
+    assert_eq!(s.pool.get_admin(), s.admin);
+
+    s.pool.accept_ownership();
+    assert_eq!(s.e.auths()[0].0, new_owner);
+    s.assert_published(&OwnerChanged {
+        previous_owner: s.admin.clone(),
+        new_owner: new_owner.clone(),
+    });
+    assert_eq!(s.pool.get_admin(), new_owner);
+
+    // Admin calls now need the new owner's signature.
+    let token = s.create_market(8000, ONE_DOLLAR);
+    s.pool.set_ltv(&token, &7000);
+    assert_eq!(s.e.auths()[0].0, new_owner);
+}
+
+#[test]
+#[should_panic(expected = "new owner is the current owner")]
+fn test_propose_current_owner_panics() {
+    let s = setup();
+    s.pool.propose_owner(&s.admin);
+}
+
+#[test]
+#[should_panic(expected = "no pending owner")]
+fn test_cancelled_transfer_cannot_be_accepted() {
+    let s = setup();
+    s.pool.propose_owner(&Address::generate(&s.e));
+    s.pool.cancel_ownership_transfer();
+    s.pool.accept_ownership();
+}
+
+#[test]
+fn test_pause_blocks_every_action() {
+    let s = setup();
+    let collateral = s.create_market(8000, ONE_DOLLAR);
+    let debt = s.create_market(8000, ONE_DOLLAR);
+    let user = Address::generate(&s.e);
+    s.fund_and_supply(&user, &collateral, 1_000);
+    s.pool.borrow(&user, &debt, &100);
+
+    s.pool.pause(&s.admin);
+    s.assert_published(&Paused {});
+
+    assert!(s.pool.try_supply(&user, &collateral, &1).is_err());
+    assert!(s.pool.try_withdraw(&user, &collateral, &1).is_err());
+    assert!(s.pool.try_borrow(&user, &debt, &1).is_err());
+    assert!(s.pool.try_repay(&user, &debt, &1).is_err());
+    assert!(s
+        .pool
+        .try_liquidate(&user, &user, &debt, &collateral, &1)
+        .is_err());
+
+    s.pool.unpause();
+    s.assert_published(&Unpaused {
+        grace_ends_at: s.e.ledger().timestamp(),
+    });
+    s.pool.repay(&user, &debt, &1);
+}
+
+#[test]
+fn test_liquidation_grace_period_after_unpause() {
+    let s = setup();
+    let collateral = s.create_market(8000, ONE_DOLLAR);
+    let debt = s.create_market(8000, ONE_DOLLAR);
+    s.pool.set_grace_period(&3_600);
+    let user = Address::generate(&s.e);
+    let liquidator = Address::generate(&s.e);
+    s.fund_and_supply(&user, &collateral, 1_000);
+    s.pool.borrow(&user, &debt, &800);
+    s.mint(&debt, &liquidator, 200);
+
+    s.pool.pause(&s.admin);
+    s.pool.set_price(&collateral, &(ONE_DOLLAR * 9 / 10));
+    s.pool.unpause();
+    s.assert_published(&Unpaused {
+        grace_ends_at: s.e.ledger().timestamp() + 3_600,
+    });
+
+    // Everything but liquidation resumes at once
+    s.pool.repay(&user, &debt, &10);
+    assert!(s
+        .pool
+        .try_liquidate(&liquidator, &user, &debt, &collateral, &100)
+        .is_err());
+    s.advance_time(3_600);
+    assert!(s
+        .pool
+        .try_liquidate(&liquidator, &user, &debt, &collateral, &100)
+        .is_err());
+    s.advance_time(1);
+    s.pool
+        .liquidate(&liquidator, &user, &debt, &collateral, &100);
+}
+
+#[test]
+fn test_exits_while_paused() {
+    let s = setup();
+    let collateral = s.create_market(8000, ONE_DOLLAR);
+    let debt = s.create_market(8000, ONE_DOLLAR);
+    let user = Address::generate(&s.e);
+    s.fund_and_supply(&user, &collateral, 1_000);
+    s.pool.borrow(&user, &debt, &100);
+
+    s.pool.pause(&s.admin);
+    s.pool.set_exits_while_paused(&true);
+
+    s.pool.repay(&user, &debt, &100);
+    s.pool.withdraw(&user, &collateral, &1_000);
+    assert_eq!(s.balance(&collateral, &user), 1_000);
+    assert!(s.pool.try_supply(&user, &collateral, &1_000).is_err());
+    assert!(s.pool.try_borrow(&user, &debt, &1).is_err());
+}
+
+#[test]
+#[should_panic(expected = "protocol paused")]
+fn test_paused_supply_panics() {
+    let s = setup();
+    let token = s.create_market(8000, ONE_DOLLAR);
+    s.pool.pause(&s.admin);
+    s.fund_and_supply(&Address::generate(&s.e), &token, 1_000);
+}
+
+#[test]
+fn test_action_pause_only_blocks_that_action() {
+    let s = setup();
+    let collateral = s.create_market(8000, ONE_DOLLAR);
+    let debt = s.create_market(8000, ONE_DOLLAR);
+    let user = Address::generate(&s.e);
+    s.fund_and_supply(&user, &collateral, 1_000);
+    s.pool.borrow(&user, &debt, &100);
+
+    s.pool.set_action_paused(&s.admin, &Action::Borrow, &true);
+    assert!(s.pool.try_borrow(&user, &debt, &1).is_err());
+    s.pool.repay(&user, &debt, &50);
+    s.pool.withdraw(&user, &collateral, &100);
+    s.fund_and_supply(&user, &collateral, 100);
+
+    s.pool.set_action_paused(&s.admin, &Action::Borrow, &false);
+    s.pool.borrow(&user, &debt, &1);
+}
+
+#[test]
+#[should_panic(expected = "action paused")]
+fn test_paused_action_panics() {
+    let s = setup();
+    let collateral = s.create_market(8000, ONE_DOLLAR);
+    let user = Address::generate(&s.e);
+    s.fund_and_supply(&user, &collateral, 1_000);
+
+    s.pool.set_action_paused(&s.admin, &Action::Withdraw, &true);
+    s.pool.withdraw(&user, &collateral, &1);
+}
+
+#[test]
+#[should_panic(expected = "borrow cap exceeded: 501 > 500")]
+fn test_borrow_cap() {
+    let s = setup();
+    let collateral = s.create_market(8000, ONE_DOLLAR);
+    let debt = s.create_market(8000, ONE_DOLLAR);
+    s.pool.set_borrow_cap(&debt, &500);
+
+    let user = Address::generate(&s.e);
+    s.fund_and_supply(&user, &collateral, 1_000);
+    s.pool.borrow(&user, &debt, &500);
+    s.pool.borrow(&user, &debt, &1);
+}
+
+#[test]
+fn test_supply_cap() {
+    let s = setup();
+    let token = s.create_market(8000, ONE_DOLLAR);
+    s.pool.set_supply_cap(&token, &(SEED + 1_000));
+
+    let user = Address::generate(&s.e);
+    s.fund_and_supply(&user, &token, 1_000);
+    s.assert_published(&SupplyCapNear {
+        token: token.clone(),
+        total_supply: SEED + 1_000,
+        cap: SEED + 1_000,
+    });
+
+    s.mint(&token, &user, 1);
+    assert!(s.pool.try_supply(&user, &token, &1).is_err());
+}
+
+#[test]
+fn test_reserve_factor_takes_share_of_interest() {
+    let s = setup();
+    let collateral = s.create_market(8000, ONE_DOLLAR);
+    let debt = s.create_market(8000, ONE_DOLLAR);
+    s.pool.set_interest_rate(&debt, &1000);
+    s.pool.set_reserve_factor(&debt, &2000);
+
+    let lender = Address::generate(&s.e);
+    let borrower = Address::generate(&s.e);
+    s.fund_and_supply(&lender, &debt, 1_000_000);
+    s.fund_and_supply(&borrower, &collateral, 2_000_000);
+    s.pool.borrow(&borrower, &debt, &1_000_000);
+
+    s.advance_time(ONE_YEAR);
+    s.mint(&debt, &borrower, 100_000);
+    s.pool.repay(&borrower, &debt, &1_100_000);
+
+    // 20% of the 100_000 interest is kept by the protocol.
+    let pool = s.pool.get_pool_info(&debt);
+    assert_eq!(pool.total_reserves, 20_000);
+    assert_eq!(pool.total_cash, SEED + 1_100_000);
+
+    // Suppliers share the remaining 80_000.
+    s.pool.withdraw(&lender, &debt, &i128::MAX);
+    assert_eq!(s.balance(&debt, &lender), 1_079_207);
+}
+
+#[test]
+fn test_withdraw_reserves() {
+    let s = setup();
+    let collateral = s.create_market(8000, ONE_DOLLAR);
+    let debt = s.create_market(8000, ONE_DOLLAR);
+    s.pool.set_interest_rate(&debt, &1000);
+    s.pool.set_reserve_factor(&debt, &2000);
+
+    let borrower = Address::generate(&s.e);
+    let treasury = Address::generate(&s.e);
+    s.fund_and_supply(&Address::generate(&s.e), &debt, 1_000_000);
+    s.fund_and_supply(&borrower, &collateral, 2_000_000);
+    s.pool.borrow(&borrower, &debt, &1_000_000);
+    s.advance_time(ONE_YEAR);
+    s.mint(&debt, &borrower, 100_000);
+    s.pool.repay(&borrower, &debt, &1_100_000);
+
+    assert!(s
+        .pool
+        .try_withdraw_reserves(&debt, &treasury, &20_001)
+        .is_err());
+
+    s.pool.withdraw_reserves(&debt, &treasury, &20_000);
+    s.assert_published(&ReservesWithdrawn {
+        token: debt.clone(),
+        to: treasury.clone(),
+        amount: 20_000,
+    });
+    assert_eq!(s.balance(&debt, &treasury), 20_000);
+    assert_eq!(s.pool.get_pool_info(&debt).total_reserves, 0);
+}
+
+#[test]
+fn test_flash_loan_fee_goes_to_reserves() {
+    let s = setup();
+    let token = s.create_market(8000, ONE_DOLLAR);
+    s.fund_and_supply(&Address::generate(&s.e), &token, 1_000_000);
+    s.pool.set_flash_loan_fee(&9);
+
+    let receiver = s.e.register(MockReceiver, ());
+    s.mint(&token, &receiver, 900);
+    s.pool.flash_loan(&receiver, &token, &1_000_000);
+
+    s.assert_published(&FlashLoan {
+        token: token.clone(),
+        receiver: receiver.clone(),
+        amount: 1_000_000,
+        fee: 900,
+    });
+    assert_eq!(s.balance(&token, &receiver), 0);
+    let pool = s.pool.get_pool_info(&token);
+    assert_eq!(pool.total_reserves, 900);
+    assert_eq!(pool.total_cash, SEED + 1_000_000 + 900);
+}
+
+#[test]
+#[should_panic(expected = "flash loan not repaid")]
+fn test_flash_loan_must_be_repaid() {
+    let s = setup();
+    let token = s.create_market(8000, ONE_DOLLAR);
+
+    let receiver = s.e.register(MockReceiver, ());
+    MockReceiverClient::new(&s.e, &receiver).set_mode(&symbol_short!("keep"));
+    s.pool.flash_loan(&receiver, &token, &SEED);
+}
+
+#[test]
+fn test_flash_loan_blocks_reentry() {
+    let s = setup();
+    let token = s.create_market(8000, ONE_DOLLAR);
+
+    let receiver = s.e.register(MockReceiver, ());
+    MockReceiverClient::new(&s.e, &receiver).set_mode(&symbol_short!("reenter"));
+    assert!(s.pool.try_flash_loan(&receiver, &token, &SEED).is_err());
+    assert_eq!(s.pool.get_pool_info(&token).total_cash, SEED);
+}
+
+#[test]
+fn test_token_cannot_reenter_during_transfer() {
+    let s = setup();
+    let token = s.create_mock_market(7, 0, 8000, ONE_DOLLAR);
+    let user = Address::generate(&s.e);
+    MockTokenClient::new(&s.e, &token).mint(&user, &1_000);
+
+    MockTokenClient::new(&s.e, &token).set_reenter(&s.pool.address);
+    assert!(s.pool.try_supply(&user, &token, &1_000).is_err());
+    assert_eq!(s.pool.get_pool_info(&token).total_cash, SEED);
+    assert_eq!(MockTokenClient::new(&s.e, &token).balance(&user), 1_000);
+}
+
+#[test]
+#[should_panic(expected = "reentrancy")]
+fn test_locked_pool_rejects_calls() {
+    let s = setup();
+    let token = s.create_market(8000, ONE_DOLLAR);
+    let user = Address::generate(&s.e);
+    s.mint(&token, &user, 1_000);
+
+    s.e.as_contract(&s.pool.address, || {
+        s.e.storage().temporary().set(&DataKey::Locked, &true);
+    });
+    s.pool.supply(&user, &token, &1_000);
+}
+
+#[test]
+fn test_failed_transfer_leaves_no_partial_state() {
+    let s = setup();
+    let collateral = s.create_market(8000, ONE_DOLLAR);
+    let token = s.create_mock_market(7, 0, 8000, ONE_DOLLAR);
+    let user = Address::generate(&s.e);
+    s.fund_and_supply(&user, &collateral, 1_000);
+    MockTokenClient::new(&s.e, &token).mint(&user, &500);
+    s.pool.supply(&user, &token, &500);
+    s.pool.set_use_as_collateral(&user, &token, &false);
+
+    MockTokenClient::new(&s.e, &token).freeze();
+    let position = s.pool.get_position(&user);
+    let pool = s.pool.get_pool_info(&token);
+    assert!(s.pool.try_borrow(&user, &token, &100).is_err());
+    assert!(s.pool.try_withdraw(&user, &token, &100).is_err());
+
+    assert_eq!(
+        s.pool.get_position(&user).deposit_shares,
+        position.deposit_shares
+    );
+    assert_eq!(s.pool.get_position(&user).debt_shares, position.debt_shares);
+    assert_eq!(s.pool.get_pool_info(&token).total_cash, pool.total_cash);
+    assert_eq!(
+        s.pool.get_pool_info(&token).total_debt_shares,
+        pool.total_debt_shares
+    );
+}
+
+#[test]
+fn test_deposit_and_debt_of() {
+    let s = setup();
+    let collateral = s.create_market(8000, ONE_DOLLAR);
+    let debt = s.create_market(8000, ONE_DOLLAR);
+    s.pool.set_interest_rate(&debt, &1000);
+    let user = Address::generate(&s.e);
+    s.fund_and_supply(&user, &collateral, 1_000);
+    s.pool.borrow(&user, &debt, &500);
+
+    assert_eq!(s.pool.deposit_of(&user, &collateral), 1_000);
+    assert_eq!(s.pool.debt_of(&user, &debt), 500);
+    assert_eq!(s.pool.deposit_of(&user, &debt), 0);
+    assert_eq!(s.pool.debt_of(&user, &collateral), 0);
+
+    // Interest accrued since the last write is included
+    s.advance_time(31_536_000);
+    assert_eq!(s.pool.debt_of(&user, &debt), 550);
+}
+
+#[test]
+fn test_total_value_locked_and_borrowed() {
+    let s = setup();
+    let collateral = s.create_market(8000, 2 * ONE_DOLLAR);
+    let debt = s.create_mock_market(6, 0, 8000, ONE_DOLLAR);
+    let user = Address::generate(&s.e);
+    s.fund_and_supply(&user, &collateral, 1_000);
+    s.pool.borrow(&user, &debt, &100);
+
+    // Every value is scaled to 7 decimals; the 6-decimal pool counts 10x.
+    assert_eq!(
+        s.pool.total_value_locked(),
+        (SEED + 1_000) * 2 * ONE_DOLLAR + SEED * 10 * ONE_DOLLAR
+    );
+    assert_eq!(s.pool.total_borrowed_value(), 100 * 10 * ONE_DOLLAR);
+}
+
+#[test]
+fn test_simulate_health_with_price_overrides() {
+    let s = setup();
+    let collateral = s.create_market(8000, ONE_DOLLAR);
+    let debt = s.create_market(8000, ONE_DOLLAR);
+    let user = Address::generate(&s.e);
+    s.fund_and_supply(&user, &collateral, 1_000);
+    s.pool.borrow(&user, &debt, &500);
+
+    let none = Map::new(&s.e);
+    assert_eq!(s.pool.simulate_health(&user, &none), 16000);
+
+    // A 20% collateral drop, then the debt asset doubling as well
+    let drop = Map::from_array(&s.e, [(collateral.clone(), ONE_DOLLAR * 8 / 10)]);
+    assert_eq!(s.pool.simulate_health(&user, &drop), 12800);
+    let both = Map::from_array(
+        &s.e,
+        [
+            (collateral.clone(), ONE_DOLLAR * 8 / 10),
+            (debt.clone(), 2 * ONE_DOLLAR),
+        ],
+    );
+    assert_eq!(s.pool.simulate_health(&user, &both), 6400);
+
+    // Stored prices are untouched
+    assert_eq!(s.pool.price_of(&collateral), ONE_DOLLAR);
+    assert_eq!(s.pool.get_health_factor(&user), 16000);
+}
+
+#[test]
+fn test_supply_for_credits_beneficiary() {
+    let s = setup();
+    let token = s.create_market(8000, ONE_DOLLAR);
+    let vault = Address::generate(&s.e);
+    let beneficiary = Address::generate(&s.e);
+
+    s.mint(&token, &vault, 1_000);
+    s.pool.supply_for(&vault, &beneficiary, &token, &1_000);
+    assert_eq!(s.e.auths().len(), 1);
+    assert_eq!(s.e.auths()[0].0, vault);
+
+    assert!(s.pool.get_position(&vault).deposit_shares.is_empty());
+    s.pool.withdraw(&beneficiary, &token, &1_000);
+    assert_eq!(s.balance(&token, &beneficiary), 1_000);
+}
+
+#[test]
+fn test_repay_for_reduces_borrower_debt() {
+    let s = setup();
+    let collateral = s.create_market(8000, ONE_DOLLAR);
+    let debt = s.create_market(8000, ONE_DOLLAR);
+    let borrower = Address::generate(&s.e);
+    let guardian = Address::generate(&s.e);
+    s.fund_and_supply(&borrower, &collateral, 1_000);
+    s.pool.borrow(&borrower, &debt, &500);
+
+    s.mint(&debt, &guardian, 500);
+    s.pool.repay_for(&guardian, &borrower, &debt, &300);
+    assert_eq!(s.e.auths()[0].0, guardian);
+    assert_eq!(s.balance(&debt, &guardian), 200);
+    assert_eq!(s.balance(&debt, &borrower), 500);
+    assert_eq!(
+        s.pool.get_position(&borrower).debt_shares.get(debt),
+        Some(200)
+    );
+}
+
+#[test]
+#[should_panic(expected = "no debt to repay")]
+fn test_repay_for_without_debt_panics() {
+    let s = setup();
+    let debt = s.create_market(8000, ONE_DOLLAR);
+    let guardian = Address::generate(&s.e);
+    s.mint(&debt, &guardian, 100);
+    s.pool
+        .repay_for(&guardian, &Address::generate(&s.e), &debt, &100);
+}
+
+#[test]
+fn test_withdraw_to_pays_recipient() {
+    let s = setup();
+    let token = s.create_market(8000, ONE_DOLLAR);
+    let user = Address::generate(&s.e);
+    let payout = Address::generate(&s.e);
+    s.fund_and_supply(&user, &token, 1_000);
+
+    s.pool.withdraw_to(&user, &token, &400, &payout);
+    assert_eq!(s.e.auths()[0].0, user);
+    assert_eq!(s.balance(&token, &payout), 400);
+    assert_eq!(s.balance(&token, &user), 0);
+    assert_eq!(
+        s.pool.get_position(&user).deposit_shares.get(token),
+        Some(600)
+    );
+}
+
+#[test]
+fn test_withdraw_max_stops_at_health_limit() {
+    let s = setup();
+    let collateral = s.create_market(8000, ONE_DOLLAR);
+    let debt = s.create_market(8000, ONE_DOLLAR);
+    s.pool.set_interest_rate(&debt, &1000);
+    let user = Address::generate(&s.e);
+    s.fund_and_supply(&user, &collateral, 1_000_000);
+    s.fund_and_supply(&Address::generate(&s.e), &debt, 1_000_000);
+    s.pool.borrow(&user, &debt, &400_000);
+    s.advance_time(ONE_YEAR / 2);
+
+    s.pool.withdraw(&user, &collateral, &i128::MAX);
+    let withdrawn = s.balance(&collateral, &user);
+    assert!(withdrawn > 0 && withdrawn < 1_000_000 - 500_000);
+    assert!(s.pool.get_health_factor(&user) >= 10000);
+    assert!(s.pool.try_withdraw(&user, &collateral, &10).is_err());
+}
+
+#[test]
+fn test_withdraw_max_is_bounded_by_liquidity() {
+    let s = setup();
+    let token = s.create_market(8000, ONE_DOLLAR);
+    let other = s.create_market(8000, ONE_DOLLAR);
+    let lender = Address::generate(&s.e);
+    let borrower = Address::generate(&s.e);
+    s.fund_and_supply(&lender, &token, 1_000);
+    s.fund_and_supply(&borrower, &other, 20_000);
+    s.pool.borrow(&borrower, &token, &(SEED + 600));
+
+    assert_eq!(s.pool.withdraw(&lender, &token, &i128::MAX), 400);
+    assert_eq!(s.balance(&token, &lender), 400);
+}
+
+#[test]
+fn test_withdraw_max_without_debt_takes_whole_deposit() {
+    let s = setup();
+    let token = s.create_market(8000, ONE_DOLLAR);
+    let user = Address::generate(&s.e);
+    s.fund_and_supply(&user, &token, 1_000);
+
+    assert_eq!(s.pool.withdraw(&user, &token, &i128::MAX), 1_000);
+    assert!(s.pool.get_position(&user).deposit_shares.is_empty());
+
+    // Moving a whole deposit to another account drops the entry too
+    s.fund_and_supply(&user, &token, 1_000);
+    s.pool
+        .set_supply_token(&token, &s.e.register(MockSupplyToken, ()));
+    let shares = s.pool.deposit_shares_of(&user, &token);
+    s.pool
+        .transfer_deposit(&token, &user, &Address::generate(&s.e), &shares);
+    assert!(s.pool.get_position(&user).deposit_shares.is_empty());
+}
+
+#[test]
+fn test_repay_max_settles_accrued_debt_exactly() {
+    let s = setup();
+    let collateral = s.create_market(8000, ONE_DOLLAR);
+    let debt = s.create_market(8000, ONE_DOLLAR);
+    s.pool.set_interest_rate(&debt, &1000);
+    let borrower = Address::generate(&s.e);
+    s.fund_and_supply(&borrower, &collateral, 2_000_000);
+    s.fund_and_supply(&Address::generate(&s.e), &debt, 1_000_000);
+    s.pool.borrow(&borrower, &debt, &1_000_000);
+    s.advance_time(ONE_YEAR);
+    s.mint(&debt, &borrower, 200_000);
+
+    let repaid = s.pool.repay(&borrower, &debt, &i128::MAX);
+    assert!(repaid > 1_000_000);
+    assert_eq!(s.balance(&debt, &borrower), 1_200_000 - repaid);
+    // The repaid token no longer shows up in the position at all
+    assert_eq!(s.pool.get_position(&borrower).debt_shares.get(debt), None);
+    assert_eq!(s.pool.user_tokens(&borrower).1, vec![&s.e]);
+}
+
+#[test]
+fn test_unhealthy_withdraw_reverts_without_state_change() {
+    let s = setup();
+    let low_ltv = s.create_market(5000, ONE_DOLLAR);
+    let high_ltv = s.create_market(9000, ONE_DOLLAR);
+    let debt = s.create_market(8000, ONE_DOLLAR);
+    let user = Address::generate(&s.e);
+    s.fund_and_supply(&user, &low_ltv, 1_000);
+    s.fund_and_supply(&user, &high_ltv, 1_000);
+    // Borrowing power is 500 + 900 = 1_400.
+    s.pool.borrow(&user, &debt, &1_200);
+
+    // 300 of the 90% asset removes 270 of power, dropping it to 1_130.
+    let deposits = s.pool.get_position(&user).deposit_shares;
+    let pool = s.pool.get_pool_info(&high_ltv);
+    assert!(s.pool.try_withdraw(&user, &high_ltv, &300).is_err());
+    assert_eq!(s.pool.get_position(&user).deposit_shares, deposits);
+    let after = s.pool.get_pool_info(&high_ltv);
+    assert_eq!(after.total_supply_shares, pool.total_supply_shares);
+    assert_eq!(after.total_cash, pool.total_cash);
+    assert_eq!(s.balance(&high_ltv, &user), 0);
+
+    // The same amount of the 50% asset only removes 150.
+    s.pool.withdraw(&user, &low_ltv, &300);
+    assert_eq!(s.balance(&low_ltv, &user), 300);
+}
+
+#[test]
+#[should_panic(expected = "overflow")]
+fn test_supply_near_i128_max_overflows_cleanly() {
+    let s = setup();
+    let token = s.create_empty_market(8000, ONE_DOLLAR);
+    s.fund_and_supply(&Address::generate(&s.e), &token, i128::MAX - 10);
+    assert_eq!(s.pool.get_pool_info(&token).total_cash, i128::MAX - 10);
+
+    s.fund_and_supply(&Address::generate(&s.e), &token, 100);
+}
+
+#[test]
+fn test_health_normalizes_token_decimals() {
+    let s = setup();
+    let usdc = s.create_mock_market(6, 0, 8000, ONE_DOLLAR);
+    let xlm = s.create_market(8000, ONE_DOLLAR);
+    let user = Address::generate(&s.e);
+
+    // $100 of a 6-decimal asset backs $80 of a 7-decimal one.
+    s.fund_and_supply(&user, &usdc, 100_000_000);
+    s.fund_and_supply(&Address::generate(&s.e), &xlm, 1_000_000_000);
+    s.pool.borrow(&user, &xlm, &800_000_000);
+    assert_eq!(s.pool.get_health_factor(&user), 10000);
+    assert!(s.pool.try_borrow(&user, &xlm, &1).is_err());
+
+    // Withdrawing the maximum stays within the 6-decimal collateral.
+    s.pool.repay(&user, &xlm, &400_000_000);
+    s.pool.withdraw(&user, &usdc, &i128::MAX);
+    let withdrawn = s.balance(&usdc, &user);
+    assert!(withdrawn > 50_000_000 - 5 && withdrawn < 50_000_000);
+    assert!(s.pool.get_health_factor(&user) >= 10000);
+}
+
+#[test]
+fn test_fee_on_transfer_supply_credits_received_amount() {
+    let s = setup();
+    // A 1% transfer fee; the seed deposit left 9_900 in the pool.
+    let token = s.create_mock_market(7, 100, 8000, ONE_DOLLAR);
+    let user = Address::generate(&s.e);
+    s.fund_and_supply(&user, &token, 10_000);
+
+    let pool = s.pool.get_pool_info(&token);
+    assert_eq!(pool.total_cash, 9_900 + 9_900);
+    assert_eq!(pool.total_cash, s.balance(&token, &s.pool.address));
+    assert_eq!(
+        s.pool.get_position(&user).deposit_shares.get(token),
+        Some(9_900)
+    );
+}
+
+#[test]
+fn test_fee_on_transfer_repay_credits_received_amount() {
+    let s = setup();
+    let collateral = s.create_market(8000, ONE_DOLLAR);
+    let debt = s.create_mock_market(7, 100, 8000, ONE_DOLLAR);
+    let borrower = Address::generate(&s.e);
+    s.fund_and_supply(&borrower, &collateral, 10_000);
+    s.pool.borrow(&borrower, &debt, &5_000);
+
+    s.mint(&debt, &borrower, 5_000);
+    assert_eq!(s.pool.repay(&borrower, &debt, &5_000), 4_950);
+    let pool = s.pool.get_pool_info(&debt);
+    assert_eq!(pool.total_debt_shares, 50);
+    assert_eq!(pool.total_cash, s.balance(&debt, &s.pool.address));
+}
+
+#[test]
+fn test_available_liquidity_excludes_borrows_and_reserves() {
+    let s = setup();
+    let collateral = s.create_market(8000, ONE_DOLLAR);
+    let debt = s.create_market(8000, ONE_DOLLAR);
+    s.pool.set_interest_rate(&debt, &1000);
+    s.pool.set_reserve_factor(&debt, &5000);
+    let borrower = Address::generate(&s.e);
+    s.fund_and_supply(&borrower, &collateral, 10_000);
+    assert_eq!(s.pool.available_liquidity(&debt), SEED);
+
+    s.pool.borrow(&borrower, &debt, &5_000);
+    assert_eq!(s.pool.available_liquidity(&debt), SEED - 5_000);
+
+    // Half of a year's 500 interest is reserved out of the remaining cash.
+    s.advance_time(ONE_YEAR);
+    assert_eq!(s.pool.available_liquidity(&debt), SEED - 5_000 - 250);
+}
+
+#[test]
+fn test_utilization_and_rates() {
+    let s = setup();
+    let collateral = s.create_market(8000, ONE_DOLLAR);
+    let debt = s.create_market(8000, ONE_DOLLAR);
+    s.pool.set_interest_rate(&debt, &1000);
+    s.pool.set_reserve_factor(&debt, &2000);
+    assert_eq!(s.pool.utilization(&debt), 0);
+    assert_eq!(s.pool.supply_rate(&debt), 0);
+
+    let borrower = Address::generate(&s.e);
+    s.fund_and_supply(&borrower, &collateral, 10_000);
+    s.pool.borrow(&borrower, &debt, &(SEED / 2));
+
+    assert_eq!(s.pool.utilization(&debt), 5000);
+    assert_eq!(s.pool.borrow_rate(&debt), 1000);
+    // 10% * 50% utilization * 80% kept by suppliers.
+    assert_eq!(s.pool.supply_rate(&debt), 400);
+}
+
+#[test]
+fn test_kinked_rate_model() {
+    let s = setup();
+    let collateral = s.create_market(8000, ONE_DOLLAR);
+    let debt = s.create_market(8000, ONE_DOLLAR);
+    s.pool.set_rate_model(
+        &debt,
+        &RateModel {
+            base_rate: 200,
+            slope1: 400,
+            slope2: 6000,
+            optimal_utilization: 8000,
+        },
+    );
+    let borrower = Address::generate(&s.e);
+    s.fund_and_supply(&borrower, &collateral, 100_000);
+
+    assert_eq!(s.pool.borrow_rate(&debt), 200);
+
+    s.pool.borrow(&borrower, &debt, &(SEED * 8 / 10));
+    assert_eq!(s.pool.utilization(&debt), 8000);
+    assert_eq!(s.pool.borrow_rate(&debt), 600);
+
+    s.pool.borrow(&borrower, &debt, &(SEED * 2 / 10));
+    assert_eq!(s.pool.utilization(&debt), 10000);
+    assert_eq!(s.pool.borrow_rate(&debt), 6600);
+
+    // Accrual uses the model: a year at 66% on the full 10_000 borrowed.
+    s.advance_time(ONE_YEAR);
+    s.mint(&debt, &borrower, 6_600);
+    assert_eq!(s.pool.repay(&borrower, &debt, &i128::MAX), 16_600);
+}
+
+#[test]
+fn test_accrue_saves_interest_once_per_ledger() {
+    let s = setup();
+    let collateral = s.create_market(8000, ONE_DOLLAR);
+    let debt = s.create_market(8000, ONE_DOLLAR);
+    s.pool.set_interest_rate(&debt, &1000);
+    let borrower = Address::generate(&s.e);
+    s.fund_and_supply(&borrower, &collateral, 10_000);
+    s.pool.borrow(&borrower, &debt, &5_000);
+    s.advance_time(ONE_YEAR);
+
+    s.pool.accrue(&debt);
+    let pool = s.pool.get_pool_info(&debt);
+    assert_eq!(pool.borrow_index, 1_100_000_000);
+    assert_eq!(pool.last_accrual, s.e.ledger().timestamp());
+
+    s.pool.accrue(&debt);
+    assert_eq!(s.pool.get_pool_info(&debt).borrow_index, 1_100_000_000);
+}
+
+#[test]
+fn test_deactivated_pool_only_allows_exits() {
+    let s = setup();
+    let collateral = s.create_market(8000, ONE_DOLLAR);
+    let debt = s.create_market(8000, ONE_DOLLAR);
+    let user = Address::generate(&s.e);
+    s.fund_and_supply(&user, &collateral, 10_000);
+    s.pool.borrow(&user, &debt, &1_000);
+
+    s.pool.deactivate_pool(&debt);
+    s.assert_published(&PoolDeactivated {
+        token: debt.clone(),
+    });
+    s.mint(&debt, &user, 1_000);
+    assert!(s.pool.try_supply(&user, &debt, &100).is_err());
+    assert!(s.pool.try_borrow(&user, &debt, &100).is_err());
+
+    s.pool.repay(&user, &debt, &i128::MAX);
+    s.pool.deactivate_pool(&collateral);
+    s.pool.withdraw(&user, &collateral, &i128::MAX);
+    assert_eq!(s.balance(&collateral, &user), 10_000);
+}
+
+#[test]
+#[should_panic(expected = "pool is not active")]
+fn test_supply_to_deactivated_pool_panics() {
+    let s = setup();
+    let token = s.create_market(8000, ONE_DOLLAR);
+    s.pool.deactivate_pool(&token);
+    s.fund_and_supply(&Address::generate(&s.e), &token, 1_000);
+}
+
+#[test]
+fn test_upgrade_after_timelock_keeps_storage() {
+    let s = setup();
+    let token = s.create_market(8000, ONE_DOLLAR);
+    let hash =
+        s.e.deployer()
+            .upload_contract_wasm(Bytes::from_slice(&s.e, EMPTY_WASM));
+
+    s.pool.schedule_upgrade(&hash);
+    s.assert_published(&UpgradeScheduled {
+        wasm_hash: hash.clone(),
+        ready_at: s.e.ledger().timestamp() + 172_800,
+    });
+    assert!(s.pool.try_upgrade(&hash).is_err());
+
+    s.advance_time(172_800);
+    s.pool.upgrade(&hash);
+
+    let pool: Pool = s.e.as_contract(&s.pool.address, || {
+        s.e.storage()
+            .persistent()
+            .get(&DataKey::Pool(token.clone()))
+            .unwrap()
+    });
+    assert_eq!(pool.total_supply_shares, SEED);
+}
+
+#[test]
+#[should_panic(expected = "upgrade does not match the scheduled hash")]
+fn test_upgrade_requires_scheduled_hash() {
+    let s = setup();
+    let hash =
+        s.e.deployer()
+            .upload_contract_wasm(Bytes::from_slice(&s.e, EMPTY_WASM));
+    s.pool.schedule_upgrade(&BytesN::from_array(&s.e, &[0; 32]));
+    s.advance_time(172_800);
+    s.pool.upgrade(&hash);
+}
+
+#[test]
+fn test_cancelled_upgrade_cannot_be_applied() {
+    let s = setup();
+    let hash = BytesN::from_array(&s.e, &[1; 32]);
+    s.pool.schedule_upgrade(&hash);
+    s.pool.cancel_upgrade();
+    s.advance_time(172_800);
+    assert!(s.pool.try_upgrade(&hash).is_err());
+}
+
+#[test]
+fn test_migrate_rewrites_version_0_pools() {
+    let s = setup();
+    let token = s.create_mock_market(6, 0, 8000, ONE_DOLLAR);
+    s.e.as_contract(&s.pool.address, || {
+        let storage = s.e.storage().persistent();
+        storage.remove(&DataKey::Version);
+        storage.remove(&DataKey::PoolList);
+        storage.remove(&DataKey::Decimals(token.clone()));
+        storage.set(
+            &DataKey::Pool(token.clone()),
+            &PoolV0 {
+                token: token.clone(),
+                total_supply_shares: SEED,
+                total_debt_shares: 4_000,
+                total_reserves: 0,
+            },
+        );
+    });
+
+    s.pool.migrate(&vec![&s.e, token.clone()]);
+    let pool = s.pool.get_pool_info(&token);
+    assert_eq!(pool.total_supply_shares, SEED);
+    assert_eq!(pool.total_debt_shares, 4_000);
+    assert_eq!(pool.total_cash, SEED - 4_000);
+    assert_eq!(pool.borrow_index, 1_000_000_000);
+    assert_eq!(s.pool.list_pools(), vec![&s.e, token.clone()]);
+    let decimals: Option<u32> = s.e.as_contract(&s.pool.address, || {
+        s.e.storage()
+            .persistent()
+            .get(&DataKey::Decimals(token.clone()))
+    });
+    assert_eq!(decimals, Some(6));
+
+    assert!(s.pool.try_migrate(&vec![&s.e, token]).is_err());
+}
+
+#[test]
+#[should_panic(expected = "storage already migrated")]
+fn test_migrate_is_refused_on_current_storage() {
+    let s = setup();
+    s.pool.migrate(&Vec::new(&s.e));
+}
+
+#[test]
+fn test_list_pools() {
+    let s = setup();
+    assert_eq!(s.pool.list_pools(), Vec::new(&s.e));
+    let first = s.create_market(8000, ONE_DOLLAR);
+    let second = s.create_market(8000, ONE_DOLLAR);
+    assert_eq!(
+        s.pool.list_pools(),
+        vec![&s.e, first.clone(), second.clone()]
+    );
+
+    s.pool.deactivate_pool(&first);
+    assert_eq!(s.pool.list_pools().len(), 2);
+    assert!(!s.pool.is_pool_active(&first));
+    assert!(s.pool.is_pool_active(&second));
+}
+
+#[test]
+fn test_max_borrow_is_exact() {
+    let s = setup();
+    let collateral = s.create_market(8000, ONE_DOLLAR);
+    let debt = s.create_market(8000, 2 * ONE_DOLLAR);
+    let user = Address::generate(&s.e);
+    s.fund_and_supply(&user, &collateral, 1_001);
+
+    // 800.8 of value buys 400 tokens at $2.
+    assert_eq!(s.pool.max_borrow(&user, &debt), 400);
+    s.pool.borrow(&user, &debt, &400);
+    assert_eq!(s.pool.max_borrow(&user, &debt), 0);
+    assert!(s.pool.try_borrow(&user, &debt, &1).is_err());
+}
+
+#[test]
+fn test_max_borrow_respects_liquidity_and_cap() {
+    let s = setup();
+    let collateral = s.create_market(8000, ONE_DOLLAR);
+    let debt = s.create_mock_market(6, 0, 8000, ONE_DOLLAR);
+    let user = Address::generate(&s.e);
+    s.fund_and_supply(&user, &collateral, 1_000_000);
+
+    // Collateral covers 80_000 units of the 6-decimal asset, more than
+    // the pool holds.
+    assert_eq!(s.pool.max_borrow(&user, &debt), SEED);
+    s.pool.set_borrow_cap(&debt, &3_000);
+    assert_eq!(s.pool.max_borrow(&user, &debt), 3_000);
+    s.pool.borrow(&user, &debt, &3_000);
+    assert_eq!(s.pool.max_borrow(&user, &debt), 0);
+}
+
+#[test]
+fn test_max_borrow_is_zero_when_borrow_would_be_refused() {
+    let s = setup();
+    let collateral = s.create_market(8000, ONE_DOLLAR);
+    let debt = s.create_market(8000, ONE_DOLLAR);
+    let user = Address::generate(&s.e);
+    s.fund_and_supply(&user, &collateral, 1_000);
+    assert_eq!(s.pool.max_borrow(&user, &debt), 800);
+
+    s.pool.set_frozen(&s.admin, &debt, &true);
+    assert_eq!(s.pool.max_borrow(&user, &debt), 0);
+    s.pool.set_frozen(&s.admin, &debt, &false);
+
+    s.pool.set_action_paused(&s.admin, &Action::Borrow, &true);
+    assert_eq!(s.pool.max_borrow(&user, &debt), 0);
+    s.pool.set_action_paused(&s.admin, &Action::Borrow, &false);
+
+    s.pool.set_allowlist_enabled(&debt, &true);
+    assert_eq!(s.pool.max_borrow(&user, &debt), 0);
+    s.pool.set_allowed(&debt, &user, &true);
+    assert_eq!(s.pool.max_borrow(&user, &debt), 800);
+
+    s.pool.set_min_borrow(&debt, &801);
+    assert_eq!(s.pool.max_borrow(&user, &debt), 0);
+    s.pool.set_min_borrow(&debt, &0);
+
+    s.pool.set_max_price_deviation(&2000);
+    s.pool.set_price(&collateral, &(ONE_DOLLAR * 2));
+    assert_eq!(s.pool.max_borrow(&user, &debt), 0);
+}
+
+#[test]
+fn test_disabled_collateral_adds_no_borrowing_power() {
+    let s = setup();
+    let collateral = s.create_market(8000, ONE_DOLLAR);
+    let savings = s.create_market(8000, ONE_DOLLAR);
+    let debt = s.create_market(8000, ONE_DOLLAR);
+    let user = Address::generate(&s.e);
+    s.fund_and_supply(&user, &collateral, 1_000);
+    s.fund_and_supply(&user, &savings, 1_000);
+
+    s.pool.set_use_as_collateral(&user, &savings, &false);
+    assert_eq!(s.pool.max_borrow(&user, &debt), 800);
+    s.pool.borrow(&user, &debt, &800);
+
+    // The disabled deposit can leave freely and can't be seized.
+    s.pool.set_price(&collateral, &(ONE_DOLLAR / 2));
+    assert!(s
+        .pool
+        .try_liquidate(&Address::generate(&s.e), &user, &debt, &savings, &100)
+        .is_err());
+    assert_eq!(s.pool.withdraw(&user, &savings, &i128::MAX), 1_000);
+}
+
+#[test]
+#[should_panic(expected = "insufficient collateral")]
+fn test_cannot_disable_collateral_backing_debt() {
+    let s = setup();
+    let collateral = s.create_market(8000, ONE_DOLLAR);
+    let debt = s.create_market(8000, ONE_DOLLAR);
+    let user = Address::generate(&s.e);
+    s.fund_and_supply(&user, &collateral, 1_000);
+    s.pool.borrow(&user, &debt, &100);
+
+    s.pool.set_use_as_collateral(&user, &collateral, &false);
+}
+
+#[test]
+fn test_isolated_collateral_enforces_debt_ceiling() {
+    let s = setup();
+    let risky = s.create_market(8000, ONE_DOLLAR);
+    let debt = s.create_market(8000, ONE_DOLLAR);
+    s.pool.set_isolation(
+        &risky,
+        &IsolationConfig {
+            isolated: true,
+            debt_ceiling: 100 * ONE_DOLLAR,
+        },
+    );
+    let user = Address::generate(&s.e);
+    s.fund_and_supply(&user, &risky, 10_000_000_000);
+    s.fund_and_supply(&Address::generate(&s.e), &debt, 10_000_000_000);
+
+    // $100 of debt tokens is the most the isolated collateral can back.
+    assert_eq!(s.pool.max_borrow(&user, &debt), 100 * ONE_DOLLAR);
+    s.pool.borrow(&user, &debt, &(100 * ONE_DOLLAR));
+    assert!(s.pool.try_borrow(&user, &debt, &1).is_err());
+}
+
+#[test]
+fn test_isolated_collateral_does_not_mix() {
+    let s = setup();
+    let risky = s.create_market(8000, ONE_DOLLAR);
+    let other = s.create_market(8000, ONE_DOLLAR);
+    let debt = s.create_market(8000, ONE_DOLLAR);
+    s.pool.set_isolation(
+        &risky,
+        &IsolationConfig {
+            isolated: true,
+            debt_ceiling: 1_000_000 * ONE_DOLLAR,
+        },
+    );
+    let user = Address::generate(&s.e);
+    s.fund_and_supply(&user, &risky, 1_000);
+    // The second deposit is held outside of collateral.
+    s.fund_and_supply(&user, &other, 1_000);
+    assert_eq!(s.pool.max_borrow(&user, &debt), 800);
+    assert!(s
+        .pool
+        .try_set_use_as_collateral(&user, &other, &true)
+        .is_err());
+
+    // Dropping the isolated asset frees the other one to back debt.
+    s.pool.set_use_as_collateral(&user, &risky, &false);
+    s.pool.set_use_as_collateral(&user, &other, &true);
+    assert_eq!(s.pool.max_borrow(&user, &debt), 800);
+}
+
+#[test]
+fn test_liquidation_threshold_above_ltv() {
+    let s = setup();
+    let collateral = s.create_market(7500, ONE_DOLLAR);
+    let debt = s.create_market(8000, ONE_DOLLAR);
+    s.pool.set_liquidation_threshold(&collateral, &8500);
+    let user = Address::generate(&s.e);
+    s.fund_and_supply(&user, &collateral, 1_000);
+    s.pool.borrow(&user, &debt, &750);
+
+    // Past the LTV but within the threshold: no new debt, no liquidation.
+    s.pool.set_price(&collateral, &(ONE_DOLLAR * 9 / 10));
+    assert_eq!(s.pool.max_borrow(&user, &debt), 0);
+    assert_eq!(s.pool.get_health_factor(&user), 10200);
+    let liquidator = Address::generate(&s.e);
+    s.mint(&debt, &liquidator, 100);
+    assert!(s
+        .pool
+        .try_liquidate(&liquidator, &user, &debt, &collateral, &100)
+        .is_err());
+
+    s.pool.set_price(&collateral, &(ONE_DOLLAR * 85 / 100));
+    s.pool
+        .liquidate(&liquidator, &user, &debt, &collateral, &100);
+}
+
+#[test]
+fn test_emode_boosts_correlated_assets() {
+    let s = setup();
+    let usdc = s.create_market(8000, ONE_DOLLAR);
+    let usdt = s.create_market(8000, ONE_DOLLAR);
+    let xlm = s.create_market(8000, ONE_DOLLAR);
+    s.pool.set_emode_category(
+        &1,
+        &EmodeCategory {
+            ltv: 9500,
+            liquidation_threshold: 9700,
+        },
+    );
+    s.pool.set_asset_emode(&usdc, &1);
+    s.pool.set_asset_emode(&usdt, &1);
+    let user = Address::generate(&s.e);
+    s.fund_and_supply(&user, &usdc, 1_000);
+
+    s.pool.set_user_emode(&user, &1);
+    assert_eq!(s.pool.max_borrow(&user, &usdt), 950);
+    s.pool.borrow(&user, &usdt, &950);
+    assert_eq!(s.pool.get_health_factor(&user), 10210);
+
+    // Borrowing outside the category would drop the boost.
+    assert!(s.pool.try_borrow(&user, &xlm, &1).is_err());
+    assert!(s.pool.try_set_user_emode(&user, &0).is_err());
+}
+
+#[test]
+fn test_credit_delegation() {
+    let s = setup();
+    let collateral = s.create_market(8000, ONE_DOLLAR);
+    let debt = s.create_market(8000, ONE_DOLLAR);
+    let delegator = Address::generate(&s.e);
+    let strategy = Address::generate(&s.e);
+    s.fund_and_supply(&delegator, &collateral, 1_000);
+
+    s.pool
+        .approve_delegation(&delegator, &strategy, &debt, &500);
+    s.pool.borrow_on_behalf(&strategy, &delegator, &debt, &300);
+    assert_eq!(s.e.auths()[0].0, strategy);
+    assert_eq!(s.balance(&debt, &strategy), 300);
+    assert_eq!(
+        s.pool
+            .get_position(&delegator)
+            .debt_shares
+            .get(debt.clone()),
+        Some(300)
+    );
+    assert_eq!(s.pool.borrow_allowance(&delegator, &strategy, &debt), 200);
+
+    assert!(s
+        .pool
+        .try_borrow_on_behalf(&strategy, &delegator, &debt, &201)
+        .is_err());
+    assert_eq!(s.pool.borrow_allowance(&delegator, &strategy, &debt), 200);
+}
+
+#[test]
+#[should_panic(expected = "insufficient collateral")]
+fn test_delegated_borrow_checks_delegator_health() {
+    let s = setup();
+    let collateral = s.create_market(8000, ONE_DOLLAR);
+    let debt = s.create_market(8000, ONE_DOLLAR);
+    let delegator = Address::generate(&s.e);
+    let strategy = Address::generate(&s.e);
+    s.fund_and_supply(&delegator, &collateral, 1_000);
+
+    s.pool
+        .approve_delegation(&delegator, &strategy, &debt, &5_000);
+    s.pool.borrow_on_behalf(&strategy, &delegator, &debt, &801);
+}
+
+#[test]
+fn test_ltv_and_threshold_validated_together() {
+    let s = setup();
+    let token = s.create_market(7500, ONE_DOLLAR);
+
+    // Equal values and a threshold of exactly 10000 are allowed
+    s.pool.set_liquidation_threshold(&token, &7500);
+    s.pool.set_liquidation_threshold(&token, &10000);
+    s.pool.set_ltv(&token, &10000);
+    s.pool.set_ltv(&token, &7500);
+
+    assert!(s.pool.try_set_liquidation_threshold(&token, &7499).is_err());
+    assert!(s
+        .pool
+        .try_set_liquidation_threshold(&token, &10001)
+        .is_err());
+    s.pool.set_liquidation_threshold(&token, &8000);
+    assert!(s.pool.try_set_ltv(&token, &8001).is_err());
+    s.pool.set_ltv(&token, &8000);
+}
+
+#[test]
+#[should_panic(expected = "LTV cannot exceed liquidation threshold")]
+fn test_ltv_above_threshold_panics() {
+    let s = setup();
+    let token = s.create_market(7500, ONE_DOLLAR);
+    s.pool.set_liquidation_threshold(&token, &8000);
+    s.pool.set_ltv(&token, &8500);
+}
+
+#[test]
+#[should_panic(expected = "liquidation threshold cannot be below LTV")]
+fn test_threshold_below_ltv_panics() {
+    let s = setup();
+    let token = s.create_market(7500, ONE_DOLLAR);
+    s.pool.set_liquidation_threshold(&token, &7000);
+}
+
+#[test]
+fn test_price_circuit_breaker() {
+    let s = setup();
+    let collateral = s.create_market(8000, ONE_DOLLAR);
+    let debt = s.create_market(8000, ONE_DOLLAR);
+    s.pool.set_max_price_deviation(&2000);
+    let user = Address::generate(&s.e);
+    s.fund_and_supply(&user, &collateral, 1_000);
+    s.pool.borrow(&user, &debt, &500);
+
+    // A 20% move is within bounds
+    s.pool.set_price(&collateral, &(ONE_DOLLAR * 8 / 10));
+    s.pool.borrow(&user, &debt, &1);
+
+    // A further 50% drop stores the price but freezes the asset
+    s.pool.set_price(&collateral, &(ONE_DOLLAR * 4 / 10));
+    s.assert_published(&PriceCircuitBreaker {
+        token: collateral.clone(),
+        previous_price: ONE_DOLLAR * 8 / 10,
+        price: ONE_DOLLAR * 4 / 10,
+    });
+    assert_eq!(s.pool.price_of(&collateral), ONE_DOLLAR * 4 / 10);
+    let liquidator = Address::generate(&s.e);
+    s.mint(&debt, &liquidator, 100);
+    assert!(s
+        .pool
+        .try_liquidate(&liquidator, &user, &debt, &collateral, &100)
+        .is_err());
+    s.pool.set_price(&collateral, &ONE_DOLLAR);
+    assert!(s.pool.try_borrow(&user, &debt, &1).is_err());
+
+    s.pool.clear_price_freeze(&collateral);
+    s.pool.borrow(&user, &debt, &1);
+}
+
+#[test]
+fn test_price_circuit_breaker_judges_oracle_prices() {
+    let s = setup();
+    let collateral = s.create_market(8000, ONE_DOLLAR);
+    let debt = s.create_market(8000, ONE_DOLLAR);
+    let oracle_id = s.e.register(MockOracle, ());
+    let oracle = MockOracleClient::new(&s.e, &oracle_id);
+    s.pool.set_oracle(&oracle_id);
+    s.pool.set_use_oracle(&collateral, &true);
+    s.pool.set_max_price_deviation(&2000);
+    let user = Address::generate(&s.e);
+    s.fund_and_supply(&user, &collateral, 1_000);
+
+    // $1 with 14 decimals, then a 20% move: both within bounds
+    oracle.set(&collateral, &100_000_000_000_000);
+    s.pool.borrow(&user, &debt, &300);
+    oracle.set(&collateral, &80_000_000_000_000);
+    s.pool.borrow(&user, &debt, &1);
+
+    // A further 50% drop freezes the asset for as long as the oracle reports it
+    oracle.set(&collateral, &40_000_000_000_000);
+    assert_eq!(s.pool.max_borrow(&user, &debt), 0);
+    assert!(s.pool.try_borrow(&user, &debt, &1).is_err());
+    assert!(s.pool.try_withdraw(&user, &collateral, &1).is_err());
+    oracle.set(&collateral, &70_000_000_000_000);
+    s.pool.borrow(&user, &debt, &1);
+
+    // Or until the owner accepts the new price
+    oracle.set(&collateral, &40_000_000_000_000);
+    assert!(s.pool.try_borrow(&user, &debt, &1).is_err());
+    s.pool.clear_price_freeze(&collateral);
+    s.pool.borrow(&user, &debt, &1);
+}
+
+#[test]
+fn test_allowlist_gates_supply_and_borrow() {
+    let s = setup();
+    let collateral = s.create_market(8000, ONE_DOLLAR);
+    let debt = s.create_market(8000, ONE_DOLLAR);
+    let user = Address::generate(&s.e);
+    s.fund_and_supply(&user, &collateral, 1_000);
+    s.mint(&debt, &user, 1_000);
+
+    s.pool.set_allowlist_enabled(&debt, &true);
+    assert!(s.pool.try_supply(&user, &debt, &100).is_err());
+    assert!(s.pool.try_borrow(&user, &debt, &100).is_err());
+    // Pools without an allowlist are unaffected
+    s.fund_and_supply(&user, &collateral, 100);
+
+    s.pool.set_allowed(&debt, &user, &true);
+    s.pool.borrow(&user, &debt, &100);
+
+    // Removed accounts can still unwind
+    s.pool.set_allowed(&debt, &user, &false);
+    assert!(s.pool.try_borrow(&user, &debt, &100).is_err());
+    s.pool.repay(&user, &debt, &i128::MAX);
+}
+
+#[test]
+fn test_withdraw_cooldown() {
+    let s = setup();
+    let token = s.create_market(8000, ONE_DOLLAR);
+    let user = Address::generate(&s.e);
+    s.advance_time(1_000);
+    s.pool.set_withdraw_cooldown(&3_600);
+    s.fund_and_supply(&user, &token, 1_000);
+
+    assert!(s.pool.try_withdraw(&user, &token, &100).is_err());
+    s.advance_time(3_599);
+    assert!(s.pool.try_withdraw(&user, &token, &100).is_err());
+    s.advance_time(1);
+    s.pool.withdraw(&user, &token, &100);
+
+    // Supplying again restarts the wait; disabling it lifts it
+    s.fund_and_supply(&user, &token, 100);
+    assert!(s.pool.try_withdraw(&user, &token, &100).is_err());
+    s.pool.set_withdraw_cooldown(&0);
+    s.pool.withdraw(&user, &token, &100);
+}
+
+#[test]
+fn test_supply_and_withdraw_return_share_deltas() {
+    let s = setup();
+    let token = s.create_market(8000, ONE_DOLLAR);
+    let other = s.create_market(8000, ONE_DOLLAR);
+    s.pool.set_interest_rate(&token, &1000);
+    let user = Address::generate(&s.e);
+    let borrower = Address::generate(&s.e);
+    s.fund_and_supply(&borrower, &other, 20_000);
+    s.pool.borrow(&borrower, &token, &5_000);
+    s.advance_time(ONE_YEAR);
+
+    // After interest, a share is worth more than one token
+    s.mint(&token, &user, 1_000);
+    let minted = s.pool.supply(&user, &token, &1_000);
+    assert!(minted > 0 && minted < 1_000);
+    let shares = s.pool.get_position(&user).deposit_shares.get(token.clone());
+    assert_eq!(shares, Some(minted));
+
+    let burned = s.pool.withdraw(&user, &token, &400);
+    assert!(burned > 0 && burned < 400);
+    let shares = s.pool.get_position(&user).deposit_shares.get(token.clone());
+    assert_eq!(shares, Some(minted - burned));
+    assert_eq!(s.pool.withdraw(&user, &token, &i128::MAX), minted - burned);
+}
+
+#[test]
+fn test_supply_token_moves_deposits() {
+    let s = setup();
+    let collateral = s.create_market(8000, ONE_DOLLAR);
+    let debt = s.create_market(8000, ONE_DOLLAR);
+    let alice = Address::generate(&s.e);
+    let bob = Address::generate(&s.e);
+    let receipt = s.e.register(MockSupplyToken, ());
+    s.fund_and_supply(&alice, &collateral, 1_000);
+    s.pool.borrow(&alice, &debt, &400);
+
+    assert!(s
+        .pool
+        .try_transfer_deposit(&collateral, &alice, &bob, &100)
+        .is_err());
+    s.pool.set_supply_token(&collateral, &receipt);
+    assert_eq!(s.pool.supply_token_of(&collateral), Some(receipt.clone()));
+
+    s.pool.transfer_deposit(&collateral, &alice, &bob, &400);
+    assert_eq!(s.e.auths()[0].0, receipt);
+    assert_eq!(s.pool.deposit_shares_of(&alice, &collateral), 600);
+    assert_eq!(s.pool.deposit_shares_of(&bob, &collateral), 400);
+    s.pool.withdraw(&bob, &collateral, &400);
+    assert_eq!(s.balance(&collateral, &bob), 400);
+
+    // The sender must stay healthy, as for a withdrawal
+    assert!(s
+        .pool
+        .try_transfer_deposit(&collateral, &alice, &bob, &101)
+        .is_err());
+    assert!(s
+        .pool
+        .try_transfer_deposit(&collateral, &bob, &alice, &1)
+        .is_err());
+}
+
+#[test]
+fn test_supply_token_hears_of_every_deposit_change() {
+    let s = setup();
+    let collateral = s.create_market(8000, ONE_DOLLAR);
+    let debt = s.create_market(8000, ONE_DOLLAR);
+    let receipt = MockSupplyTokenClient::new(&s.e, &s.e.register(MockSupplyToken, ()));
+    s.pool.set_supply_token(&collateral, &receipt.address);
+    let borrower = Address::generate(&s.e);
+    let liquidator = Address::generate(&s.e);
+
+    s.fund_and_supply(&borrower, &collateral, 1_000);
+    assert_eq!(receipt.balance(&borrower), 1_000);
+    s.pool.withdraw(&borrower, &collateral, &100);
+    s.pool.request_withdraw(&borrower, &collateral, &100);
+    assert_eq!(receipt.balance(&borrower), 800);
+
+    s.pool.borrow(&borrower, &debt, &600);
+    s.pool.set_price(&collateral, &(ONE_DOLLAR / 2));
+    s.mint(&debt, &liquidator, 200);
+    s.pool
+        .liquidate(&liquidator, &borrower, &debt, &collateral, &200);
+    assert_eq!(receipt.balance(&borrower), 400);
+    assert_eq!(
+        receipt.balance(&borrower),
+        s.pool.deposit_shares_of(&borrower, &collateral)
+    );
+}
+
+#[test]
+fn test_withdraw_shares() {
+    let s = setup();
+    let token = s.create_market(8000, ONE_DOLLAR);
+    let other = s.create_market(8000, ONE_DOLLAR);
+    let debt = s.create_market(8000, ONE_DOLLAR);
+    s.pool.set_interest_rate(&token, &1000);
+    let user = Address::generate(&s.e);
+    let borrower = Address::generate(&s.e);
+    s.fund_and_supply(&user, &token, 1_000);
+    s.fund_and_supply(&borrower, &other, 20_000);
+    s.pool.borrow(&borrower, &token, &5_000);
+    s.advance_time(ONE_YEAR);
+
+    // Shares have grown in value, so 500 of them pay out more than 500
+    let paid = s.pool.withdraw_shares(&user, &token, &500);
+    assert!(paid > 500);
+    assert_eq!(s.balance(&token, &user), paid);
+    assert_eq!(s.pool.deposit_shares_of(&user, &token), 500);
+    assert!(s.pool.try_withdraw_shares(&user, &token, &501).is_err());
+
+    // The same health check as `withdraw` applies
+    s.pool.borrow(&user, &debt, &300);
+    assert!(s.pool.try_withdraw_shares(&user, &token, &200).is_err());
+    s.pool.withdraw_shares(&user, &token, &50);
+}
+
+#[test]
+fn test_exchange_rates_track_interest() {
+    let s = setup();
+    let token = s.create_market(8000, ONE_DOLLAR);
+    let other = s.create_market(8000, ONE_DOLLAR);
+    s.pool.set_interest_rate(&token, &1000);
+    let borrower = Address::generate(&s.e);
+    s.fund_and_supply(&borrower, &other, 20_000);
+    s.pool.borrow(&borrower, &token, &5_000);
+    assert_eq!(s.pool.supply_exchange_rate(&token), 1_000_000_000);
+    assert_eq!(s.pool.borrow_exchange_rate(&token), 1_000_000_000);
+
+    s.advance_time(ONE_YEAR);
+    assert_eq!(s.pool.borrow_exchange_rate(&token), 1_100_000_000);
+    let rate = s.pool.supply_exchange_rate(&token);
+    assert!(rate > 1_000_000_000);
+
+    // The rate converts shares the same way the contract does
+    let user = Address::generate(&s.e);
+    s.fund_and_supply(&user, &token, 1_000);
+    let shares = s.pool.deposit_shares_of(&user, &token);
+    assert_eq!(shares, 1_000 * 1_000_000_000 / rate);
+}
+
+#[test]
+fn test_supply_many() {
+    let s = setup();
+    let a = s.create_market(8000, ONE_DOLLAR);
+    let b = s.create_market(5000, ONE_DOLLAR);
+    let user = Address::generate(&s.e);
+    s.mint(&a, &user, 1_000);
+    s.mint(&b, &user, 2_000);
+
+    let deposits = Map::from_array(&s.e, [(a.clone(), 1_000), (b.clone(), 2_000)]);
+    assert_eq!(s.pool.supply_many(&user, &deposits), 2);
+    assert_eq!(s.e.auths().len(), 1);
+    assert_eq!(s.pool.deposit_of(&user, &a), 1_000);
+    assert_eq!(s.pool.deposit_of(&user, &b), 2_000);
+
+    // One bad entry fails the whole batch
+    s.mint(&a, &user, 100);
+    let unknown = Address::generate(&s.e);
+    let deposits = Map::from_array(&s.e, [(a.clone(), 100), (unknown, 100)]);
+    assert!(s.pool.try_supply_many(&user, &deposits).is_err());
+    let deposits = Map::from_array(&s.e, [(a.clone(), 100), (b.clone(), 0)]);
+    assert!(s.pool.try_supply_many(&user, &deposits).is_err());
+    assert_eq!(s.pool.deposit_of(&user, &a), 1_000);
+}
+
+#[test]
+fn test_repay_many_clamps_each_debt() {
+    let s = setup();
+    let collateral = s.create_market(8000, ONE_DOLLAR);
+    let a = s.create_market(8000, ONE_DOLLAR);
+    let b = s.create_market(8000, ONE_DOLLAR);
+    let user = Address::generate(&s.e);
+    s.fund_and_supply(&user, &collateral, 1_000);
+    s.pool.borrow(&user, &a, &300);
+    s.pool.borrow(&user, &b, &200);
+    s.mint(&b, &user, 100);
+
+    let repayments = Map::from_array(&s.e, [(a.clone(), 100), (b.clone(), i128::MAX)]);
+    let repaid = s.pool.repay_many(&user, &repayments);
+    assert_eq!(
+        repaid,
+        Map::from_array(&s.e, [(a.clone(), 100), (b.clone(), 200)])
+    );
+    assert_eq!(s.e.auths().len(), 1);
+    assert_eq!(s.pool.debt_of(&user, &a), 200);
+    assert_eq!(s.pool.debt_of(&user, &b), 0);
+    assert_eq!(s.balance(&b, &user), 100);
+}
+
+#[test]
+fn test_is_liquidatable() {
+    let s = setup();
+    let collateral = s.create_market(8000, ONE_DOLLAR);
+    let debt = s.create_market(8000, ONE_DOLLAR);
+    let user = Address::generate(&s.e);
+    assert!(!s.pool.is_liquidatable(&user));
+    s.fund_and_supply(&user, &collateral, 1_000);
+    s.pool.borrow(&user, &debt, &800);
+    assert!(!s.pool.is_liquidatable(&user));
+
+    s.pool.set_price(&collateral, &(ONE_DOLLAR * 99 / 100));
+    assert!(s.pool.is_liquidatable(&user));
+    let liquidator = Address::generate(&s.e);
+    s.mint(&debt, &liquidator, 100);
+    s.pool
+        .liquidate(&liquidator, &user, &debt, &collateral, &100);
+}
+
+#[test]
+fn test_max_withdraw() {
+    let s = setup();
+    let collateral = s.create_market(8000, ONE_DOLLAR);
+    let debt = s.create_market(8000, ONE_DOLLAR);
+    let user = Address::generate(&s.e);
+    assert_eq!(s.pool.max_withdraw(&user, &collateral), 0);
+    s.fund_and_supply(&user, &collateral, 1_000);
+    assert_eq!(s.pool.max_withdraw(&user, &collateral), 1_000);
+
+    s.pool.borrow(&user, &debt, &400);
+    let max = s.pool.max_withdraw(&user, &collateral);
+    assert!(max > 490 && max <= 500);
+    assert!(s
+        .pool
+        .try_withdraw(&user, &collateral, &(max + 10))
+        .is_err());
+    s.pool.withdraw(&user, &collateral, &i128::MAX);
+    assert_eq!(s.balance(&collateral, &user), max);
+}
+
+#[test]
+#[should_panic(expected = "token cannot be the pool itself")]
+fn test_init_pool_rejects_own_address() {
+    let s = setup();
+    s.pool.init_pool(&s.pool.address);
+}
+
+#[test]
+#[should_panic(expected = "token cannot be the pool itself")]
+fn test_set_ltv_rejects_own_address() {
+    let s = setup();
+    s.pool.set_ltv(&s.pool.address, &8000);
+}
+
+#[test]
+#[should_panic(expected = "pool not initialized")]
+fn test_set_ltv_requires_pool() {
+    let s = setup();
+    s.pool.set_ltv(&Address::generate(&s.e), &8000);
+}
+
+#[test]
+#[should_panic(expected = "token cannot be the pool itself")]
+fn test_set_price_rejects_own_address() {
+    let s = setup();
+    s.pool.set_price(&s.pool.address, &ONE_DOLLAR);
+}
+
+#[test]
+#[should_panic(expected = "pool not initialized")]
+fn test_set_price_requires_pool() {
+    let s = setup();
+    s.pool.set_price(&Address::generate(&s.e), &ONE_DOLLAR);
+}
+
+#[test]
+#[should_panic(expected = "price not set for")]
+fn test_unpriced_collateral_reverts_withdraw() {
+    let s = setup();
+    let collateral = s.create_market(8000, ONE_DOLLAR);
+    let other = s.create_market(8000, ONE_DOLLAR);
+    let debt = s.create_market(8000, ONE_DOLLAR);
+    let user = Address::generate(&s.e);
+    s.fund_and_supply(&user, &collateral, 1_000);
+    s.fund_and_supply(&user, &other, 1_000);
+    s.pool.borrow(&user, &debt, &100);
+
+    // Valuing `other` at 0 would still pass this withdrawal; it must fail
+    // loudly instead.
+    s.pool.set_price(&other, &0);
+    assert_eq!(s.pool.price_of(&other), 0);
+    s.pool.withdraw(&user, &collateral, &100);
+}
+
+#[test]
+fn test_check_invariants() {
+    let s = setup();
+    let collateral = s.create_market(8000, ONE_DOLLAR);
+    let token = s.create_market(8000, ONE_DOLLAR);
+    s.pool.set_interest_rate(&token, &1000);
+    s.pool.set_reserve_factor(&token, &2000);
+    let user = Address::generate(&s.e);
+    s.fund_and_supply(&user, &collateral, 20_000);
+    s.pool.borrow(&user, &token, &5_000);
+    s.advance_time(ONE_YEAR);
+    s.pool.check_invariants(&token);
+
+    s.mint(&token, &user, 10_000);
+    s.pool.repay(&user, &token, &i128::MAX);
+    s.pool.withdraw(&user, &collateral, &i128::MAX);
+    s.pool.check_invariants(&token);
+    s.pool.check_invariants(&collateral);
+
+    // Cash the contract doesn't actually hold is caught
+    let edit_pool = |edit: &dyn Fn(&mut Pool)| {
+        s.e.as_contract(&s.pool.address, || {
+            let mut pool: Pool =
+                s.e.storage()
+                    .persistent()
+                    .get(&DataKey::Pool(token.clone()))
+                    .unwrap();
+            edit(&mut pool);
+            s.e.storage()
+                .persistent()
+                .set(&DataKey::Pool(token.clone()), &pool);
+        });
+    };
+    edit_pool(&|pool| pool.total_cash += 1);
+    assert!(s.pool.try_check_invariants(&token).is_err());
+    edit_pool(&|pool| pool.total_cash -= 1);
+
+    // Tokens sent straight to the pool and queued withdrawals are allowed for
+    s.mint(&token, &s.pool.address, 1_000);
+    s.fund_and_supply(&user, &token, 1_000);
+    s.pool.request_withdraw(&user, &token, &500);
+    s.pool.check_invariants(&token);
+
+    // Assets that no supply share or reserve accounts for are caught too
+    edit_pool(&|pool| pool.total_supply_shares = 0);
+    assert!(s.pool.try_check_invariants(&token).is_err());
+}
+
+#[test]
+fn test_reward_emissions() {
+    let s = setup();
+    let collateral = s.create_market(8000, ONE_DOLLAR);
+    let token = s.create_market(8000, ONE_DOLLAR);
+    let reward =
+        s.e.register_stellar_asset_contract_v2(s.admin.clone())
+            .address();
+    s.mint(&reward, &s.pool.address, 1_000_000);
+    let config = EmissionConfig {
+        reward_token: reward.clone(),
+        supply_per_second: 100,
+        borrow_per_second: 50,
+    };
+    s.pool.set_emission_config(&token, &config);
+
+    // Half of the supply shares and all of the debt shares
+    let supplier = Address::generate(&s.e);
+    let borrower = Address::generate(&s.e);
+    s.fund_and_supply(&supplier, &token, SEED);
+    s.fund_and_supply(&borrower, &collateral, 5_000);
+    s.pool.borrow(&borrower, &token, &1_000);
+    s.advance_time(100);
+
+    assert_eq!(s.pool.pending_rewards(&supplier, &token), 5_000);
+    assert_eq!(s.pool.pending_rewards(&borrower, &token), 5_000);
+    assert_eq!(s.pool.claim_rewards(&supplier, &token), 5_000);
+    assert_eq!(s.balance(&reward, &supplier), 5_000);
+    assert_eq!(s.pool.pending_rewards(&supplier, &token), 0);
+
+    // Rewards earned before a position change are kept
+    s.pool.repay(&borrower, &token, &i128::MAX);
+    s.advance_time(100);
+    assert_eq!(s.pool.pending_rewards(&borrower, &token), 5_000);
+    assert_eq!(s.pool.claim_rewards(&borrower, &token), 5_000);
+    assert_eq!(s.pool.pending_rewards(&supplier, &token), 5_000);
+    assert_eq!(s.pool.pending_rewards(&supplier, &collateral), 0);
+}
+
+#[test]
+#[should_panic(expected = "reward token cannot be a pool asset")]
+fn test_reward_token_cannot_be_pool_asset() {
+    let s = setup();
+    let token = s.create_market(8000, ONE_DOLLAR);
+    let other = s.create_market(8000, ONE_DOLLAR);
+    let config = EmissionConfig {
+        reward_token: other,
+        supply_per_second: 100,
+        borrow_per_second: 0,
+    };
+    s.pool.set_emission_config(&token, &config);
+}
+
+#[test]
+#[should_panic(expected = "reward token cannot be a pool asset")]
+fn test_reward_token_cannot_be_listed() {
+    let s = setup();
+    let token = s.create_market(8000, ONE_DOLLAR);
+    let reward =
+        s.e.register_stellar_asset_contract_v2(s.admin.clone())
+            .address();
+    let config = EmissionConfig {
+        reward_token: reward.clone(),
+        supply_per_second: 100,
+        borrow_per_second: 0,
+    };
+    s.pool.set_emission_config(&token, &config);
+    s.pool.init_pool(&reward);
+}
+
+#[test]
+fn test_origination_fee_is_added_to_debt_and_reserves() {
+    let s = setup();
+    let collateral = s.create_market(8000, ONE_DOLLAR);
+    let token = s.create_market(8000, ONE_DOLLAR);
+    s.pool.set_origination_fee(&token, &100);
+    let user = Address::generate(&s.e);
+    s.fund_and_supply(&user, &collateral, 1_000);
+
+    // The fee counts against the borrow limit, so 792 + 8 uses all of it
+    assert_eq!(s.pool.max_borrow(&user, &token), 792);
+    s.pool.borrow(&user, &token, &792);
+    s.assert_published(&Borrow {
+        token: token.clone(),
+        user: user.clone(),
+        to: user.clone(),
+        amount: 792,
+        fee: 8,
+    });
+    assert_eq!(s.balance(&token, &user), 792);
+    assert_eq!(s.pool.debt_of(&user, &token), 800);
+    assert_eq!(s.pool.get_pool_info(&token).total_reserves, 8);
+    assert_eq!(s.pool.max_borrow(&user, &token), 0);
+    assert!(s.pool.try_borrow(&user, &token, &1).is_err());
+    s.pool.check_invariants(&token);
+}
+
+#[test]
+#[should_panic(expected = "origination fee cannot be over 10000")]
+fn test_origination_fee_over_10000_rejected() {
+    let s = setup();
+    let token = s.create_market(8000, ONE_DOLLAR);
+    s.pool.set_origination_fee(&token, &10001);
+}
+
+#[test]
+fn test_native_xlm_market() {
+    let s = setup();
+    let collateral = s.create_market(8000, ONE_DOLLAR);
+    let xlm = s.native_token();
+    s.pool.init_pool(&xlm);
+    s.pool.set_ltv(&xlm, &5000);
+    s.pool.set_price(&xlm, &(ONE_DOLLAR / 10));
+    assert_eq!(s.pool.get_pool_info(&xlm).token, xlm);
+
+    // A classic account supplies XLM directly from its native balance
+    let lender = s.xlm_account(100_000_000_000);
+    s.pool.supply(&lender, &xlm, &50_000_000_000);
+    assert_eq!(s.balance(&xlm, &s.pool.address), 50_000_000_000);
+
+    // 1,000 XLM of debt at $0.10 is $100, within $800 of borrow limit
+    let borrower = Address::generate(&s.e);
+    s.fund_and_supply(&borrower, &collateral, 10_000_000_000);
+    s.pool.borrow(&borrower, &xlm, &10_000_000_000);
+    assert_eq!(s.balance(&xlm, &borrower), 10_000_000_000);
+    assert_eq!(s.pool.debt_of(&borrower, &xlm), 10_000_000_000);
+
+    s.pool.repay(&borrower, &xlm, &i128::MAX);
+    assert_eq!(
+        s.pool.withdraw(&lender, &xlm, &i128::MAX),
+        50_000_000_000 - 1000
+    );
+    s.pool.check_invariants(&xlm);
+}
+
+#[test]
+fn test_max_utilization_limits_borrows() {
+    let s = setup();
+    let collateral = s.create_market(8000, ONE_DOLLAR);
+    let token = s.create_market(8000, ONE_DOLLAR);
+    s.pool.set_max_utilization(&token, &8000);
+    let user = Address::generate(&s.e);
+    s.fund_and_supply(&user, &collateral, 100_000);
+
+    // Only 8,000 of the 10,000 supplied may be lent out
+    assert_eq!(s.pool.max_borrow(&user, &token), 8_000);
+    assert!(s.pool.try_borrow(&user, &token, &8_001).is_err());
+    s.pool.borrow(&user, &token, &8_000);
+    assert_eq!(s.pool.utilization(&token), 8000);
+    assert!(s.pool.try_borrow(&user, &token, &1).is_err());
+}
+
+#[test]
+fn test_get_reserve_config() {
+    let s = setup();
+    let token = s.create_market(8000, ONE_DOLLAR);
+    assert_eq!(
+        s.pool.get_reserve_config(&token),
+        ReserveConfig {
+            ltv: 8000,
+            liquidation_threshold: 8000,
+            liquidation_bonus: 0,
+            reserve_factor: 0,
+            price: ONE_DOLLAR,
+            borrow_cap: 0,
+            supply_cap: 0,
+            active: true,
+            paused: false,
+        }
+    );
+
+    s.pool.set_liquidation_threshold(&token, &8500);
+    s.pool.set_liquidation_bonus(&token, &500);
+    s.pool.set_reserve_factor(&token, &1000);
+    s.pool.set_borrow_cap(&token, &50_000);
+    s.pool.set_supply_cap(&token, &100_000);
+    s.pool.deactivate_pool(&token);
+    s.pool.pause(&s.admin);
+    assert_eq!(
+        s.pool.get_reserve_config(&token),
+        ReserveConfig {
+            ltv: 8000,
+            liquidation_threshold: 8500,
+            liquidation_bonus: 500,
+            reserve_factor: 1000,
+            price: ONE_DOLLAR,
+            borrow_cap: 50_000,
+            supply_cap: 100_000,
+            active: false,
+            paused: true,
+        }
+    );
+}
+
+#[test]
+fn test_set_reserve_config_updates_everything_at_once() {
+    let s = setup();
+    let token = s.create_market(8000, ONE_DOLLAR);
+    let previous = s.pool.get_reserve_config(&token);
+    let config = ReserveConfig {
+        ltv: 6000,
+        liquidation_threshold: 7000,
+        liquidation_bonus: 800,
+        reserve_factor: 1500,
+        price: ONE_DOLLAR / 2,
+        borrow_cap: 1_000,
+        supply_cap: 2_000,
+        ..previous.clone()
+    };
+
+    s.pool.set_reserve_config(&token, &config);
+    s.assert_published(&ReserveConfigUpdated {
+        token: token.clone(),
+        previous,
+        config: config.clone(),
+    });
+    assert_eq!(s.pool.get_reserve_config(&token), config);
+    assert_eq!(s.pool.price_of(&token), ONE_DOLLAR / 2);
+}
+
+#[test]
+fn test_set_reserve_config_rejects_invalid_params() {
+    let s = setup();
+    let token = s.create_market(8000, ONE_DOLLAR);
+    let config = s.pool.get_reserve_config(&token);
+
+    let ltv_over_threshold = ReserveConfig {
+        ltv: 9000,
+        ..config.clone()
+    };
+    assert!(s
+        .pool
+        .try_set_reserve_config(&token, &ltv_over_threshold)
+        .is_err());
+    let status_change = ReserveConfig {
+        active: false,
+        ..config.clone()
+    };
+    assert!(s
+        .pool
+        .try_set_reserve_config(&token, &status_change)
+        .is_err());
+    let negative_cap = ReserveConfig {
+        borrow_cap: -1,
+        ..config.clone()
+    };
+    assert!(s
+        .pool
+        .try_set_reserve_config(&token, &negative_cap)
+        .is_err());
+    assert_eq!(s.pool.get_reserve_config(&token), config);
+}
+
+#[test]
+fn test_repay_with_collateral_nets_deposit_against_debt() {
+    let s = setup();
+    let collateral = s.create_market(8000, ONE_DOLLAR);
+    let debt = s.create_market(8000, ONE_DOLLAR);
+    let user = Address::generate(&s.e);
+    s.fund_and_supply(&user, &collateral, 1_000);
+    s.pool.borrow(&user, &debt, &700);
+    s.fund_and_supply(&user, &debt, 300);
+    let health_before = s.pool.get_health_factor(&user);
+    let cash_before = s.balance(&debt, &s.pool.address);
+
+    assert_eq!(
+        s.pool
+            .repay_with_collateral(&user, &debt, &debt, &i128::MAX),
+        300
+    );
+    assert_eq!(s.pool.debt_of(&user, &debt), 400);
+    assert_eq!(s.pool.deposit_shares_of(&user, &debt), 0);
+    assert!(s.pool.get_health_factor(&user) > health_before);
+    assert_eq!(s.balance(&debt, &s.pool.address), cash_before);
+    s.pool.check_invariants(&debt);
+}
+
+#[test]
+#[should_panic(expected = "collateral must be the debt token")]
+fn test_repay_with_collateral_cannot_swap_assets() {
+    let s = setup();
+    let collateral = s.create_market(8000, ONE_DOLLAR);
+    let debt = s.create_market(8000, ONE_DOLLAR);
+    let user = Address::generate(&s.e);
+    s.fund_and_supply(&user, &collateral, 1_000);
+    s.pool.borrow(&user, &debt, &700);
+    s.pool
+        .repay_with_collateral(&user, &debt, &collateral, &100);
+}
+
+#[test]
+fn test_price_decimals() {
+    for price_decimals in [6, 7, 8] {
+        let s = setup_with_price_decimals(price_decimals);
+        let one_dollar = 10i128.pow(price_decimals);
+        assert_eq!(s.pool.price_decimals(), price_decimals);
+        let collateral = s.create_market(8000, one_dollar);
+        let debt = s.create_market(8000, one_dollar * 2);
+
+        // Oracle prices are converted to the pool's decimals: $2.50
+        let oracle_id = s.e.register(MockOracle, ());
+        s.pool.set_oracle(&oracle_id);
+        s.pool.set_use_oracle(&collateral, &true);
+        MockOracleClient::new(&s.e, &oracle_id).set(&collateral, &250_000_000_000_000);
+        assert_eq!(s.pool.price_of(&collateral), one_dollar * 5 / 2);
+
+        // Values are amounts times prices, so the scale changes them but
+        // not the health factor.
+        let user = Address::generate(&s.e);
+        s.fund_and_supply(&user, &collateral, 1_000);
+        assert_eq!(s.pool.max_borrow(&user, &debt), 1_000);
+        s.pool.borrow(&user, &debt, &1_000);
+        let data = s.pool.get_account_data(&user);
+        assert_eq!(data.total_collateral_value, 2_500 * one_dollar);
+        assert_eq!(data.total_debt_value, 2_000 * one_dollar);
+        assert_eq!(data.health_factor, 10000);
+
+        // A $1 isolation ceiling is read at the pool's scale too: half a
+        // token of the $2 asset
+        s.fund_and_supply(&Address::generate(&s.e), &debt, 100_000_000);
+        let isolated = s.create_market(8000, one_dollar);
+        s.pool.set_isolation(
+            &isolated,
+            &IsolationConfig {
+                isolated: true,
+                debt_ceiling: one_dollar,
+            },
+        );
+        let other = Address::generate(&s.e);
+        s.fund_and_supply(&other, &isolated, 100_000_000);
+        assert_eq!(s.pool.max_borrow(&other, &debt), 5_000_000);
+    }
+}
+
+#[test]
+#[should_panic(expected = "price decimals cannot be over 18")]
+fn test_price_decimals_over_18_rejected() {
+    setup_with_price_decimals(19);
+}
+
+#[test]
+fn test_constructor_risk_defaults() {
+    let e = Env::default();
+    e.mock_all_auths();
+    let admin = Address::generate(&e);
+    let contract_id = e.register(
+        LendingPool,
+        (&admin, 7u32, Some(4000u32), Some(700u32), Some(3600u64)),
+    );
+    let s = Setup {
+        pool: LendingPoolClient::new(&e, &contract_id),
+        admin,
+        e,
+    };
+    let token = s.create_market(8000, ONE_DOLLAR);
+    let other = s.create_market(8000, ONE_DOLLAR);
+    s.pool.set_liquidation_bonus(&other, &300);
+
+    assert_eq!(s.pool.get_reserve_config(&token).liquidation_bonus, 700);
+    assert_eq!(s.pool.get_reserve_config(&other).liquidation_bonus, 300);
+    s.e.as_contract(&s.pool.address, || {
+        let storage = s.e.storage().persistent();
+        assert_eq!(storage.get(&DataKey::CloseFactor), Some(4000u32));
+        assert_eq!(storage.get(&DataKey::MaxPriceAge), Some(3600u64));
+    });
+}
+
+#[test]
+#[should_panic(expected = "close factor must be between 1 and 10000")]
+fn test_constructor_rejects_invalid_close_factor() {
+    let e = Env::default();
+    e.register(
+        LendingPool,
+        (
+            Address::generate(&e),
+            7u32,
+            Some(0u32),
+            None::<u32>,
+            None::<u64>,
+        ),
+    );
+}
+
+#[test]
+fn test_admin_setters_publish_admin_action() {
+    let s = setup();
+    let token = s.create_market(8000, ONE_DOLLAR);
+
+    s.pool.set_ltv(&token, &7000);
+    s.assert_published(&AdminAction {
+        param: Symbol::new(&s.e, "ltv"),
+        token: Some(token.clone()),
+        value: 7000u32.into_val(&s.e),
+    });
+    s.pool.set_price(&token, &(ONE_DOLLAR * 2));
+    s.assert_published(&AdminAction {
+        param: Symbol::new(&s.e, "price"),
+        token: Some(token.clone()),
+        value: (ONE_DOLLAR * 2).into_val(&s.e),
+    });
+    s.pool.set_close_factor(&6000);
+    s.assert_published(&AdminAction {
+        param: Symbol::new(&s.e, "close_factor"),
+        token: None,
+        value: 6000u32.into_val(&s.e),
+    });
+    s.pool.set_action_paused(&s.admin, &Action::Borrow, &true);
+    s.assert_published(&AdminAction {
+        param: Symbol::new(&s.e, "action_paused"),
+        token: None,
+        value: (Action::Borrow, true).into_val(&s.e),
+    });
+    s.pool.pause(&s.admin);
+    s.assert_published(&AdminAction {
+        param: Symbol::new(&s.e, "paused"),
+        token: None,
+        value: true.into_val(&s.e),
+    });
+
+    let mut config = s.pool.get_reserve_config(&token);
+    config.borrow_cap = 5_000;
+    s.pool.set_reserve_config(&token, &config);
+    s.assert_published(&AdminAction {
+        param: Symbol::new(&s.e, "reserve_config"),
+        token: Some(token.clone()),
+        value: config.into_val(&s.e),
+    });
+    s.pool.deactivate_pool(&token);
+    s.assert_published(&AdminAction {
+        param: Symbol::new(&s.e, "pool_active"),
+        token: Some(token.clone()),
+        value: false.into_val(&s.e),
+    });
+
+    let new_owner = Address::generate(&s.e);
+    s.pool.propose_owner(&new_owner);
+    s.assert_published(&AdminAction {
+        param: Symbol::new(&s.e, "pending_owner"),
+        token: None,
+        value: Some(new_owner).into_val(&s.e),
+    });
+    s.pool.cancel_ownership_transfer();
+    s.assert_published(&AdminAction {
+        param: Symbol::new(&s.e, "pending_owner"),
+        token: None,
+        value: None::<Address>.into_val(&s.e),
+    });
+    s.pool.schedule_upgrade(&BytesN::random(&s.e));
+    s.pool.cancel_upgrade();
+    s.assert_published(&AdminAction {
+        param: Symbol::new(&s.e, "pending_upgrade"),
+        token: None,
+        value: None::<BytesN<32>>.into_val(&s.e),
+    });
+}
+
+#[test]
+fn test_min_health_buffer_on_borrow() {
+    let s = setup();
+    let collateral = s.create_market(8000, ONE_DOLLAR);
+    let debt = s.create_market(8000, ONE_DOLLAR);
+    let user = Address::generate(&s.e);
+    s.fund_and_supply(&user, &collateral, 1_010);
+
+    // By default a borrow may use the whole 808 borrow limit
+    assert_eq!(s.pool.max_borrow(&user, &debt), 808);
+
+    // With a 1.01 buffer the limit must cover 1.01x the debt
+    s.pool.set_min_health_buffer(&10100);
+    assert_eq!(s.pool.max_borrow(&user, &debt), 800);
+    assert!(s.pool.try_borrow(&user, &debt, &801).is_err());
+    s.pool.borrow(&user, &debt, &800);
+    assert!(s.pool.try_set_min_health_buffer(&9999).is_err());
+}
+
+#[test]
+fn test_borrowers_registry() {
+    let s = setup();
+    let collateral = s.create_market(8000, ONE_DOLLAR);
+    let debt = s.create_market(8000, ONE_DOLLAR);
+    let users = [
+        Address::generate(&s.e),
+        Address::generate(&s.e),
+        Address::generate(&s.e),
+    ];
+    for user in users.iter() {
+        s.fund_and_supply(user, &collateral, 1_000);
+        s.pool.borrow(user, &debt, &100);
+    }
+    // A second borrow doesn't register the user twice
+    s.pool.borrow(&users[0], &debt, &100);
+    assert_eq!(
+        s.pool.borrowers(&0, &10),
+        vec![&s.e, users[0].clone(), users[1].clone(), users[2].clone()]
+    );
+    assert_eq!(s.pool.borrowers(&1, &1), vec![&s.e, users[1].clone()]);
+    assert_eq!(s.pool.borrowers(&5, &10), vec![&s.e]);
+
+    // Repaying in full frees the slot for the last borrower
+    s.pool.repay(&users[0], &debt, &i128::MAX);
+    assert_eq!(
+        s.pool.borrowers(&0, &10),
+        vec![&s.e, users[2].clone(), users[1].clone()]
+    );
+    s.pool.repay(&users[1], &debt, &i128::MAX);
+    s.pool.repay(&users[2], &debt, &i128::MAX);
+    assert_eq!(s.pool.borrowers(&0, &10), vec![&s.e]);
+}
+
+#[test]
+fn test_preview_borrow() {
+    let s = setup();
+    let collateral = s.create_market(8000, ONE_DOLLAR);
+    let debt = s.create_market(8000, ONE_DOLLAR);
+    s.pool.set_borrow_cap(&debt, &600);
+    let user = Address::generate(&s.e);
+    s.fund_and_supply(&user, &collateral, 1_000);
+
+    // Threshold-weighted collateral of 800 against 500 of debt
+    assert_eq!(s.pool.preview_borrow(&user, &debt, &500), (true, 16000));
+    // Past the borrow cap, and past the borrow limit
+    assert_eq!(s.pool.preview_borrow(&user, &debt, &700), (false, 11428));
+    s.pool.set_borrow_cap(&debt, &0);
+    assert_eq!(s.pool.preview_borrow(&user, &debt, &900), (false, 8888));
+
+    // Previews change nothing; the real borrow lands where predicted
+    assert_eq!(s.pool.get_position(&user).debt_shares.len(), 0);
+    s.pool.borrow(&user, &debt, &500);
+    assert_eq!(s.pool.get_health_factor(&user), 16000);
+}
+
+#[test]
+fn test_preview_liquidation() {
+    let s = setup();
+    let collateral = s.create_market(8000, ONE_DOLLAR);
+    let debt = s.create_market(8000, ONE_DOLLAR);
+    s.pool.set_liquidation_bonus(&collateral, &1000);
+
+    let lender = Address::generate(&s.e);
+    let borrower = Address::generate(&s.e);
+    let liquidator = Address::generate(&s.e);
+    s.fund_and_supply(&lender, &debt, 10_000);
+    s.fund_and_supply(&borrower, &collateral, 1_000);
+    s.pool.borrow(&borrower, &debt, &800);
+
+    // Healthy positions can't be liquidated
+    assert_eq!(
+        s.pool
+            .preview_liquidation(&borrower, &debt, &collateral, &200),
+        (0, 0)
+    );
+
+    s.pool.set_price(&collateral, &(ONE_DOLLAR / 2));
+    // 200 debt at $1 buys 400 collateral at $0.50, plus a 10% bonus
+    assert_eq!(
+        s.pool
+            .preview_liquidation(&borrower, &debt, &collateral, &200),
+        (440, 200)
+    );
+    // Repays past the 50% close factor are clamped to it
+    assert_eq!(
+        s.pool
+            .preview_liquidation(&borrower, &debt, &collateral, &700),
+        (880, 400)
+    );
+
+    // Previews change nothing; the real liquidation lands where predicted
+    s.mint(&debt, &liquidator, 400);
+    s.pool
+        .liquidate(&liquidator, &borrower, &debt, &collateral, &400);
+    assert_eq!(s.balance(&collateral, &liquidator), 880);
+}
+
+#[test]
+fn test_liquidation_protocol_fee() {
+    let s = setup();
+    let collateral = s.create_market(8000, ONE_DOLLAR);
+    let debt = s.create_market(8000, ONE_DOLLAR);
+    s.pool.set_liquidation_bonus(&collateral, &1000);
+    s.pool.set_liquidation_protocol_fee(&5000);
+    assert!(s.pool.try_set_liquidation_protocol_fee(&10001).is_err());
+
+    let lender = Address::generate(&s.e);
+    let borrower = Address::generate(&s.e);
+    let liquidator = Address::generate(&s.e);
+    s.fund_and_supply(&lender, &debt, 10_000);
+    s.fund_and_supply(&borrower, &collateral, 1_000);
+    s.pool.borrow(&borrower, &debt, &800);
+    s.pool.set_price(&collateral, &(ONE_DOLLAR / 2));
+    assert_eq!(
+        s.pool
+            .preview_liquidation(&borrower, &debt, &collateral, &200),
+        (420, 200)
+    );
+
+    s.mint(&debt, &liquidator, 200);
+    s.pool
+        .liquidate(&liquidator, &borrower, &debt, &collateral, &200);
+
+    // Of the 440 seized, half the 40 bonus goes to reserves
+    s.assert_published(&Liquidation {
+        borrower: borrower.clone(),
+        liquidator: liquidator.clone(),
+        debt_token: debt.clone(),
+        collateral_token: collateral.clone(),
+        repaid: 200,
+        seized: 440,
+        protocol_fee: 20,
+    });
+    assert_eq!(s.balance(&collateral, &liquidator), 420);
+    assert_eq!(s.pool.get_pool_info(&collateral).total_reserves, 20);
+    assert_eq!(
+        s.pool
+            .get_position(&borrower)
+            .deposit_shares
+            .get(collateral),
+        Some(560)
+    );
+}
+
+#[test]
+fn test_liquidation_protocol_fee_takes_the_actual_bonus() {
+    let s = setup();
+    let collateral = s.create_market(8000, ONE_DOLLAR);
+    let debt = s.create_market(8000, ONE_DOLLAR);
+    s.pool.set_liquidation_bonus(&collateral, &1000);
+    s.pool.set_liquidation_protocol_fee(&5000);
+    s.pool.set_min_liquidation_seize(&collateral, &70);
+    let borrower = Address::generate(&s.e);
+    let small_borrower = Address::generate(&s.e);
+    let liquidator = Address::generate(&s.e);
+    s.fund_and_supply(&Address::generate(&s.e), &debt, 10_000);
+    s.fund_and_supply(&borrower, &collateral, 100);
+    s.pool.borrow(&borrower, &debt, &50);
+    s.fund_and_supply(&small_borrower, &collateral, 60);
+    s.pool.borrow(&small_borrower, &debt, &48);
+    s.pool.set_price(&collateral, &(ONE_DOLLAR / 2));
+
+    // The floor lifts the seize to 70 for debt worth 50: the bonus is 20
+    s.mint(&debt, &liquidator, 25);
+    s.pool
+        .liquidate(&liquidator, &borrower, &debt, &collateral, &25);
+    assert_eq!(s.balance(&collateral, &liquidator), 60);
+    assert_eq!(s.pool.get_pool_info(&collateral).total_reserves, 10);
+
+    // Capped at a deposit worth less than the debt there is no bonus to share
+    s.pool.set_price(&collateral, &(ONE_DOLLAR / 4));
+    assert_eq!(
+        s.pool
+            .preview_liquidation(&small_borrower, &debt, &collateral, &23),
+        (60, 23)
+    );
+}
+
+#[test]
+fn test_liquidation_pays_out_of_lendable_cash() {
+    let s = setup();
+    let collateral = s.create_empty_market(8000, ONE_DOLLAR);
+    let debt = s.create_market(8000, ONE_DOLLAR);
+    s.pool.set_liquidation_bonus(&collateral, &1000);
+    s.pool.set_liquidation_protocol_fee(&5000);
+    let borrower = Address::generate(&s.e);
+    let lender = Address::generate(&s.e);
+    let drainer = Address::generate(&s.e);
+    let liquidator = Address::generate(&s.e);
+    s.fund_and_supply(&borrower, &collateral, 10_000);
+    s.fund_and_supply(&lender, &collateral, 10_000);
+    s.fund_and_supply(&drainer, &debt, 100_000);
+    s.pool.borrow(&drainer, &collateral, &15_700);
+    s.pool.borrow(&borrower, &debt, &7_200);
+    s.pool.set_price(&collateral, &(ONE_DOLLAR / 2));
+
+    // 4,400 is seized but only the 4,200 paid out has to be in cash
+    s.mint(&debt, &liquidator, 3_000);
+    assert_eq!(
+        s.pool
+            .preview_liquidation(&borrower, &debt, &collateral, &2_000),
+        (4_200, 2_000)
+    );
+    s.pool
+        .liquidate(&liquidator, &borrower, &debt, &collateral, &2_000);
+    assert_eq!(s.balance(&collateral, &liquidator), 4_200);
+
+    // Cash owed to the withdrawal queue can't be paid to a liquidator
+    s.pool.request_withdraw(&lender, &collateral, &10_000);
+    s.pool.repay(&drainer, &collateral, &5_000);
+    assert_eq!(
+        s.pool
+            .preview_liquidation(&borrower, &debt, &collateral, &1_000),
+        (0, 0)
+    );
+    assert!(s
+        .pool
+        .try_liquidate(&liquidator, &borrower, &debt, &collateral, &1_000)
+        .is_err());
+}
+
+#[test]
+fn test_supplier_accrued() {
+    let s = setup();
+    let collateral = s.create_market(8000, ONE_DOLLAR);
+    let debt = s.create_market(8000, ONE_DOLLAR);
+    s.pool.set_interest_rate(&debt, &1000);
+    let lender = Address::generate(&s.e);
+    let other = Address::generate(&s.e);
+    let borrower = Address::generate(&s.e);
+    s.fund_and_supply(&lender, &debt, 10_000);
+    assert_eq!(s.pool.supplier_accrued(&lender, &debt), 0);
+
+    s.fund_and_supply(&borrower, &collateral, 100_000);
+    s.pool.borrow(&borrower, &debt, &5_000);
+    s.e.ledger().with_mut(|l| l.timestamp += ONE_YEAR);
+    let earned = s.pool.supplier_accrued(&lender, &debt);
+    assert!(earned > 0);
+    assert_eq!(s.pool.deposit_of(&lender, &debt) - earned, 10_000);
+
+    // Withdrawing or transferring half the deposit takes half the
+    // principal, and half the interest, with it
+    s.pool
+        .set_supply_token(&debt, &s.e.register(MockSupplyToken, ()));
+    let shares = s.pool.deposit_shares_of(&lender, &debt);
+    s.pool
+        .transfer_deposit(&debt, &lender, &other, &(shares / 2));
+    assert_eq!(s.pool.supplier_accrued(&lender, &debt), earned / 2);
+    assert_eq!(s.pool.supplier_accrued(&other, &debt), earned / 2);
+    s.pool.withdraw(&other, &debt, &i128::MAX);
+    assert_eq!(s.pool.supplier_accrued(&other, &debt), 0);
+}
+
+#[test]
+fn test_frozen_pool_only_allows_exits() {
+    let s = setup();
+    let collateral = s.create_market(8000, ONE_DOLLAR);
+    let debt = s.create_market(8000, ONE_DOLLAR);
+    let user = Address::generate(&s.e);
+    s.fund_and_supply(&user, &collateral, 10_000);
+    s.pool.borrow(&user, &debt, &1_000);
+
+    s.pool.set_frozen(&s.admin, &debt, &true);
+    s.assert_published(&Frozen {
+        token: debt.clone(),
+    });
+    assert!(!s.pool.preview_borrow(&user, &debt, &100).0);
+
+    s.pool.repay(&user, &debt, &i128::MAX);
+    s.pool.set_frozen(&s.admin, &collateral, &true);
+    s.pool.withdraw(&user, &collateral, &i128::MAX);
+    assert_eq!(s.balance(&collateral, &user), 10_000);
+
+    s.pool.set_frozen(&s.admin, &debt, &false);
+    s.assert_published(&Unfrozen {
+        token: debt.clone(),
+    });
+    s.pool.set_frozen(&s.admin, &collateral, &false);
+    s.fund_and_supply(&user, &collateral, 1_000);
+    s.pool.borrow(&user, &debt, &100);
+}
+
+#[test]
+#[should_panic(expected = "pool is frozen")]
+fn test_supply_to_frozen_pool_panics() {
+    let s = setup();
+    let token = s.create_market(8000, ONE_DOLLAR);
+    s.pool.set_frozen(&s.admin, &token, &true);
+    s.fund_and_supply(&Address::generate(&s.e), &token, 1_000);
+}
+
+#[test]
+#[should_panic(expected = "pool is frozen")]
+fn test_borrow_from_frozen_pool_panics() {
+    let s = setup();
+    let collateral = s.create_market(8000, ONE_DOLLAR);
+    let debt = s.create_market(8000, ONE_DOLLAR);
+    let user = Address::generate(&s.e);
+    s.fund_and_supply(&user, &collateral, 1_000);
+    s.pool.set_frozen(&s.admin, &debt, &true);
+    s.pool.borrow(&user, &debt, &100);
+}
+
+#[test]
+fn test_value_of() {
+    // 7-decimal amounts are valued as is; others scale to 7 decimals
+    assert_eq!(
+        LendingPool::value_of(1_000, ONE_DOLLAR, 7),
+        1_000 * ONE_DOLLAR
+    );
+    assert_eq!(LendingPool::value_of(1_999, ONE_DOLLAR, 9), 19 * ONE_DOLLAR);
+    assert_eq!(
+        LendingPool::debt_value_of(1_999, ONE_DOLLAR, 9),
+        20 * ONE_DOLLAR
+    );
+    assert_eq!(LendingPool::value_of(i128::MAX, 1, 7), i128::MAX);
+    assert_eq!(LendingPool::value_of(i128::MAX / 2, 2, 7), i128::MAX - 1);
+    assert_eq!(
+        LendingPool::debt_value_of(i128::MAX, 1, 8),
+        i128::MAX / 10 + 1
+    );
+}
+
+#[test]
+#[should_panic(expected = "overflow")]
+fn test_value_of_overflow_panics() {
+    LendingPool::value_of(i128::MAX / 2 + 1, 2, 7);
+}
+
+#[test]
+fn test_apply_bps() {
+    assert_eq!(LendingPool::apply_bps(1_000, 2500), 250);
+    assert_eq!(LendingPool::apply_bps(9_999, 1), 0);
+    assert_eq!(LendingPool::apply_bps(-9_999, 1), 0);
+    // Exact even where `value * bps` alone would overflow
+    assert_eq!(LendingPool::apply_bps(i128::MAX, 10000), i128::MAX);
+    assert_eq!(LendingPool::apply_bps(i128::MAX, 5000), i128::MAX / 2);
+    assert_eq!(LendingPool::apply_bps(i128::MIN, 10000), i128::MIN);
+    assert_eq!(LendingPool::apply_bps(i128::MAX / 2, 20000), i128::MAX - 1);
+}
+
+#[test]
+#[should_panic(expected = "overflow")]
+fn test_apply_bps_overflow_panics() {
+    LendingPool::apply_bps(i128::MAX, 10001);
+}
+
+#[test]
+fn test_repay_and_supply() {
+    let s = setup();
+    let collateral = s.create_market(8000, ONE_DOLLAR);
+    let debt = s.create_market(8000, ONE_DOLLAR);
+    let user = Address::generate(&s.e);
+    s.fund_and_supply(&user, &collateral, 1_000);
+    s.pool.borrow(&user, &debt, &500);
+
+    // 500 settles the debt and the other 300 becomes a deposit
+    s.mint(&debt, &user, 300);
+    assert_eq!(s.pool.repay_and_supply(&user, &debt, &800), (500, 300));
+    s.assert_published(&Repay {
+        token: debt.clone(),
+        payer: user.clone(),
+        borrower: user.clone(),
+        amount: 500,
+    });
+    s.assert_published(&Supply {
+        token: debt.clone(),
+        from: user.clone(),
+        user: user.clone(),
+        amount: 300,
+        shares: 300,
+    });
+    assert_eq!(s.balance(&debt, &user), 0);
+    assert_eq!(s.pool.get_position(&user).debt_shares.len(), 0);
+    assert_eq!(s.pool.deposit_of(&user, &debt), 300);
+
+    // Without debt everything is supplied
+    s.mint(&debt, &user, 100);
+    assert_eq!(s.pool.repay_and_supply(&user, &debt, &100), (0, 100));
+    assert_eq!(s.pool.deposit_of(&user, &debt), 400);
+}
+
+#[test]
+fn test_min_liquidation_seize() {
+    let s = setup();
+    let collateral = s.create_market(8000, ONE_DOLLAR);
+    let debt = s.create_market(8000, ONE_DOLLAR);
+    s.pool.set_min_liquidation_seize(&collateral, &60);
+    assert!(s
+        .pool
+        .try_set_min_liquidation_seize(&collateral, &-1)
+        .is_err());
+
+    let lender = Address::generate(&s.e);
+    let borrower = Address::generate(&s.e);
+    let liquidator = Address::generate(&s.e);
+    s.fund_and_supply(&lender, &debt, 10_000);
+    s.fund_and_supply(&borrower, &collateral, 100);
+    s.pool.borrow(&borrower, &debt, &50);
+    s.pool.set_price(&collateral, &(ONE_DOLLAR / 2));
+
+    // Repaying part of the close factor's 25 earns no floor
+    assert_eq!(
+        s.pool
+            .preview_liquidation(&borrower, &debt, &collateral, &10),
+        (20, 10)
+    );
+    // Repaying all of it lifts the 50 collateral it buys to the floor
+    assert_eq!(
+        s.pool
+            .preview_liquidation(&borrower, &debt, &collateral, &25),
+        (60, 25)
+    );
+    s.mint(&debt, &liquidator, 25);
+    s.pool
+        .liquidate(&liquidator, &borrower, &debt, &collateral, &25);
+    assert_eq!(s.balance(&collateral, &liquidator), 60);
+}
+
+#[test]
+fn test_apys() {
+    let s = setup();
+    let collateral = s.create_market(8000, ONE_DOLLAR);
+    let debt = s.create_market(8000, ONE_DOLLAR);
+    s.pool.set_interest_rate(&debt, &1000);
+    s.pool.set_reserve_factor(&debt, &2000);
+    assert_eq!(s.pool.borrow_apy(&debt), 1051);
+    assert_eq!(s.pool.supply_apy(&debt), 0);
+
+    // Half the seed deposit lent out: 10% * 50% * 80% kept = 4%
+    let borrower = Address::generate(&s.e);
+    s.fund_and_supply(&borrower, &collateral, 100_000);
+    s.pool.borrow(&borrower, &debt, &(SEED / 2));
+    assert_eq!(s.pool.supply_rate(&debt), 400);
+    assert_eq!(s.pool.supply_apy(&debt), 408);
+
+    // At the highest rate the series needs more than a few terms: e - 1
+    s.pool.set_interest_rate(&debt, &10000);
+    assert_eq!(s.pool.borrow_apy(&debt), 17182);
+}
+
+#[test]
+fn test_guardian_can_only_pause_and_freeze() {
+    let s = setup();
+    let token = s.create_market(8000, ONE_DOLLAR);
+    let guardian = Address::generate(&s.e);
+    let stranger = Address::generate(&s.e);
+    assert!(s.pool.try_pause(&guardian).is_err());
+    s.pool.set_guardian(&Some(guardian.clone()));
+    assert_eq!(s.pool.get_guardian(), Some(guardian.clone()));
+
+    // The guardian signs its own emergency calls...
+    s.pool.pause(&guardian);
+    assert_eq!(s.e.auths()[0].0, guardian);
+    s.pool.set_action_paused(&guardian, &Action::Borrow, &true);
+    assert_eq!(s.e.auths()[0].0, guardian);
+    s.pool.set_frozen(&guardian, &token, &true);
+    assert_eq!(s.e.auths()[0].0, guardian);
+    assert!(s.pool.try_pause(&stranger).is_err());
+
+    // ...but can't undo a pause or freeze...
+    assert!(s.pool.try_set_frozen(&guardian, &token, &false).is_err());
+    assert!(s
+        .pool
+        .try_set_action_paused(&guardian, &Action::Borrow, &false)
+        .is_err());
+
+    // ...while everything else still takes the owner
+    s.pool.unpause();
+    assert_eq!(s.e.auths()[0].0, s.admin);
+    s.pool.set_frozen(&s.admin, &token, &false);
+    assert_eq!(s.e.auths()[0].0, s.admin);
+    s.pool.set_action_paused(&s.admin, &Action::Borrow, &false);
+    assert_eq!(s.e.auths()[0].0, s.admin);
+    s.pool.set_ltv(&token, &7000);
+    assert_eq!(s.e.auths()[0].0, s.admin);
+    s.pool.set_guardian(&None);
+    assert_eq!(s.e.auths()[0].0, s.admin);
+    assert!(s.pool.try_pause(&guardian).is_err());
+}
+
+#[test]
+fn test_timelocked_param_changes() {
+    let s = setup();
+    let token = s.create_market(8000, ONE_DOLLAR);
+    s.pool.set_timelock_delay(&ONE_YEAR);
+    assert!(s.pool.try_set_ltv(&token, &5000).is_err());
+    assert!(s.pool.try_set_liquidation_threshold(&token, &9000).is_err());
+    assert!(s.pool.try_set_timelock_delay(&0).is_err());
+
+    let id = s
+        .pool
+        .queue_param_change(&ParamKey::Ltv(token.clone()), &5000);
+    let change = PendingChange {
+        key: ParamKey::Ltv(token.clone()),
+        value: 5000,
+        eta: s.e.ledger().timestamp() + ONE_YEAR,
+    };
+    s.assert_published(&ParamChangeQueued {
+        id,
+        change: change.clone(),
+    });
+    assert_eq!(s.pool.pending_param_changes().get(id), Some(change));
+
+    // Too early, then applied once the delay has passed
+    assert!(s.pool.try_execute_param_change(&id).is_err());
+    s.e.ledger().with_mut(|l| l.timestamp += ONE_YEAR);
+    s.pool.execute_param_change(&id);
+    assert_eq!(s.pool.ltv_of(&token), 5000);
+    assert!(s.pool.pending_param_changes().is_empty());
+    assert!(s.pool.try_execute_param_change(&id).is_err());
+
+    // Cancelled changes never apply
+    let id = s.pool.queue_param_change(&ParamKey::TimelockDelay, &0);
+    s.pool.cancel_param_change(&id);
+    s.e.ledger().with_mut(|l| l.timestamp += ONE_YEAR);
+    assert!(s.pool.try_execute_param_change(&id).is_err());
+    assert!(s.pool.try_set_ltv(&token, &6000).is_err());
+}
+
+#[test]
+fn test_timelocked_emode_changes() {
+    let s = setup();
+    let usdc = s.create_market(8000, ONE_DOLLAR);
+    let category = EmodeCategory {
+        ltv: 9500,
+        liquidation_threshold: 9700,
+    };
+    s.pool.set_emode_category(&1, &category);
+    s.pool.set_timelock_delay(&ONE_YEAR);
+
+    // New categories hold no assets yet, so they can still be created
+    s.pool.set_emode_category(&2, &category);
+    assert!(s.pool.try_set_emode_category(&1, &category).is_err());
+    assert!(s.pool.try_set_asset_emode(&usdc, &1).is_err());
+
+    let asset_id = s
+        .pool
+        .queue_param_change(&ParamKey::AssetEmode(usdc.clone()), &1);
+    let ltv_id = s.pool.queue_param_change(&ParamKey::EmodeLtv(1), &9000);
+    let threshold_id = s
+        .pool
+        .queue_param_change(&ParamKey::EmodeLiquidationThreshold(1), &9200);
+    s.e.ledger().with_mut(|l| l.timestamp += ONE_YEAR);
+    s.pool.execute_param_change(&asset_id);
+    s.pool.execute_param_change(&ltv_id);
+    s.pool.execute_param_change(&threshold_id);
+
+    let user = Address::generate(&s.e);
+    s.fund_and_supply(&user, &usdc, 1_000);
+    s.pool.set_user_emode(&user, &1);
+    assert_eq!(s.pool.get_account_data(&user).liquidation_threshold, 9200);
+}
+
+#[test]
+fn test_queued_threshold_requires_listed_token() {
+    let s = setup();
+    s.pool.set_timelock_delay(&ONE_YEAR);
+    let id = s.pool.queue_param_change(
+        &ParamKey::LiquidationThreshold(Address::generate(&s.e)),
+        &9000,
+    );
+    s.e.ledger().with_mut(|l| l.timestamp += ONE_YEAR);
+    assert!(s.pool.try_execute_param_change(&id).is_err());
+}
+
+#[test]
+fn test_withdraw_queue() {
+    let s = setup();
+    let collateral = s.create_market(8000, ONE_DOLLAR);
+    let debt = s.create_market(8000, ONE_DOLLAR);
+    let lender = Address::generate(&s.e);
+    let borrower = Address::generate(&s.e);
+    s.fund_and_supply(&lender, &debt, 10_000);
+    s.fund_and_supply(&borrower, &collateral, 100_000);
+    s.pool.borrow(&borrower, &debt, &18_000);
+
+    // Only 2,000 of the 20,000 supplied is left in cash
+    assert!(s.pool.try_withdraw(&lender, &debt, &10_000).is_err());
+    assert_eq!(s.pool.request_withdraw(&lender, &debt, &10_000), 10_000);
+    assert_eq!(s.pool.deposit_shares_of(&lender, &debt), 0);
+    assert_eq!(s.pool.claim_withdraw(&lender, &debt), 2_000);
+    assert_eq!(s.pool.queued_withdraw(&lender, &debt), 8_000);
+    assert_eq!(s.pool.claim_withdraw(&lender, &debt), 0);
+
+    // Repaid cash goes to the queue, not to new borrows
+    s.pool.repay(&borrower, &debt, &5_000);
+    assert_eq!(s.pool.max_borrow(&borrower, &debt), 0);
+    assert!(s.pool.try_borrow(&borrower, &debt, &1).is_err());
+    assert_eq!(s.pool.claim_withdraw(&lender, &debt), 5_000);
+    assert_eq!(s.pool.queued_withdraw(&lender, &debt), 3_000);
+    assert_eq!(s.balance(&debt, &lender), 7_000);
+}
+
+#[test]
+fn test_withdraw_cannot_take_queued_cash() {
+    let s = setup();
+    let collateral = s.create_market(8000, ONE_DOLLAR);
+    let debt = s.create_market(8000, ONE_DOLLAR);
+    let lender = Address::generate(&s.e);
+    let other_lender = Address::generate(&s.e);
+    let borrower = Address::generate(&s.e);
+    s.fund_and_supply(&lender, &debt, 10_000);
+    s.fund_and_supply(&other_lender, &debt, 10_000);
+    s.fund_and_supply(&borrower, &collateral, 100_000);
+    s.pool.borrow(&borrower, &debt, &27_000);
+    s.pool.request_withdraw(&lender, &debt, &10_000);
+    assert_eq!(s.pool.claim_withdraw(&lender, &debt), 3_000);
+
+    // The repaid cash is owed to the queue, so a plain withdrawal can't take it
+    s.pool.repay(&borrower, &debt, &5_000);
+    assert_eq!(s.pool.available_liquidity(&debt), 0);
+    assert_eq!(s.pool.max_withdraw(&other_lender, &debt), 0);
+    assert!(s.pool.try_withdraw(&other_lender, &debt, &1).is_err());
+    assert_eq!(s.pool.withdraw(&other_lender, &debt, &i128::MAX), 0);
+    assert!(s
+        .pool
+        .try_withdraw_shares(&other_lender, &debt, &1)
+        .is_err());
+    assert_eq!(s.pool.claim_withdraw(&lender, &debt), 5_000);
+    assert_eq!(s.pool.queued_withdraw(&lender, &debt), 2_000);
+}
+
+#[test]
+fn test_queued_shares_earn_no_rewards() {
+    let s = setup();
+    let token = s.create_market(8000, ONE_DOLLAR);
+    let reward =
+        s.e.register_stellar_asset_contract_v2(s.admin.clone())
+            .address();
+    s.mint(&reward, &s.pool.address, 1_000_000);
+    let config = EmissionConfig {
+        reward_token: reward,
+        supply_per_second: 100,
+        borrow_per_second: 0,
+    };
+    s.pool.set_emission_config(&token, &config);
+    let supplier = Address::generate(&s.e);
+    let queuer = Address::generate(&s.e);
+    s.fund_and_supply(&supplier, &token, SEED);
+    s.fund_and_supply(&queuer, &token, SEED * 2);
+
+    // The queued shares' part of the stream goes to the other suppliers
+    s.pool.request_withdraw(&queuer, &token, &(SEED * 2));
+    s.advance_time(100);
+    assert_eq!(s.pool.pending_rewards(&supplier, &token), 5_000);
+    assert_eq!(s.pool.pending_rewards(&queuer, &token), 0);
+
+    // Claiming doesn't change what was earned before it
+    s.pool.claim_withdraw(&queuer, &token);
+    assert_eq!(s.pool.queued_withdraw(&queuer, &token), 0);
+    s.advance_time(100);
+    assert_eq!(s.pool.pending_rewards(&supplier, &token), 10_000);
+}
+
+#[test]
+fn test_get_account_data() {
+    let s = setup();
+    let stable = s.create_market(8000, ONE_DOLLAR);
+    let volatile = s.create_market(5000, ONE_DOLLAR);
+    let debt = s.create_market(8000, ONE_DOLLAR);
+    s.pool.set_liquidation_threshold(&stable, &9000);
+    s.pool.set_liquidation_threshold(&volatile, &7000);
+    let user = Address::generate(&s.e);
+    s.fund_and_supply(&user, &stable, 1_000);
+    s.fund_and_supply(&user, &volatile, 1_000);
+    s.pool.borrow(&user, &debt, &650);
+
+    assert_eq!(
+        s.pool.get_account_data(&user),
+        AccountData {
+            total_collateral_value: 2_000 * ONE_DOLLAR,
+            total_debt_value: 650 * ONE_DOLLAR,
+            available_borrows_value: 650 * ONE_DOLLAR,
+            current_ltv: 6500,
+            liquidation_threshold: 8000,
+            health_factor: 24615,
+        }
+    );
+}
+
+#[test]
+fn test_init_pool_returns_pool_and_publishes_event() {
+    let s = setup();
+    let token =
+        s.e.register_stellar_asset_contract_v2(s.admin.clone())
+            .address();
+
+    let pool = s.pool.init_pool(&token);
+    s.assert_published(&PoolInitialized {
+        token: token.clone(),
+    });
+    assert_eq!(pool.token, token);
+    assert_eq!(pool.total_supply_shares, 0);
+    assert_eq!(pool.borrow_index, 1_000_000_000);
+    assert!(s.pool.try_init_pool(&token).is_err());
+}
+
+#[test]
+fn test_init_pool_rejects_non_token() {
+    let s = setup();
+    let receiver = s.e.register(MockReceiver, ());
+    assert!(s.pool.try_init_pool(&receiver).is_err());
+    assert!(s.pool.try_init_pool(&Address::generate(&s.e)).is_err());
+}
+
+#[test]
+#[should_panic(expected = "not a token contract")]
+fn test_init_pool_with_bogus_address_panics() {
+    let s = setup();
+    s.pool.init_pool(&Address::generate(&s.e));
+}
+
+#[test]
+#[should_panic(expected = "cannot borrow an asset used as collateral")]
+fn test_cannot_borrow_against_the_same_asset() {
+    let s = setup();
+    let token = s.create_market(8000, ONE_DOLLAR);
+    let user = Address::generate(&s.e);
+    s.fund_and_supply(&user, &token, 1_000);
+    s.pool.borrow(&user, &token, &500);
+}
+
+#[test]
+fn test_borrowed_asset_is_never_collateral() {
+    let s = setup();
+    let collateral = s.create_market(8000, ONE_DOLLAR);
+    let token = s.create_market(8000, ONE_DOLLAR);
+    let other = s.create_market(8000, ONE_DOLLAR);
+    let user = Address::generate(&s.e);
+    s.fund_and_supply(&user, &collateral, 1_000);
+    s.pool.borrow(&user, &token, &500);
+    assert_eq!(s.pool.max_borrow(&user, &collateral), 0);
+
+    // Supplying the borrowed asset back adds no borrowing power.
+    s.fund_and_supply(&user, &token, 500);
+    assert_eq!(s.pool.max_borrow(&user, &other), 300);
+    assert!(s
+        .pool
+        .try_set_use_as_collateral(&user, &token, &true)
+        .is_err());
+
+    // A plain deposit of the asset doesn't block borrowing more of it.
+    s.pool.borrow(&user, &token, &300);
+}
+
+#[test]
+fn test_min_borrow_rejects_dust_debt() {
+    let s = setup();
+    let collateral = s.create_market(8000, ONE_DOLLAR);
+    let debt = s.create_market(8000, ONE_DOLLAR);
+    s.pool.set_min_borrow(&debt, &100);
+    let user = Address::generate(&s.e);
+    s.fund_and_supply(&user, &collateral, 1_000);
+
+    assert!(s.pool.try_borrow(&user, &debt, &99).is_err());
+    s.pool.borrow(&user, &debt, &100);
+    // Adding to an existing debt above the minimum is fine.
+    s.pool.borrow(&user, &debt, &1);
+}
+
+#[test]
+fn test_liquidation_clears_debt_that_would_become_dust() {
+    let s = setup();
+    let collateral = s.create_market(8000, ONE_DOLLAR);
+    let debt = s.create_market(8000, ONE_DOLLAR);
+    s.pool.set_min_borrow(&debt, &300);
+    let user = Address::generate(&s.e);
+    s.fund_and_supply(&user, &collateral, 500);
+    s.pool.borrow(&user, &debt, &400);
+    s.pool.set_price(&collateral, &(ONE_DOLLAR * 9 / 10));
+
+    // Half the debt would leave 200 behind, under the minimum.
+    let liquidator = Address::generate(&s.e);
+    s.mint(&debt, &liquidator, 400);
+    assert!(s
+        .pool
+        .try_liquidate(&liquidator, &user, &debt, &collateral, &200)
+        .is_err());
+    s.pool
+        .liquidate(&liquidator, &user, &debt, &collateral, &400);
+    assert_eq!(s.pool.get_position(&user).debt_shares.get(debt), None);
+}
+
+#[test]
+fn test_clear_bad_debt_socializes_loss() {
+    let s = setup();
+    let collateral = s.create_market(8000, ONE_DOLLAR);
+    let debt = s.create_market(8000, ONE_DOLLAR);
+    s.pool.set_close_factor(&10000);
+    let user = Address::generate(&s.e);
+    s.fund_and_supply(&user, &collateral, 1_000);
+    s.pool.borrow(&user, &debt, &800);
+
+    // The collateral crashes and the liquidator takes all of it.
+    s.pool.set_price(&collateral, &(ONE_DOLLAR / 4));
+    let liquidator = Address::generate(&s.e);
+    s.mint(&debt, &liquidator, 250);
+    s.pool
+        .liquidate(&liquidator, &user, &debt, &collateral, &250);
+    assert!(s.pool.get_health_factor(&user) < 10000);
+
+    assert_eq!(s.pool.clear_bad_debt(&user, &debt), 550);
+    s.assert_published(&BadDebt {
+        token: debt.clone(),
+        borrower: user.clone(),
+        amount: 550,
+    });
+    assert_eq!(s.pool.get_health_factor(&user), i128::MAX);
+    // Suppliers' shares are now backed by 550 fewer tokens.
+    let pool = s.pool.get_pool_info(&debt);
+    assert_eq!(pool.total_debt_shares, 0);
+    assert_eq!(pool.total_cash, SEED - 550);
+}
+
+#[test]
+#[should_panic(expected = "borrower still has collateral")]
+fn test_clear_bad_debt_requires_no_collateral() {
+    let s = setup();
+    let collateral = s.create_market(8000, ONE_DOLLAR);
+    let debt = s.create_market(8000, ONE_DOLLAR);
+    let user = Address::generate(&s.e);
+    s.fund_and_supply(&user, &collateral, 1_000);
+    s.pool.borrow(&user, &debt, &800);
+    s.pool.clear_bad_debt(&user, &debt);
+}
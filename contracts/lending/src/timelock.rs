@@ -0,0 +1,95 @@
+//! Timelocked risk-parameter changes.
+//!
+//! Lowering an asset's LTV or liquidation threshold, directly or through
+//! its e-mode category, can push existing positions into liquidation at
+//! once. With a delay set by
+//! `set_timelock_delay`, such changes are queued with `queue_param_change`
+//! and only take effect through `execute_param_change` once the delay has
+//! passed, so users see them coming and can adjust their positions.
+
+use soroban_sdk::{contracttype, Address, Env, Map};
+
+use crate::contract::{EmodeCategory, ExtraKey, LendingPool};
+
+/// A parameter that can only change through the timelock while it is on.
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[contracttype]
+pub enum ParamKey {
+    Ltv(Address),
+    LiquidationThreshold(Address),
+    /// The LTV of an existing e-mode category.
+    EmodeLtv(u32),
+    /// The liquidation threshold of an existing e-mode category.
+    EmodeLiquidationThreshold(u32),
+    /// The e-mode category of an asset; the value is the category id.
+    AssetEmode(Address),
+    /// Lowering the delay itself; raising it takes effect at once.
+    TimelockDelay,
+}
+
+/// A queued change and the ledger timestamp it can be executed from.
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[contracttype]
+pub struct PendingChange {
+    pub key: ParamKey,
+    pub value: u64,
+    pub eta: u64,
+}
+
+impl LendingPool {
+    /// Seconds a queued change waits before it can be executed, or 0 if
+    /// the timelock is off.
+    pub(crate) fn timelock_delay(e: &Env) -> u64 {
+        e.storage()
+            .persistent()
+            .get(&ExtraKey::TimelockDelay)
+            .unwrap_or(0)
+    }
+
+    /// Panics if the timelock is on, so a parameter can't be set directly.
+    pub(crate) fn require_not_timelocked(e: &Env) {
+        if Self::timelock_delay(e) > 0 {
+            panic!("parameter is timelocked; use queue_param_change");
+        }
+    }
+
+    pub(crate) fn pending_changes(e: &Env) -> Map<u32, PendingChange> {
+        e.storage()
+            .persistent()
+            .get(&ExtraKey::PendingChanges)
+            .unwrap_or(Map::new(e))
+    }
+
+    pub(crate) fn save_pending_changes(e: &Env, changes: &Map<u32, PendingChange>) {
+        e.storage()
+            .persistent()
+            .set(&ExtraKey::PendingChanges, changes);
+    }
+
+    /// Applies `key = value` with the same checks as its setter.
+    pub(crate) fn apply_param_change(e: &Env, key: ParamKey, value: u64) {
+        let bps = || u32::try_from(value).expect("value out of range");
+        match key {
+            ParamKey::Ltv(token) => Self::write_ltv(e, token, bps()),
+            ParamKey::LiquidationThreshold(token) => {
+                Self::write_liquidation_threshold(e, token, bps())
+            }
+            ParamKey::EmodeLtv(id) => {
+                let mut category = Self::existing_emode_category(e, id);
+                category.ltv = bps();
+                Self::write_emode_category(e, id, category)
+            }
+            ParamKey::EmodeLiquidationThreshold(id) => {
+                let mut category = Self::existing_emode_category(e, id);
+                category.liquidation_threshold = bps();
+                Self::write_emode_category(e, id, category)
+            }
+            ParamKey::AssetEmode(token) => Self::write_asset_emode(e, token, bps()),
+            ParamKey::TimelockDelay => Self::write_timelock_delay(e, value),
+        }
+    }
+
+    fn existing_emode_category(e: &Env, id: u32) -> EmodeCategory {
+        Self::get_emode_category(e, id).expect("e-mode category not found")
+    }
+}
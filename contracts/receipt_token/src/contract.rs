@@ -0,0 +1,182 @@
+use soroban_sdk::{
+    contract, contractimpl, contracttype, token, token::TokenInterface, Address, Env, MuxedAddress,
+    String,
+};
+
+use crate::events::{Approve, Burn, Mint, Transfer};
+use crate::pool::LendingPoolClient;
+
+#[derive(Clone)]
+#[contracttype]
+pub enum DataKey {
+    Pool,
+    Underlying,
+    Name,
+    Symbol,
+    Decimals,
+    /// (from, spender)
+    Allowance(Address, Address),
+}
+
+/// An allowance and the last ledger it can be spent in.
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[contracttype]
+pub struct AllowanceValue {
+    pub amount: i128,
+    pub expiration_ledger: u32,
+}
+
+/// A SEP-41 receipt for deposits in one lending pool.
+///
+/// The token keeps no balances of its own: a balance is the holder's
+/// supply shares in the pool, so supplying mints receipts and withdrawing
+/// burns them; the pool reports both so the token can publish their
+/// events. Transferring receipts moves the deposit behind them, subject
+/// to the pool's health checks on the sender.
+#[contract]
+pub struct ReceiptToken;
+
+#[contractimpl]
+impl ReceiptToken {
+    /// Creates the receipt for `underlying`'s pool in `pool`. Register the
+    /// deployed contract with the pool's `set_supply_token` to activate it.
+    pub fn __constructor(e: Env, pool: Address, underlying: Address, name: String, symbol: String) {
+        let decimals = token::Client::new(&e, &underlying).decimals();
+        let storage = e.storage().persistent();
+        storage.set(&DataKey::Pool, &pool);
+        storage.set(&DataKey::Underlying, &underlying);
+        storage.set(&DataKey::Name, &name);
+        storage.set(&DataKey::Symbol, &symbol);
+        storage.set(&DataKey::Decimals, &decimals);
+    }
+
+    /// Returns the lending pool this token is a receipt for.
+    pub fn pool(e: Env) -> Address {
+        e.storage().persistent().get(&DataKey::Pool).unwrap()
+    }
+
+    /// Returns the asset whose deposits this token represents.
+    pub fn underlying(e: Env) -> Address {
+        e.storage().persistent().get(&DataKey::Underlying).unwrap()
+    }
+
+    /// Publishes the `mint` of `amount` receipts to `to`, whose deposit
+    /// grew by as many shares. Only the pool may call this.
+    pub fn notify_mint(e: Env, to: Address, amount: i128) {
+        Self::pool(e.clone()).require_auth();
+        Mint { to, amount }.publish(&e);
+    }
+
+    /// Publishes the `burn` of `amount` of `from`'s receipts, whose deposit
+    /// shrank by as many shares. Only the pool may call this.
+    pub fn notify_burn(e: Env, from: Address, amount: i128) {
+        Self::pool(e.clone()).require_auth();
+        Burn { from, amount }.publish(&e);
+    }
+
+    // --- Helper Functions ---
+    fn pool_client(e: &Env) -> LendingPoolClient<'_> {
+        LendingPoolClient::new(e, &Self::pool(e.clone()))
+    }
+
+    fn get_allowance(e: &Env, from: Address, spender: Address) -> AllowanceValue {
+        let allowance: Option<AllowanceValue> = e
+            .storage()
+            .persistent()
+            .get(&DataKey::Allowance(from, spender));
+        match allowance {
+            Some(allowance) if allowance.expiration_ledger >= e.ledger().sequence() => allowance,
+            _ => AllowanceValue {
+                amount: 0,
+                expiration_ledger: 0,
+            },
+        }
+    }
+
+    fn spend_allowance(e: &Env, from: Address, spender: Address, amount: i128) {
+        let allowance = Self::get_allowance(e, from.clone(), spender.clone());
+        if amount > allowance.amount {
+            panic!("insufficient allowance");
+        }
+        e.storage().persistent().set(
+            &DataKey::Allowance(from, spender),
+            &AllowanceValue {
+                amount: allowance.amount - amount,
+                expiration_ledger: allowance.expiration_ledger,
+            },
+        );
+    }
+
+    fn move_deposit(e: &Env, from: Address, to: Address, amount: i128) {
+        Self::pool_client(e).transfer_deposit(&Self::underlying(e.clone()), &from, &to, &amount);
+        Transfer { from, to, amount }.publish(e);
+    }
+}
+
+#[contractimpl]
+impl TokenInterface for ReceiptToken {
+    fn allowance(e: Env, from: Address, spender: Address) -> i128 {
+        Self::get_allowance(&e, from, spender).amount
+    }
+
+    fn approve(e: Env, from: Address, spender: Address, amount: i128, expiration_ledger: u32) {
+        from.require_auth();
+        if amount < 0 {
+            panic!("amount cannot be negative");
+        }
+        if amount > 0 && expiration_ledger < e.ledger().sequence() {
+            panic!("expiration ledger is in the past");
+        }
+        e.storage().persistent().set(
+            &DataKey::Allowance(from.clone(), spender.clone()),
+            &AllowanceValue {
+                amount,
+                expiration_ledger,
+            },
+        );
+        Approve {
+            from,
+            spender,
+            amount,
+            expiration_ledger,
+        }
+        .publish(&e);
+    }
+
+    fn balance(e: Env, id: Address) -> i128 {
+        Self::pool_client(&e).deposit_shares_of(&id, &Self::underlying(e.clone()))
+    }
+
+    fn transfer(e: Env, from: Address, to: MuxedAddress, amount: i128) {
+        from.require_auth();
+        Self::move_deposit(&e, from, to.address(), amount);
+    }
+
+    fn transfer_from(e: Env, spender: Address, from: Address, to: Address, amount: i128) {
+        spender.require_auth();
+        Self::spend_allowance(&e, from.clone(), spender, amount);
+        Self::move_deposit(&e, from, to, amount);
+    }
+
+    /// Receipts are only burned by withdrawing from the pool.
+    fn burn(_e: Env, _from: Address, _amount: i128) {
+        panic!("withdraw from the pool to burn receipts");
+    }
+
+    /// Receipts are only burned by withdrawing from the pool.
+    fn burn_from(_e: Env, _spender: Address, _from: Address, _amount: i128) {
+        panic!("withdraw from the pool to burn receipts");
+    }
+
+    fn decimals(e: Env) -> u32 {
+        e.storage().persistent().get(&DataKey::Decimals).unwrap()
+    }
+
+    fn name(e: Env) -> String {
+        e.storage().persistent().get(&DataKey::Name).unwrap()
+    }
+
+    fn symbol(e: Env) -> String {
+        e.storage().persistent().get(&DataKey::Symbol).unwrap()
+    }
+}
@@ -0,0 +1,7 @@
+#![no_std]
+pub mod contract;
+pub mod events;
+pub mod pool;
+
+#[cfg(test)]
+mod test;
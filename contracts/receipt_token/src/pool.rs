@@ -0,0 +1,13 @@
+//! Client interface for the lending pool backing a receipt token.
+//! Only the calls this contract makes are declared.
+
+use soroban_sdk::{contractclient, Address, Env};
+
+#[contractclient(name = "LendingPoolClient")]
+pub trait LendingPool {
+    /// `user`'s supply shares in `token`'s pool.
+    fn deposit_shares_of(e: Env, user: Address, token: Address) -> i128;
+    /// Moves `shares` of `from`'s `token` deposit to `to`. Requires this
+    /// contract's authorization.
+    fn transfer_deposit(e: Env, token: Address, from: Address, to: Address, shares: i128);
+}
@@ -0,0 +1,174 @@
+#![cfg(test)]
+extern crate std;
+
+use crate::contract::{ReceiptToken, ReceiptTokenClient};
+use crate::events::{Burn, Mint, Transfer};
+use soroban_sdk::{
+    contract, contractimpl,
+    testutils::{Address as _, Events as _, Ledger},
+    Address, Env, Event, String,
+};
+
+/// Stands in for the lending pool: tracks one token's supply shares.
+#[contract]
+struct MockPool;
+
+#[contractimpl]
+impl MockPool {
+    pub fn set_shares(e: Env, user: Address, shares: i128) {
+        e.storage().persistent().set(&user, &shares);
+    }
+
+    pub fn deposit_shares_of(e: Env, user: Address, _token: Address) -> i128 {
+        e.storage().persistent().get(&user).unwrap_or(0)
+    }
+
+    pub fn transfer_deposit(e: Env, token: Address, from: Address, to: Address, shares: i128) {
+        let from_shares = Self::deposit_shares_of(e.clone(), from.clone(), token.clone());
+        if shares > from_shares {
+            panic!("insufficient balance");
+        }
+        let to_shares = Self::deposit_shares_of(e.clone(), to.clone(), token);
+        Self::set_shares(e.clone(), from, from_shares - shares);
+        Self::set_shares(e, to, to_shares + shares);
+    }
+}
+
+struct Setup<'a> {
+    e: Env,
+    pool: MockPoolClient<'a>,
+    receipt: ReceiptTokenClient<'a>,
+}
+
+fn setup<'a>() -> Setup<'a> {
+    let e = Env::default();
+    e.mock_all_auths();
+
+    let pool = e.register(MockPool, ());
+    let underlying = e
+        .register_stellar_asset_contract_v2(Address::generate(&e))
+        .address();
+    let receipt = e.register(
+        ReceiptToken,
+        (
+            &pool,
+            &underlying,
+            String::from_str(&e, "Lending USDC"),
+            String::from_str(&e, "lUSDC"),
+        ),
+    );
+    Setup {
+        pool: MockPoolClient::new(&e, &pool),
+        receipt: ReceiptTokenClient::new(&e, &receipt),
+        e,
+    }
+}
+
+#[test]
+fn test_metadata() {
+    let s = setup();
+    assert_eq!(s.receipt.decimals(), 7);
+    assert_eq!(s.receipt.name(), String::from_str(&s.e, "Lending USDC"));
+    assert_eq!(s.receipt.symbol(), String::from_str(&s.e, "lUSDC"));
+    assert_eq!(s.receipt.pool(), s.pool.address);
+}
+
+#[test]
+fn test_balance_is_pool_shares() {
+    let s = setup();
+    let user = Address::generate(&s.e);
+    assert_eq!(s.receipt.balance(&user), 0);
+    s.pool.set_shares(&user, &1_000);
+    assert_eq!(s.receipt.balance(&user), 1_000);
+}
+
+#[test]
+fn test_transfer_moves_deposit() {
+    let s = setup();
+    let alice = Address::generate(&s.e);
+    let bob = Address::generate(&s.e);
+    s.pool.set_shares(&alice, &1_000);
+
+    s.receipt.transfer(&alice, &bob, &400);
+    let event = Transfer {
+        from: alice.clone(),
+        to: bob.clone(),
+        amount: 400,
+    };
+    let expected = (
+        s.receipt.address.clone(),
+        event.topics(&s.e),
+        event.data(&s.e),
+    );
+    assert!(s.e.events().all().contains(expected));
+    assert_eq!(s.receipt.balance(&alice), 600);
+    assert_eq!(s.receipt.balance(&bob), 400);
+    assert!(s.receipt.try_transfer(&alice, &bob, &601).is_err());
+}
+
+#[test]
+fn test_transfer_from_spends_allowance() {
+    let s = setup();
+    let alice = Address::generate(&s.e);
+    let spender = Address::generate(&s.e);
+    let bob = Address::generate(&s.e);
+    s.pool.set_shares(&alice, &1_000);
+
+    s.receipt.approve(&alice, &spender, &500, &100);
+    s.receipt.transfer_from(&spender, &alice, &bob, &300);
+    assert_eq!(s.receipt.allowance(&alice, &spender), 200);
+    assert_eq!(s.receipt.balance(&bob), 300);
+    assert!(s
+        .receipt
+        .try_transfer_from(&spender, &alice, &bob, &201)
+        .is_err());
+
+    // An expired allowance can't be spent
+    s.e.ledger().with_mut(|l| l.sequence_number = 101);
+    assert_eq!(s.receipt.allowance(&alice, &spender), 0);
+    assert!(s
+        .receipt
+        .try_transfer_from(&spender, &alice, &bob, &1)
+        .is_err());
+}
+
+#[test]
+fn test_pool_reports_mints_and_burns() {
+    let s = setup();
+    let alice = Address::generate(&s.e);
+
+    s.receipt.notify_mint(&alice, &1_000);
+    assert_eq!(s.e.auths()[0].0, s.pool.address);
+    let event = Mint {
+        to: alice.clone(),
+        amount: 1_000,
+    };
+    let expected = (
+        s.receipt.address.clone(),
+        event.topics(&s.e),
+        event.data(&s.e),
+    );
+    assert!(s.e.events().all().contains(expected));
+
+    s.receipt.notify_burn(&alice, &400);
+    assert_eq!(s.e.auths()[0].0, s.pool.address);
+    let event = Burn {
+        from: alice.clone(),
+        amount: 400,
+    };
+    let expected = (
+        s.receipt.address.clone(),
+        event.topics(&s.e),
+        event.data(&s.e),
+    );
+    assert!(s.e.events().all().contains(expected));
+}
+
+#[test]
+#[should_panic(expected = "withdraw from the pool to burn receipts")]
+fn test_burn_is_unsupported() {
+    let s = setup();
+    let alice = Address::generate(&s.e);
+    s.pool.set_shares(&alice, &1_000);
+    s.receipt.burn(&alice, &100);
+}